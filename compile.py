@@ -65,6 +65,13 @@ def main():
         const="power_failure",
         help="Inject power failure at a regular interval",
     )
+    parser.add_argument(
+        "--jit_ckpt",
+        dest="features",
+        action="append_const",
+        const="jit_checkpoint",
+        help="Enable the JIT checkpointing mode for tasks registered as checkpointed (qemu/apollo4bp only)",
+    )
     parser.add_argument(
         "--vanilla",
         action="store_true",
@@ -46,6 +46,14 @@ SECTIONS
     /* used by startup to initialize data */
     _sidata = LOADADDR(.data);
 
+    .checkpoint_meta :
+    {
+        . = ALIGN(4);
+        *(.checkpoint_meta)
+        *(.checkpoint_meta*)
+        . = ALIGN(4);
+    } > SHARED_SRAM
+
     .pmem :
     {
         . = ALIGN(4);
@@ -28,12 +28,10 @@ fn task_fn_instrument(_args: TokenStream, item: TokenStream) -> TokenStream {
     );
     let block = item.block;
     quote!(
-        #[allow(dead_code)]
+        #[allow(dead_code, unreachable_code)]
         #vis #sig {
             #block;
-            loop {
-
-            }
+            crate::user::transaction::run_pure_sys(|t| crate::syscalls::sys_task_exit(t))
         }
     )
     .into()
@@ -114,12 +114,44 @@ pub fn arch_is_cycle_cnt_enabled() -> bool {
     false
 }
 
+// Sleep until the next interrupt
+#[cfg(feature = "tickless_idle")]
+#[inline(always)]
+pub fn arch_sleep() {
+    #[cfg(not(test))]
+    unsafe {
+        arch!(sleep())
+    };
+}
+
+// Stop the tick until the next task or timer is due, then sleep
+#[cfg(feature = "tickless_idle")]
+#[inline(always)]
+pub fn arch_suppress_ticks_and_sleep() {
+    #[cfg(not(test))]
+    unsafe {
+        arch!(suppress_ticks_and_sleep())
+    };
+}
+
+// Park the cpu after a JIT checkpoint until the power is gone
+#[cfg(feature = "jit_checkpoint")]
+pub fn arch_wait_for_power_failure() -> ! {
+    #[cfg(not(test))]
+    unsafe {
+        arch!(wait_for_power_failure())
+    }
+    #[cfg(test)]
+    panic!("Impossible to wait for power failure!");
+}
+
 #[inline(always)]
 pub fn initialize_stack(stack_top: usize, task_func: usize, param: usize) -> usize {
     #[cfg(not(test))]
     return unsafe { arch!(initialize_stack(stack_top, task_func, param)) };
+    // tests run on the host stack, nothing is pushed
     #[cfg(test)]
-    return 0;
+    return stack_top;
 }
 
 #[inline(always)]
@@ -163,6 +195,8 @@ pub fn recover_and_boot() {
     unsafe { reset_scheduler_started() };
     increase_generation();
     init_boot_tx();
+    // ticks skipped before the power failure are not owed to the new timer
+    crate::time::TIME_MANAGER.set_suppressed_ticks(0);
     // before doing anything, run a recovery protocal
     recover();
     run_boot_sequence();
@@ -174,6 +208,8 @@ pub fn run_boot_sequence() {
     // idempotent boot sequence
     idempotent_boot(|| {
         heap::init();
+        #[cfg(feature = "jit_checkpoint")]
+        crate::checkpoint::init();
         debug_print!("creating idle task...");
         task::create_idle_task();
         #[cfg(any(bench_task = "sense", bench_task = "sense_base", timer_daemon))]
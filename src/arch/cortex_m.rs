@@ -66,12 +66,12 @@ pub enum SystClkSource {
     External,
 }
 
-// const SYST_COUNTER_MASK: u32 = 0x00ff_ffff;
+const SYST_COUNTER_MASK: u32 = 0x00ff_ffff;
 
 const SYST_CSR_ENABLE: u32 = 1 << 0;
 const SYST_CSR_TICKINT: u32 = 1 << 1;
 const SYST_CSR_CLKSOURCE: u32 = 1 << 2;
-// const SYST_CSR_COUNTFLAG: u32 = 1 << 16;
+const SYST_CSR_COUNTFLAG: u32 = 1 << 16;
 
 // const SYST_CALIB_SKEW: u32 = 1 << 30;
 // const SYST_CALIB_NOREF: u32 = 1 << 31;
@@ -108,6 +108,12 @@ impl SystemTimer {
     pub fn set_config(&mut self, config_value: u32) {
         unsafe { self.p.csr.write(config_value) }
     }
+
+    // reading it clears the count flag
+    #[inline(always)]
+    pub fn get_config(&self) -> u32 {
+        self.p.csr.read()
+    }
 }
 
 pub fn get_sysclk_counter_elapsed() -> u32 {
@@ -722,6 +728,61 @@ unsafe fn new_power_cycle() {
     )
 }
 
+#[cfg(feature = "jit_checkpoint")]
+pub unsafe fn wait_for_power_failure() -> ! {
+    // the failure injector stands in for the brown-out
+    #[cfg(feature = "power_failure")]
+    new_power_cycle();
+    loop {
+        asm!("wfi");
+    }
+}
+
+#[inline(always)]
+pub unsafe fn sleep() {
+    asm!("dsb", "wfi", "isb");
+}
+
+// Stretch the SysTick period over the idle ticks. If the tick fires, its
+// handler accounts for the suppressed ticks, otherwise they are counted here.
+#[cfg(feature = "tickless_idle")]
+pub unsafe fn suppress_ticks_and_sleep() {
+    use crate::time::{MIN_SUPPRESSED_TICKS, TIME_MANAGER};
+    // wfi still wakes up on interrupts masked by primask
+    asm!("cpsid i");
+    let expected = crate::task::expected_idle_ticks() as u32;
+    let ticks = core::cmp::min(expected, SYST_COUNTER_MASK / CLK_RELOAD_VALUE);
+    if ticks < MIN_SUPPRESSED_TICKS as u32 {
+        asm!("cpsie i");
+        sleep();
+        return;
+    }
+    let mut syst = SystemTimer::new();
+    syst.set_config(SYST_CSR_TICKINT | SYST_CSR_CLKSOURCE);
+    // the rest of the current tick comes first
+    let reload = syst.get_time() + CLK_RELOAD_VALUE * (ticks - 1);
+    syst.set_reload(reload);
+    syst.set_time(0);
+    syst.set_config(SYST_CSR_ENABLE | SYST_CSR_TICKINT | SYST_CSR_CLKSOURCE);
+    TIME_MANAGER.set_suppressed_ticks((ticks - 1) as Time);
+    sleep();
+    syst.set_config(SYST_CSR_TICKINT | SYST_CSR_CLKSOURCE);
+    if syst.get_config() & SYST_CSR_COUNTFLAG == 0 {
+        // woken up by another interrupt
+        let elapsed = reload - syst.get_time();
+        TIME_MANAGER.take_suppressed_ticks();
+        TIME_MANAGER.inc_tick((elapsed / CLK_RELOAD_VALUE) as Time);
+        syst.set_reload(CLK_RELOAD_VALUE - elapsed % CLK_RELOAD_VALUE);
+    } else {
+        syst.set_reload(CLK_RELOAD_VALUE);
+    }
+    syst.set_time(0);
+    syst.set_config(SYST_CSR_ENABLE | SYST_CSR_TICKINT | SYST_CSR_CLKSOURCE);
+    // the counter has loaded the partial period already
+    syst.set_reload(CLK_RELOAD_VALUE);
+    asm!("cpsie i");
+}
+
 fn disable_systick() {
     let mut sys_t = SystemTimer::new();
     sys_t.set_config(0);
@@ -754,6 +815,15 @@ pub unsafe extern "C" fn SysTick() {
         && current_generation() < MAX_FAILURE_CNT
     {
         TIME_SINCE_LAST_POWER_FAILURE = 0;
+        // treat the injected failure as a low-energy warning, PendSV will
+        // take the checkpoint and then cut the power
+        #[cfg(feature = "jit_checkpoint")]
+        {
+            let current_task = unsafe { &*get_current_task_ptr().volatile_load() };
+            power_failure_task_update_stats(current_task);
+            crate::checkpoint::low_energy_warning();
+            return;
+        }
         if ctx_sw_pending {
             critical::with_no_interrupt(|cs| {
                 task_switch();
@@ -121,8 +121,10 @@ unsafe extern "msp430-interrupt" fn TickHandler() {
             "push r15",
             "mov  &CURRENT_TASK_PTR, r12",
             "mov  r1, 0(r12)",
+            /* wake up the interrupted context if it's in low power mode */
+            "bic  #0xf0, 24(r1)",
             /* call process tick */
-            "call #process_tick",
+            "call #tick_handler",
             /* restore context */
             "mov  &CURRENT_TASK_PTR, r12",
             "mov  0(r12), r1",
@@ -166,6 +168,8 @@ unsafe extern "msp430-interrupt" fn TickHandler() {
             "push r15",
             "mov  &CURRENT_TASK_PTR, r12",
             "mov  r1, 0(r12)",
+            /* wake up the interrupted context if it's in low power mode */
+            "bic  #0xf0, 24(r1)",
             /* call process tick */
             "call #tick_handler",
             /* restore context */
@@ -202,6 +206,18 @@ unsafe fn new_power_cycle() {
 static mut TIME_SINCE_LAST_CTX_SW: u32 = 0;
 static mut TIME_SINCE_LAST_POWER_FAILURE: u32 = 0;
 
+#[cfg(not(feature = "power_failure"))]
+#[export_name = "tick_handler"]
+unsafe fn tick_handler() {
+    // the idle task may have stretched the period
+    #[cfg(feature = "tickless_idle")]
+    {
+        let timer_a = crate::board::msp430fr5994::peripherals::TimerA::new();
+        timer_a.p.ta0ccr0.write(CLK_RELOAD_VALUE as u16);
+    }
+    process_tick();
+}
+
 #[cfg(feature = "power_failure")]
 #[export_name = "tick_handler"]
 unsafe fn tick_handler() {
@@ -375,6 +391,29 @@ pub fn get_system_clock_cycles() -> u32 {
     }
 }
 
+// LPM0, the tick handler turns the cpu back on
+#[inline(always)]
+pub unsafe fn sleep() {
+    asm!("bis #0x18, r2", "nop");
+}
+
+// Stretch the TimerA period over the idle ticks, the tick handler accounts
+// for the suppressed ticks and restores the period
+#[cfg(feature = "tickless_idle")]
+pub unsafe fn suppress_ticks_and_sleep() {
+    use crate::time::{MIN_SUPPRESSED_TICKS, TIME_MANAGER};
+    disable_interrupt();
+    let expected = crate::task::expected_idle_ticks() as u32;
+    let ticks = core::cmp::min(expected, u16::MAX as u32 / CLK_RELOAD_VALUE);
+    if ticks >= MIN_SUPPRESSED_TICKS as u32 {
+        let timer_a = crate::board::msp430fr5994::peripherals::TimerA::new();
+        timer_a.p.ta0ccr0.write((CLK_RELOAD_VALUE * ticks) as u16);
+        TIME_MANAGER.set_suppressed_ticks((ticks - 1) as Time);
+    }
+    // enables the interrupts as well
+    sleep();
+}
+
 pub fn start_kernel() {
     #[cfg(not(feature = "power_failure"))]
     {
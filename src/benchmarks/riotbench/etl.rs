@@ -180,8 +180,16 @@ fn task_annotate_join(rq: QueueHandle<SensorData>) {
     let (q, mut s2d) = transaction::run_sys(|j, t| {
         let q = syscalls::sys_queue_create(DEFAULT_Q_LENGTH, t).unwrap();
         let sensor_2_data = Ptr::new(SensorData::new(), t);
-        syscalls::sys_create_task_custom("task store", 1, task_store, q, TASK_STORE_PMEM_SZ, t)
-            .unwrap();
+        syscalls::sys_create_task_custom(
+            "task store",
+            1,
+            task_store,
+            q,
+            TASK_STORE_PMEM_SZ,
+            crate::task::DEFAULT_STACK_SIZE,
+            t,
+        )
+        .unwrap();
         (q, sensor_2_data)
     });
 
@@ -218,8 +226,16 @@ fn task_annotate_join(rq: QueueHandle<SensorData>) {
     let (q, mut s2d) = transaction::run_sys(|j, t| {
         let q = syscalls::sys_queue_create(DEFAULT_Q_LENGTH, t).unwrap();
         let sensor_2_data = PBox::new(SensorData::new(), t);
-        syscalls::sys_create_task_custom("task store", 1, task_store, q, TASK_STORE_PMEM_SZ, t)
-            .unwrap();
+        syscalls::sys_create_task_custom(
+            "task store",
+            1,
+            task_store,
+            q,
+            TASK_STORE_PMEM_SZ,
+            crate::task::DEFAULT_STACK_SIZE,
+            t,
+        )
+        .unwrap();
         (q, sensor_2_data)
     });
 
@@ -105,6 +105,7 @@ fn task_sense() {
             task_dt_trainer,
             qs_dt,
             TASK_DT_PMEM_SZ,
+            crate::task::DEFAULT_STACK_SIZE,
             t,
         );
 
@@ -70,4 +70,9 @@ pub(super) const BOOT_PM_HEAP_SIZE: usize = 1024 * 4;
 pub(super) const PM_JOURNAL_SIZE: usize = 1024;
 pub(super) const STACK_SIZE: usize = 1024 * 4;
 pub(super) const TASK_NUM_LIMIT: usize = 6;
+pub(super) const NUM_PRIORITY_LEVELS: usize = 8;
+pub(super) const STACK_ARENA_SIZE: usize = STACK_SIZE * TASK_NUM_LIMIT;
 pub const PM_HEAP_SIZE: usize = PM_HEAP_SIZE_PER_TASK * (crate::task::TASK_NUM_LIMIT - 1);
+// words of live stack a JIT checkpoint can hold per task
+#[cfg(feature = "jit_checkpoint")]
+pub const CHECKPOINT_STACK_SIZE: usize = 1024;
@@ -123,6 +123,14 @@ macro_rules! board {
     };
 }
 
+#[cfg(board = "test")]
+#[macro_export]
+macro_rules! board {
+    ($var: ident) => {
+        crate::board::$var
+    };
+}
+
 #[cfg(board = "apollo4bp")]
 pub const HEAP_SIZE: usize = apollo4bp::HEAP_SIZE;
 #[cfg(board = "apollo4bp")]
@@ -135,6 +143,10 @@ pub const PM_JOURNAL_SIZE: usize = apollo4bp::PM_JOURNAL_SIZE;
 pub const STACK_SIZE: usize = apollo4bp::STACK_SIZE;
 #[cfg(board = "apollo4bp")]
 pub const TASK_NUM_LIMIT: usize = apollo4bp::TASK_NUM_LIMIT;
+#[cfg(board = "apollo4bp")]
+pub const NUM_PRIORITY_LEVELS: usize = apollo4bp::NUM_PRIORITY_LEVELS;
+#[cfg(board = "apollo4bp")]
+pub const STACK_ARENA_SIZE: usize = apollo4bp::STACK_ARENA_SIZE;
 
 #[cfg(board = "qemu")]
 pub const HEAP_SIZE: usize = qemu::HEAP_SIZE;
@@ -148,6 +160,10 @@ pub const PM_JOURNAL_SIZE: usize = qemu::PM_JOURNAL_SIZE;
 pub const STACK_SIZE: usize = qemu::STACK_SIZE;
 #[cfg(board = "qemu")]
 pub const TASK_NUM_LIMIT: usize = qemu::TASK_NUM_LIMIT;
+#[cfg(board = "qemu")]
+pub const NUM_PRIORITY_LEVELS: usize = qemu::NUM_PRIORITY_LEVELS;
+#[cfg(board = "qemu")]
+pub const STACK_ARENA_SIZE: usize = qemu::STACK_ARENA_SIZE;
 
 #[cfg(board = "msp430fr5994")]
 pub const HEAP_SIZE: usize = msp430fr5994::HEAP_SIZE;
@@ -161,6 +177,10 @@ pub const PM_JOURNAL_SIZE: usize = msp430fr5994::PM_JOURNAL_SIZE;
 pub const STACK_SIZE: usize = msp430fr5994::STACK_SIZE;
 #[cfg(board = "msp430fr5994")]
 pub const TASK_NUM_LIMIT: usize = msp430fr5994::TASK_NUM_LIMIT;
+#[cfg(board = "msp430fr5994")]
+pub const NUM_PRIORITY_LEVELS: usize = msp430fr5994::NUM_PRIORITY_LEVELS;
+#[cfg(board = "msp430fr5994")]
+pub const STACK_ARENA_SIZE: usize = msp430fr5994::STACK_ARENA_SIZE;
 
 #[cfg(board = "test")]
 pub const HEAP_SIZE: usize = 512;
@@ -174,3 +194,11 @@ pub const PM_JOURNAL_SIZE: usize = 512;
 pub const STACK_SIZE: usize = 512;
 #[cfg(board = "test")]
 pub const TASK_NUM_LIMIT: usize = 8;
+#[cfg(board = "test")]
+pub const NUM_PRIORITY_LEVELS: usize = 8;
+#[cfg(board = "test")]
+pub const STACK_ARENA_SIZE: usize = STACK_SIZE * TASK_NUM_LIMIT;
+#[cfg(board = "test")]
+pub const CHECKPOINT_STACK_SIZE: usize = 128;
+#[cfg(board = "test")]
+pub const PM_HEAP_SIZE: usize = PM_HEAP_SIZE_PER_TASK * (TASK_NUM_LIMIT - 1);
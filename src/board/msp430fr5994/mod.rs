@@ -103,7 +103,10 @@ pub(super) const PM_HEAP_SIZE_PER_TASK: usize = MSP430FR5994_PM_HEAP_SIZE_PER_TA
 pub(super) const BOOT_PM_HEAP_SIZE: usize = MSP430FR5994_BOOT_PM_HEAP_SIZE;
 pub(super) const PM_JOURNAL_SIZE: usize = MSP430FR5994_PM_JOURNAL_SIZE;
 pub const PM_HEAP_SIZE: usize = MSP430FR5994_PM_HEAP_SIZE;
-pub(super) const TASK_NUM_LIMIT: usize = MSP430FR5994_TASK_NUM_LIMIT;
+// Stacks take SRAM but TCBs FRAM, so the configured limit sizes the stack
+// arena in default stacks and two more TCBs are left for smaller stacks
+pub(super) const TASK_NUM_LIMIT: usize = MSP430FR5994_TASK_NUM_LIMIT + 2;
+pub(super) const NUM_PRIORITY_LEVELS: usize = 8;
 
 // pub(super) const STACK_SIZE: usize = 400;
 #[cfg(not(feature = "power_failure"))]
@@ -112,6 +115,8 @@ pub(super) const STACK_SIZE: usize = 400;
 #[cfg(feature = "power_failure")]
 pub(super) const STACK_SIZE: usize = 500;
 
+pub(super) const STACK_ARENA_SIZE: usize = STACK_SIZE * MSP430FR5994_TASK_NUM_LIMIT;
+
 pub(super) fn init() {
     peripherals::wdt_a_hold();
 
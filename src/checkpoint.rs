@@ -0,0 +1,159 @@
+use core::mem::size_of;
+
+use crate::arch;
+use crate::critical::CriticalSection;
+use crate::task::{self, TASK_NUM_LIMIT};
+use crate::util::compiler_pm_fence;
+use crate::{debug_print, os_dbg_print};
+
+// Just-in-time checkpointing: on a low-energy warning the volatile stacks of
+// checkpoint-mode tasks are copied into the .checkpoint_meta section. The
+// registers of every task are already on its stack when the copy is taken
+// from inside the context switch, so a stack image plus the saved stack top
+// is enough to resume the task after reboot.
+
+// the link scripts of the other boards have a .checkpoint_meta section
+#[cfg(board = "msp430fr5994")]
+compile_error!("JIT checkpointing is not supported on the msp430fr5994 board");
+
+const CHECKPOINT_STACK_SIZE: usize = crate::board!(CHECKPOINT_STACK_SIZE);
+const CHECKPOINT_MAGIC: usize = 0xC4EC;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExecMode {
+    // replay/bypass based on transactions (default)
+    Transactional,
+    // resume from the JIT checkpoint if there is a valid one
+    Checkpoint,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CheckpointSlot {
+    magic: usize,
+    generation: usize,
+    // 0 means invalid
+    stack_top: usize,
+}
+
+impl CheckpointSlot {
+    const fn new() -> Self {
+        Self {
+            magic: 0,
+            generation: 0,
+            stack_top: 0,
+        }
+    }
+
+    fn is_valid_for(&self, generation: usize) -> bool {
+        self.magic == CHECKPOINT_MAGIC && self.stack_top != 0 && self.generation == generation
+    }
+}
+
+// The section is not initialized by the startup code, a slot is only trusted
+// if the magic number matches
+#[link_section = ".checkpoint_meta"]
+static mut CHECKPOINT_SLOTS: [CheckpointSlot; TASK_NUM_LIMIT] =
+    [CheckpointSlot::new(); TASK_NUM_LIMIT];
+
+#[link_section = ".checkpoint_meta"]
+static mut CHECKPOINT_STACKS: [[usize; CHECKPOINT_STACK_SIZE]; TASK_NUM_LIMIT] =
+    [[0; CHECKPOINT_STACK_SIZE]; TASK_NUM_LIMIT];
+
+// volatile, set by the low-energy warning
+static mut CHECKPOINT_REQUESTED: bool = false;
+
+// invalidate stale checkpoints of a previous image, called during first boot
+pub fn init() {
+    unsafe {
+        for slot in CHECKPOINT_SLOTS.iter_mut() {
+            slot.stack_top = 0;
+        }
+    }
+}
+
+// Entry point of the low-energy warning (e.g. a comparator interrupt).
+// The checkpoint itself is taken in the next context switch.
+pub fn low_energy_warning() {
+    unsafe {
+        CHECKPOINT_REQUESTED = true;
+    }
+    arch::arch_yield();
+}
+
+pub fn is_checkpoint_requested() -> bool {
+    unsafe { CHECKPOINT_REQUESTED }
+}
+
+// Must be called from the context switch after the context of the current
+// task is pushed onto its stack. Does not return.
+pub unsafe fn checkpoint_and_wait_for_power_failure(cs: &CriticalSection) -> ! {
+    take_checkpoint(cs);
+    CHECKPOINT_REQUESTED = false;
+    arch::arch_wait_for_power_failure()
+}
+
+pub unsafe fn take_checkpoint(_cs: &CriticalSection) {
+    for task_ptr in task::iter_tasks() {
+        let t = task_ptr.as_ref();
+        // only stacks which survived since the last recovery are meaningful
+        if t.get_exec_mode() == ExecMode::Checkpoint && !t.is_crashed() {
+            let (stack_top, stack_bottom) = t.get_stack_region();
+            save_stack(t.get_task_id(), t.get_generation(), stack_top, stack_bottom);
+        }
+    }
+    os_dbg_print!("JIT checkpoint taken");
+}
+
+fn save_stack(tid: usize, generation: usize, stack_top: usize, stack_bottom: usize) {
+    unsafe {
+        let slot = &mut CHECKPOINT_SLOTS[tid];
+        slot.stack_top = 0;
+        compiler_pm_fence();
+        let n = (stack_bottom - stack_top) / size_of::<usize>();
+        if n >= CHECKPOINT_STACK_SIZE {
+            // leave the slot invalid, the task falls back to replay
+            os_dbg_print!("Stack of task {} is too deep for a JIT checkpoint", tid);
+            return;
+        }
+        let dst = shadow_addr_of(tid, stack_top, stack_bottom);
+        core::ptr::copy_nonoverlapping(stack_top as *const usize, dst as *mut usize, n);
+        slot.generation = generation;
+        slot.magic = CHECKPOINT_MAGIC;
+        compiler_pm_fence();
+        // commit
+        slot.stack_top = stack_top;
+    }
+}
+
+// Copy the stack image back, return the saved stack top on success
+pub fn restore_stack(tid: usize, generation: usize, stack_bottom: usize) -> Option<usize> {
+    unsafe {
+        let slot = &CHECKPOINT_SLOTS[tid];
+        if !slot.is_valid_for(generation) {
+            return None;
+        }
+        let stack_top = slot.stack_top;
+        let src = shadow_addr_of(tid, stack_top, stack_bottom);
+        let n = (stack_bottom - stack_top) / size_of::<usize>();
+        core::ptr::copy_nonoverlapping(src as *const usize, stack_top as *mut usize, n);
+        debug_print!("Restored task {} from JIT checkpoint", tid);
+        Some(stack_top)
+    }
+}
+
+// A checkpoint can only be resumed once
+pub fn invalidate(tid: usize) {
+    unsafe {
+        CHECKPOINT_SLOTS[tid].stack_top = 0;
+    }
+}
+
+// shadow stacks mirror the top part of the task stacks
+fn shadow_addr_of(tid: usize, stack_top: usize, stack_bottom: usize) -> usize {
+    unsafe {
+        let shadow_bottom =
+            &CHECKPOINT_STACKS[tid][CHECKPOINT_STACK_SIZE - 1] as *const usize as usize;
+        shadow_bottom - (stack_bottom - stack_top)
+    }
+}
@@ -0,0 +1,204 @@
+use crate::critical::{self, CriticalSection};
+use crate::list::{self, InsertSortedPList, Node, SortedPList};
+use crate::pmem::{JournalHandle, PMPtr};
+use crate::semaphore::{self, Semaphore};
+use crate::task::{self, current, BlockedListItem, SchedListItem, Task, TASK_NUM_LIMIT};
+use crate::{
+    arch::arch_yield,
+    heap,
+    time::{self, Time},
+    transaction,
+};
+
+const MUTEX_WAIT_TIME: Time = (Time::MAX) >> 2;
+
+// A notified waiter stays in `notified` until it holds the mutex again, so a
+// waiter which crashes in between still sees the notification when it waits
+// again.
+pub struct CondVar {
+    wait_list: SortedPList<BlockedListItem>,
+    notified: [Option<PMPtr<Task>>; TASK_NUM_LIMIT],
+}
+
+impl CondVar {
+    pub fn new(j: JournalHandle) -> Option<PMPtr<CondVar>> {
+        heap::pm_new(
+            CondVar {
+                wait_list: SortedPList::new(),
+                notified: [None; TASK_NUM_LIMIT],
+            },
+            j,
+        )
+    }
+
+    fn is_notified(&self, task: PMPtr<Task>) -> bool {
+        self.notified.contains(&Some(task))
+    }
+
+    // Without a free slot the waiter only sees a spurious wakeup
+    fn mark_notified(
+        notified: &mut [Option<PMPtr<Task>>; TASK_NUM_LIMIT],
+        task: PMPtr<Task>,
+        j: JournalHandle,
+    ) {
+        if notified.contains(&Some(task)) {
+            return;
+        }
+        if let Some(slot) = notified.iter().position(|n| n.is_none()) {
+            j.get_mut()
+                .append_log_of(&mut notified[slot] as *mut Option<PMPtr<Task>>);
+            notified[slot] = Some(task);
+        }
+    }
+
+    fn take_notification(&mut self, task: PMPtr<Task>, j: JournalHandle) -> bool {
+        match self.notified.iter().position(|n| *n == Some(task)) {
+            Some(slot) => {
+                j.get_mut()
+                    .append_log_of(&mut self.notified[slot] as *mut Option<PMPtr<Task>>);
+                self.notified[slot] = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    fn block(&mut self, j: JournalHandle, cs: &CriticalSection) -> &mut Node<SchedListItem> {
+        let task = task::current();
+        let link = task.get_event_node_ptr();
+        let node = task.remove_from_ready_list(j, cs);
+        self.wait_list.insert(cs, j, link);
+        node
+    }
+}
+
+pub fn create_condvar() -> Option<PMPtr<CondVar>> {
+    transaction::run(|j| CondVar::new(j))
+}
+
+// Give the mutex and sleep on the wait list in one go, so no notification
+// slips in between. Returns whether the task sleeps.
+#[cfg(not(feature = "opt_list"))]
+fn release_and_block(
+    cv: &mut CondVar,
+    sem: PMPtr<Semaphore>,
+    wait_ticks: Time,
+    cs: &CriticalSection,
+) -> bool {
+    semaphore::mutex_give_if_held(sem, cs);
+    if wait_ticks == 0 {
+        return false;
+    }
+    let task = task::current();
+    let wakeup_time = time::TIME_MANAGER
+        .get_ticks()
+        .checked_add(wait_ticks)
+        .unwrap();
+    current().get_mut_tx().run_no_replay(|j| {
+        let sched_node = cv.block(j, cs);
+        task.set_wakeup_time(wakeup_time);
+        task.add_node_to_delayed_list(sched_node, j, cs);
+    });
+    true
+}
+
+#[cfg(feature = "opt_list")]
+fn release_and_block(
+    cv: &mut CondVar,
+    sem: PMPtr<Semaphore>,
+    wait_ticks: Time,
+    cs: &CriticalSection,
+) -> bool {
+    semaphore::mutex_give_if_held(sem, cs);
+    if wait_ticks == 0 {
+        return false;
+    }
+    let wakeup_time = time::TIME_MANAGER
+        .get_ticks()
+        .checked_add(wait_ticks)
+        .unwrap();
+    list::atomic_roll_forward_insert_into_waitlist(
+        &mut cv.wait_list,
+        task::current(),
+        wakeup_time,
+        cs,
+    );
+    true
+}
+
+// Give the mutex, sleep until notified and take the mutex again. Err if no
+// notification came within `wait_ticks`.
+pub fn condvar_wait(
+    mut cv_ptr: PMPtr<CondVar>,
+    sem: PMPtr<Semaphore>,
+    wait_ticks: Time,
+) -> Result<(), ()> {
+    let me = current().as_pm_ptr();
+    let blocked = critical::with_no_interrupt(|cs| {
+        let cv = unsafe { cv_ptr.as_mut_no_logging() };
+        // notified before a crash, the replayed caller may hold the mutex
+        // again already
+        if cv.is_notified(me) {
+            return false;
+        }
+        release_and_block(cv, sem, wait_ticks, cs)
+    });
+    if blocked {
+        arch_yield();
+    }
+    // a mutex is not taken twice by its holder
+    if !sem.as_ref().is_held_by_current() {
+        while let Err(_) = semaphore::semaphore_take(sem, MUTEX_WAIT_TIME) {}
+    }
+    let notified = critical::with_no_interrupt(|_| {
+        current().get_mut_tx().run_no_replay(|j| {
+            unsafe { cv_ptr.as_mut_no_logging() }.take_notification(me, j)
+        })
+    });
+    if notified {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+#[cfg(not(feature = "opt_list"))]
+pub fn condvar_notify(mut cv_ptr: PMPtr<CondVar>, all: bool) {
+    critical::with_no_interrupt(|cs| {
+        current().get_mut_tx().run_no_replay(|j| {
+            let cv = unsafe { cv_ptr.as_mut_no_logging() };
+            while let Some(node) = cv.wait_list.pop_front(cs, j) {
+                let task = node.value.get_mut_task();
+                CondVar::mark_notified(&mut cv.notified, task.as_pm_ptr(), j);
+                let n = task.remove_from_delayed_list(j, cs);
+                task.add_node_to_ready_list(n, j, cs);
+                if !all {
+                    break;
+                }
+            }
+        })
+    });
+}
+
+// The waiters are marked before they are woken. A crash in between leaves a
+// marked waiter asleep, it takes the notification once it wakes up.
+#[cfg(feature = "opt_list")]
+pub fn condvar_notify(mut cv_ptr: PMPtr<CondVar>, all: bool) {
+    critical::with_no_interrupt(|cs| {
+        let cv = unsafe { cv_ptr.as_mut_no_logging() };
+        current().get_mut_tx().run_no_replay(|j| {
+            for item in cv.wait_list.iter() {
+                CondVar::mark_notified(&mut cv.notified, item.get_task().as_pm_ptr(), j);
+                if !all {
+                    break;
+                }
+            }
+        });
+        if all {
+            list::atomic_roll_forward_pop_remove_all_from_waitlist(&mut cv.wait_list, cs);
+        } else {
+            list::atomic_roll_forward_pop_remove_from_waitlist(&mut cv.wait_list, cs);
+        }
+    });
+}
@@ -3,9 +3,9 @@ use crate::list;
 use crate::{
     arch,
     critical::{self, CriticalSection},
-    heap,
+    declare_pm_var, heap,
     list::UnsortedPList,
-    task::{current, BlockedListItem},
+    task::{self, current, BlockedListItem},
     time::TIME_MANAGER,
     transaction,
 };
@@ -14,7 +14,7 @@ use crate::{
     marker::TxOutSafe,
     pmem::{JournalHandle, PMPtr, PMVar},
     time::Time,
-    util::debug_syscall_tx_cache,
+    util::{compiler_pm_fence, debug_syscall_tx_cache},
 };
 
 pub type EventBits = usize;
@@ -22,35 +22,60 @@ pub type EventBits = usize;
 #[cfg(target_pointer_width = "32")]
 const CLEAR_EVENTS_ON_EXIT_BIT: usize = 0x01000000;
 #[cfg(target_pointer_width = "32")]
-const UNBLOCKED_DUE_TO_BIT_SET: usize = 0x02000000;
+pub const UNBLOCKED_DUE_TO_BIT_SET: usize = 0x02000000;
 #[cfg(target_pointer_width = "32")]
 const WAIT_FOR_ALL_BITS: usize = 0x04000000;
 #[cfg(target_pointer_width = "32")]
+pub const UNBLOCKED_DUE_TO_DELETE: usize = 0x08000000;
+#[cfg(target_pointer_width = "32")]
 const EVENT_BITS_CONTROL_BYTES: usize = 0xff000000;
 
 #[cfg(any(target_pointer_width = "16", target_pointer_width = "64"))]
 const CLEAR_EVENTS_ON_EXIT_BIT: usize = 0x0100;
 #[cfg(any(target_pointer_width = "16", target_pointer_width = "64"))]
-const UNBLOCKED_DUE_TO_BIT_SET: usize = 0x0200;
+pub const UNBLOCKED_DUE_TO_BIT_SET: usize = 0x0200;
 #[cfg(any(target_pointer_width = "16", target_pointer_width = "64"))]
 const WAIT_FOR_ALL_BITS: usize = 0x0400;
 #[cfg(any(target_pointer_width = "16", target_pointer_width = "64"))]
+pub const UNBLOCKED_DUE_TO_DELETE: usize = 0x0800;
+#[cfg(any(target_pointer_width = "16", target_pointer_width = "64"))]
 const EVENT_BITS_CONTROL_BYTES: usize = 0xff00;
 
+declare_pm_var!(NEXT_EVENT_GROUP_GENERATION, usize, 1);
+
+// Sets deferred by interrupt handlers until the timer daemon applies them.
+// A slot is filled before the tail moves past it, so a power failure never
+// exposes a half-written slot.
+const DEFERRED_SET_SLOTS: usize = 8;
+declare_pm_var!(
+    DEFERRED_SETS,
+    [Option<(EventGroupHandle, EventBits)>; DEFERRED_SET_SLOTS],
+    [None; DEFERRED_SET_SLOTS]
+);
+declare_pm_var!(DEFERRED_SET_HEAD, usize, 0);
+declare_pm_var!(DEFERRED_SET_TAIL, usize, 0);
+
 #[cfg(not(feature = "opt_list"))]
 pub struct EventGroup {
     event_bits: EventBits, // 0xAAXXXXXX: AA is the control byte
     waiting_tasks: UnsortedPList<BlockedListItem>,
+    // matched against the handles, 0 once the group is deleted
+    generation: usize,
 }
 
 #[cfg(feature = "opt_list")]
 pub struct EventGroup {
     event_bits: PMVar<EventBits>, // 0xAAXXXXXX: AA is the control byte
     waiting_tasks: UnsortedPList<BlockedListItem>,
+    // matched against the handles, 0 once the group is deleted
+    generation: usize,
 }
 
 impl EventGroup {
     fn new_from_heap(j: JournalHandle) -> Option<PMPtr<EventGroup>> {
+        let next_gen = unsafe { NEXT_EVENT_GROUP_GENERATION.borrow_mut(j) };
+        let generation = *next_gen;
+        *next_gen += 1;
         heap::pm_new(
             EventGroup {
                 #[cfg(not(feature = "opt_list"))]
@@ -58,11 +83,23 @@ impl EventGroup {
                 #[cfg(feature = "opt_list")]
                 event_bits: unsafe { PMVar::new(0) },
                 waiting_tasks: UnsortedPList::new(),
+                generation,
             },
             j,
         )
     }
 
+    #[inline(always)]
+    fn is_alive(&self, gen: usize) -> bool {
+        gen != 0 && self.generation == gen
+    }
+
+    // The memory stays, like that of a deleted queue
+    fn release(&mut self, j: JournalHandle) {
+        j.get_mut().append_log_of(&mut self.generation as *mut usize);
+        self.generation = 0;
+    }
+
     #[cfg(not(feature = "opt_list"))]
     #[inline(always)]
     fn get_event_bits(&self, _: &CriticalSection) -> EventBits {
@@ -125,6 +162,7 @@ impl EventGroup {
     #[cfg(feature = "opt_list")]
     fn wait_for_event_group_bits(
         mut eg_ptr: PMPtr<EventGroup>,
+        gen: usize,
         bits_to_wait_for: EventBits,
         clear_on_exit: bool,
         wait_for_all_bits: bool,
@@ -142,6 +180,9 @@ impl EventGroup {
             crate::os_dbg_print!("Failed just before awaken...");
             return Ok(bits & !EVENT_BITS_CONTROL_BYTES);
         }
+        if bits & UNBLOCKED_DUE_TO_DELETE != 0 {
+            return Err(bits & !EVENT_BITS_CONTROL_BYTES);
+        }
 
         let eg = eg_ptr.as_ref();
         if clear_on_exit {
@@ -150,7 +191,10 @@ impl EventGroup {
         if wait_for_all_bits {
             control_bits |= WAIT_FOR_ALL_BITS;
         }
-        critical::with_no_interrupt(|cs| {
+        let alive = critical::with_no_interrupt(|cs| {
+            if !eg.is_alive(gen) {
+                return false;
+            }
             if eg.wait_condition_ok(bits_to_wait_for, wait_for_all_bits, cs) {
                 res = eg.get_event_bits(cs);
                 if clear_on_exit {
@@ -172,7 +216,11 @@ impl EventGroup {
             } else {
                 res = eg.get_event_bits(cs);
             }
+            true
         });
+        if !alive {
+            return Err(0);
+        }
 
         if wait_ticks > 0 {
             arch::arch_yield();
@@ -180,6 +228,9 @@ impl EventGroup {
             let event_bits = task.get_block_item_value();
             // debug_print!("Event bits: {:#X}", event_bits);
             res = event_bits & !EVENT_BITS_CONTROL_BYTES;
+            if event_bits & UNBLOCKED_DUE_TO_DELETE != 0 {
+                return Err(res);
+            }
             if event_bits & UNBLOCKED_DUE_TO_BIT_SET == 0 {
                 let ok = critical::with_no_interrupt(|cs| {
                     // debug_print!("Bits: {}, bits_to_wait_for: {}", eg.get_event_bits(cs) & !EVENT_BITS_CONTROL_BYTES, bits_to_wait_for);
@@ -206,6 +257,7 @@ impl EventGroup {
     #[cfg(not(feature = "opt_list"))]
     fn wait_for_event_group_bits(
         &mut self,
+        gen: usize,
         bits_to_wait_for: EventBits,
         clear_on_exit: bool,
         wait_for_all_bits: bool,
@@ -221,7 +273,10 @@ impl EventGroup {
         if wait_for_all_bits {
             control_bits |= WAIT_FOR_ALL_BITS;
         }
-        critical::with_no_interrupt(|cs| {
+        let alive = critical::with_no_interrupt(|cs| {
+            if !self.is_alive(gen) {
+                return false;
+            }
             if self.wait_condition_ok(bits_to_wait_for, wait_for_all_bits, cs) {
                 res = self.get_event_bits(cs);
                 if clear_on_exit {
@@ -240,7 +295,11 @@ impl EventGroup {
             } else {
                 res = self.get_event_bits(cs);
             }
+            true
         });
+        if !alive {
+            return Err(0);
+        }
 
         if wait_ticks > 0 {
             arch::arch_yield();
@@ -248,6 +307,9 @@ impl EventGroup {
             let event_bits = task.reset_block_item_value();
             debug_print!("Event bits: {:#X}", event_bits);
             res = event_bits & !EVENT_BITS_CONTROL_BYTES;
+            if event_bits & UNBLOCKED_DUE_TO_DELETE != 0 {
+                return Err(res);
+            }
             if event_bits & UNBLOCKED_DUE_TO_BIT_SET == 0 {
                 let ok = critical::with_no_interrupt(|cs| {
                     debug_print!("Checking...");
@@ -275,11 +337,15 @@ impl EventGroup {
     #[cfg(not(feature = "opt_list"))]
     fn set_event_group_bits(
         mut eg_ptr: PMPtr<EventGroup>,
+        gen: usize,
         bits_to_set: EventBits,
         j: JournalHandle,
     ) {
         let mut yld_to_higher_prio = false;
         critical::with_no_interrupt(|cs| {
+            if !eg_ptr.as_ref().is_alive(gen) {
+                return;
+            }
             // set event bits
             let eg = eg_ptr.as_mut(j);
             eg.set_event_bits(bits_to_set, cs);
@@ -333,7 +399,7 @@ impl EventGroup {
     }
 
     #[cfg(feature = "opt_list")]
-    fn set_event_group_bits(mut eg_ptr: PMPtr<EventGroup>, bits_to_set: EventBits) {
+    fn set_event_group_bits(mut eg_ptr: PMPtr<EventGroup>, gen: usize, bits_to_set: EventBits) {
         use crate::os_print;
 
         let mut yld_to_higher_prio = false;
@@ -341,6 +407,9 @@ impl EventGroup {
         critical::with_no_interrupt(|cs| {
             // set event bits
             let eg = unsafe { eg_ptr.as_mut_no_logging() };
+            if !eg.is_alive(gen) {
+                return;
+            }
             let bits_to_set = bits_to_set & (!EVENT_BITS_CONTROL_BYTES);
             let mut bits_to_clear: usize = 0;
             // debug_print!("Waiting list: \n {}", eg.waiting_tasks);
@@ -395,6 +464,7 @@ impl EventGroup {
     #[cfg(not(feature = "opt_list"))]
     fn sync_event_group_bits(
         mut eg_ptr: PMPtr<EventGroup>,
+        gen: usize,
         bits_to_set: EventBits,
         bits_to_wait_for: EventBits,
         wait_ticks: Time,
@@ -403,9 +473,12 @@ impl EventGroup {
         let mut ret = 0;
         let mut wait_ticks = wait_ticks;
         let eg = eg_ptr.as_mut(j);
-        critical::with_no_interrupt(|cs| {
+        let alive = critical::with_no_interrupt(|cs| {
+            if !eg.is_alive(gen) {
+                return false;
+            }
             let original_bits = eg.get_event_bits(cs);
-            EventGroup::set_event_group_bits(eg_ptr, bits_to_set, j);
+            EventGroup::set_event_group_bits(eg_ptr, gen, bits_to_set, j);
 
             if ((original_bits | bits_to_set) & bits_to_wait_for) == bits_to_wait_for {
                 ret = original_bits | bits_to_set;
@@ -427,7 +500,11 @@ impl EventGroup {
             } else {
                 ret = eg.get_event_bits(cs);
             }
+            true
         });
+        if !alive {
+            return Err(0);
+        }
 
         if wait_ticks > 0 {
             // Oh no we need to block
@@ -435,6 +512,9 @@ impl EventGroup {
             let task = current();
             let event_bits = task.reset_block_item_value();
             ret = event_bits & !EVENT_BITS_CONTROL_BYTES;
+            if event_bits & UNBLOCKED_DUE_TO_DELETE != 0 {
+                return Err(ret);
+            }
             if event_bits & UNBLOCKED_DUE_TO_BIT_SET == 0 {
                 // check  whether the bits are set anyway
                 let ok = critical::with_no_interrupt(|cs| {
@@ -457,6 +537,7 @@ impl EventGroup {
     #[cfg(feature = "opt_list")]
     fn sync_event_group_bits(
         mut eg_ptr: PMPtr<EventGroup>,
+        gen: usize,
         bits_to_set: EventBits,
         bits_to_wait_for: EventBits,
         wait_ticks: Time,
@@ -466,10 +547,13 @@ impl EventGroup {
         let mut ret = 0;
         let mut wait_ticks = wait_ticks;
         let eg = unsafe { eg_ptr.as_mut_no_logging() };
-        critical::with_no_interrupt(|cs| {
+        let alive = critical::with_no_interrupt(|cs| {
+            if !eg.is_alive(gen) {
+                return false;
+            }
             let original_bits = transaction::run(|_| eg.get_event_bits(cs));
 
-            EventGroup::set_event_group_bits(eg_ptr, bits_to_set);
+            EventGroup::set_event_group_bits(eg_ptr, gen, bits_to_set);
 
             if ((original_bits | bits_to_set) & bits_to_wait_for) == bits_to_wait_for {
                 ret = original_bits | bits_to_set;
@@ -495,7 +579,11 @@ impl EventGroup {
             } else {
                 ret = eg.get_event_bits(cs);
             }
+            true
         });
+        if !alive {
+            return Err(0);
+        }
 
         if wait_ticks > 0 {
             // Oh no we need to block
@@ -503,6 +591,9 @@ impl EventGroup {
             let task = current();
             let event_bits = task.reset_block_item_value();
             ret = event_bits & !EVENT_BITS_CONTROL_BYTES;
+            if event_bits & UNBLOCKED_DUE_TO_DELETE != 0 {
+                return Err(ret);
+            }
             if event_bits & UNBLOCKED_DUE_TO_BIT_SET == 0 {
                 // check  whether the bits are set anyway
                 let ok = critical::with_no_interrupt(|cs| {
@@ -525,10 +616,80 @@ impl EventGroup {
         }
         Ok(ret)
     }
+
+    // Wake every waiter with an error, then free the group
+    #[cfg(not(feature = "opt_list"))]
+    fn delete_event_group(
+        mut eg_ptr: PMPtr<EventGroup>,
+        gen: usize,
+        j: JournalHandle,
+    ) -> Result<(), ()> {
+        let mut yld_to_higher_prio = false;
+        let r = critical::with_no_interrupt(|cs| {
+            let eg = eg_ptr.as_mut(j);
+            if !eg.is_alive(gen) {
+                return Err(());
+            }
+            let bits = eg.get_event_bits(cs);
+            let waiting_list = &mut eg.waiting_tasks;
+            for node_ptr in waiting_list.iter_mut() {
+                let task = node_ptr.as_ref().value.get_task();
+                if task.get_priority() > current().get_priority() {
+                    yld_to_higher_prio = true;
+                }
+                let block_item = &mut waiting_list.remove(cs, j, node_ptr).value;
+                let sched_node = task.remove_from_delayed_list(j, cs);
+                task.add_node_to_ready_list(sched_node, j, cs);
+                block_item.set_opaque_value(bits | UNBLOCKED_DUE_TO_DELETE);
+            }
+            eg.release(j);
+            Ok(())
+        });
+        if yld_to_higher_prio {
+            arch_yield();
+        }
+        r
+    }
+
+    // The waiters are woken while the group is still alive, so a crash in
+    // between leaves a live group. They see the delete bit once they run.
+    #[cfg(feature = "opt_list")]
+    fn delete_event_group(mut eg_ptr: PMPtr<EventGroup>, gen: usize) -> Result<(), ()> {
+        let mut yld_to_higher_prio = false;
+        let r = critical::with_no_interrupt(|cs| {
+            // cached, so a replay after the release still succeeds
+            if !transaction::run(move |_| eg_ptr.as_ref().is_alive(gen)) {
+                return Err(());
+            }
+            let eg = unsafe { eg_ptr.as_mut_no_logging() };
+            let bits = eg.get_event_bits(cs);
+            let waiting_list = &mut eg.waiting_tasks;
+            for mut node_ptr in waiting_list.iter_mut() {
+                let block_item = unsafe { &mut node_ptr.as_mut_no_logging().value };
+                let task = block_item.get_task();
+                if task.get_priority() > current().get_priority() {
+                    yld_to_higher_prio = true;
+                }
+                block_item.set_opaque_value(bits | UNBLOCKED_DUE_TO_DELETE);
+                list::atomic_roll_forward_remove_from_unsorted_waitlist(waiting_list, node_ptr, cs);
+            }
+            transaction::run(move |j| {
+                unsafe { eg_ptr.as_mut_no_logging() }.release(j);
+            });
+            Ok(())
+        });
+        if yld_to_higher_prio {
+            arch_yield();
+        }
+        r
+    }
 }
 
 #[derive(Clone, Copy)]
-pub struct EventGroupHandle(PMPtr<EventGroup>);
+pub struct EventGroupHandle {
+    ptr: PMPtr<EventGroup>,
+    gen: usize,
+}
 
 unsafe impl TxOutSafe for EventGroupHandle {}
 
@@ -536,7 +697,12 @@ unsafe impl Sync for EventGroupHandle {}
 unsafe impl Send for EventGroupHandle {}
 
 pub fn create_event_group() -> Option<EventGroupHandle> {
-    transaction::run(|j| EventGroup::new_from_heap(j).map(|ptr| EventGroupHandle(ptr)))
+    transaction::run(|j| {
+        EventGroup::new_from_heap(j).map(|ptr| EventGroupHandle {
+            ptr,
+            gen: ptr.as_ref().generation,
+        })
+    })
 }
 
 pub fn event_group_wait(
@@ -546,12 +712,14 @@ pub fn event_group_wait(
     wait_for_all_bits: bool,
     wait_ticks: Time,
 ) -> Result<EventBits, EventBits> {
+    let EventGroupHandle { ptr, gen } = event_grp;
     #[cfg(not(feature = "opt_list"))]
     {
-        let mut eg_ptr = event_grp.0;
+        let mut eg_ptr = ptr;
         transaction::run(move |j| {
             let event_grp = eg_ptr.as_mut(j);
             let r = event_grp.wait_for_event_group_bits(
+                gen,
                 bits_to_wait_for,
                 clear_on_exit,
                 wait_for_all_bits,
@@ -564,7 +732,8 @@ pub fn event_group_wait(
     #[cfg(feature = "opt_list")]
     {
         EventGroup::wait_for_event_group_bits(
-            event_grp.0,
+            ptr,
+            gen,
             bits_to_wait_for,
             clear_on_exit,
             wait_for_all_bits,
@@ -579,13 +748,13 @@ pub fn event_group_set(event_grp: EventGroupHandle, bits_to_set: EventBits) {
     #[cfg(not(feature = "opt_list"))]
     {
         transaction::run(|j| {
-            EventGroup::set_event_group_bits(event_grp.0, bits_to_set, j);
+            EventGroup::set_event_group_bits(event_grp.ptr, event_grp.gen, bits_to_set, j);
         });
     }
 
     #[cfg(feature = "opt_list")]
     {
-        EventGroup::set_event_group_bits(event_grp.0, bits_to_set);
+        EventGroup::set_event_group_bits(event_grp.ptr, event_grp.gen, bits_to_set);
     }
 }
 
@@ -595,11 +764,13 @@ pub fn event_group_sync(
     bits_to_wait_for: EventBits,
     wait_ticks: Time,
 ) -> Result<EventBits, EventBits> {
+    let EventGroupHandle { ptr, gen } = event_grp;
     #[cfg(not(feature = "opt_list"))]
     {
         transaction::run(|j| {
             let r = EventGroup::sync_event_group_bits(
-                event_grp.0,
+                ptr,
+                gen,
                 bits_to_set,
                 bits_to_wait_for,
                 wait_ticks,
@@ -610,5 +781,96 @@ pub fn event_group_sync(
     }
 
     #[cfg(feature = "opt_list")]
-    EventGroup::sync_event_group_bits(event_grp.0, bits_to_set, bits_to_wait_for, wait_ticks)
+    EventGroup::sync_event_group_bits(ptr, gen, bits_to_set, bits_to_wait_for, wait_ticks)
+}
+
+// Returns the bits as they were before clearing
+pub fn event_group_clear(
+    event_grp: EventGroupHandle,
+    bits_to_clear: EventBits,
+) -> Result<EventBits, ()> {
+    let EventGroupHandle { mut ptr, gen } = event_grp;
+    let bits_to_clear = bits_to_clear & !EVENT_BITS_CONTROL_BYTES;
+    critical::with_no_interrupt(|cs| {
+        transaction::run(move |j| {
+            #[cfg(not(feature = "opt_list"))]
+            let eg = ptr.as_mut(j);
+            #[cfg(feature = "opt_list")]
+            let eg = unsafe { ptr.as_mut_no_logging() };
+            if !eg.is_alive(gen) {
+                return Err(());
+            }
+            let old_bits = eg.get_event_bits(cs);
+            #[cfg(not(feature = "opt_list"))]
+            eg.clear_event_bits(bits_to_clear, cs);
+            #[cfg(feature = "opt_list")]
+            eg.clear_event_bits(bits_to_clear, j, cs);
+            Ok(old_bits)
+        })
+    })
+}
+
+pub fn event_group_get(event_grp: EventGroupHandle) -> Result<EventBits, ()> {
+    critical::with_no_interrupt(|cs| {
+        let eg = event_grp.ptr.as_ref();
+        if !eg.is_alive(event_grp.gen) {
+            return Err(());
+        }
+        Ok(eg.get_event_bits(cs))
+    })
+}
+
+pub fn event_group_delete(event_grp: EventGroupHandle) -> Result<(), ()> {
+    #[cfg(not(feature = "opt_list"))]
+    {
+        transaction::run(|j| EventGroup::delete_event_group(event_grp.ptr, event_grp.gen, j))
+    }
+
+    #[cfg(feature = "opt_list")]
+    EventGroup::delete_event_group(event_grp.ptr, event_grp.gen)
+}
+
+// For interrupt handlers. A set wakes waiters and has to be replayed from the
+// cache of a task, which a handler doesn't have; only the wakeup of the
+// daemon is made atomic, by a one-off transaction or a roll forward. The set
+// is queued for the timer daemon, so it fails if there is none or the slots
+// are used up.
+pub fn event_group_set_from_isr(
+    event_grp: EventGroupHandle,
+    bits_to_set: EventBits,
+) -> Result<(), ()> {
+    critical::with_no_interrupt(|cs| unsafe {
+        let daemon = TIME_MANAGER.get_timer_daemon_task().ok_or(())?;
+        let tail = *DEFERRED_SET_TAIL;
+        let next = (tail + 1) % DEFERRED_SET_SLOTS;
+        if next == *DEFERRED_SET_HEAD {
+            return Err(());
+        }
+        DEFERRED_SETS.borrow_mut_no_logging()[tail] = Some((event_grp, bits_to_set));
+        compiler_pm_fence();
+        *DEFERRED_SET_TAIL.borrow_mut_no_logging() = next;
+        task::wake_task_from_isr(daemon, cs);
+        Ok(())
+    })
+}
+
+pub fn has_deferred_sets() -> bool {
+    unsafe { *DEFERRED_SET_HEAD != *DEFERRED_SET_TAIL }
+}
+
+// Apply the oldest deferred set, false if there is none. The slot is only
+// released after the set, which a replay after a crash in between finds
+// cached.
+pub fn apply_deferred_set() -> bool {
+    let head = unsafe { *DEFERRED_SET_HEAD };
+    if head == unsafe { *DEFERRED_SET_TAIL } {
+        return false;
+    }
+    if let Some((event_grp, bits_to_set)) = unsafe { (*DEFERRED_SETS)[head] } {
+        event_group_set(event_grp, bits_to_set);
+    }
+    transaction::run(move |j| unsafe {
+        *DEFERRED_SET_HEAD.borrow_mut(j) = (head + 1) % DEFERRED_SET_SLOTS;
+    });
+    true
 }
@@ -155,6 +155,10 @@ impl BumpAllocator {
         }
     }
 
+    fn region(&self) -> (usize, usize) {
+        (self.heap_start, self.heap_end - self.heap_start)
+    }
+
     fn per_task_alloc(&mut self, layout: Layout) -> *mut u8 {
         let alloc_start = align_up(self.next, layout.align());
         // debug_assert!(alloc_start % crate::arch::ARCH_ALIGN == 0);
@@ -316,6 +320,38 @@ pub unsafe fn create_per_task_pm_heap(
     Ok(())
 }
 
+// The heap region of a deleted task stays with its TCB slot. The next task
+// created in the slot takes it over if it's large enough and nothing was
+// allocated from it, as objects of the deleted task may still be in use.
+// Otherwise a new region is carved from the global heap and the old one is
+// leaked.
+pub unsafe fn recycle_per_task_pm_heap(
+    heap: &mut PMHeap<PerTaskPMBumpAllocator>,
+    size: usize,
+    j: JournalHandle,
+) -> Result<(), ()> {
+    let (start, capacity) = heap.allocator.bump.region();
+    if size <= capacity && heap.stat().mem_used == 0 {
+        heap.allocator.init(start, capacity);
+        return Ok(());
+    }
+    create_per_task_pm_heap(heap, size, j)
+}
+
+// Memory owned by the kernel rather than by a task, e.g. the TCBs of dynamic
+// tasks. It's never given back.
+pub unsafe fn palloc_global<T>(j: JournalHandle) -> Option<PMPtr<T>> {
+    palloc_global_layout(Layout::new::<T>(), j).map(|start| PMPtr::new(start as *mut T))
+}
+
+pub unsafe fn palloc_global_layout(layout: Layout, j: JournalHandle) -> Option<usize> {
+    let pad = (layout.align() - GLOBAL_PM_HEAP.next % layout.align()) % layout.align();
+    match GLOBAL_PM_HEAP.alloc(pad + layout.size(), j) {
+        Ok(start) => Some(start + pad),
+        Err(_) => None,
+    }
+}
+
 fn get_pm_heap() -> &'static mut PMHeap<PerTaskPMBumpAllocator> {
     if unsafe { is_scheduler_started() } {
         current().get_pm_heap()
@@ -4,7 +4,7 @@ use crate::{
     debug_print, list_dbg_print, os_print,
     pmem::{JournalHandle, PMPtr, PVolatilePtr},
     task::{
-        current, get_current_task_ptr, get_delay_list, get_sched_list, BlockedListItem,
+        current, get_current_task_ptr, get_delay_list, get_sched_list, BlockedListItem, Priority,
         SchedListItem, Task,
     },
     time::{Time, TimerListItem},
@@ -107,6 +107,13 @@ pub enum ListTxOpCode {
     ActiveListPop, // not used
     ActiveListTxCommitted,
     ReadyListNext,
+    TaskRemoval,
+    TaskSuspend,
+    TaskResume,
+    TaskPriorityChange,
+    TaskPriorityInherit,
+    TaskNotifyWakeup,
+    TaskIsrWakeup,
 }
 ///     micro_op_old_len
 ///    | opcode | old len |
@@ -118,6 +125,9 @@ pub struct ListTxOpLog {
     block_node_ptr: Option<PMPtr<Node<BlockedListItem>>>,
     task_ptr: Option<PMPtr<Task>>,
     wait_list_ptr: Option<PMPtr<SortedPList<BlockedListItem>>>,
+    // wait list and target priority of a priority change
+    plist_ptr: Option<PMPtr<PList<BlockedListItem>>>,
+    priority: usize,
 }
 
 unsafe impl Sync for ListTxOpLog {}
@@ -149,6 +159,8 @@ impl ListTxOpLog {
             block_node_ptr: None,
             task_ptr: None,
             wait_list_ptr: None,
+            plist_ptr: None,
+            priority: 0,
         }
     }
 
@@ -284,6 +296,48 @@ impl ListTxOpLog {
     pub fn get_wait_list_ptr(&self) -> Option<PMPtr<SortedPList<BlockedListItem>>> {
         self.wait_list_ptr
     }
+
+    // also read by the roll forward itself, so it's kept without crash_safe
+    #[inline(always)]
+    pub fn set_plist_ptr(&mut self, ptr: Option<PMPtr<PList<BlockedListItem>>>) {
+        self.plist_ptr = ptr;
+    }
+
+    #[inline(always)]
+    pub fn get_plist_ptr(&self) -> Option<PMPtr<PList<BlockedListItem>>> {
+        self.plist_ptr
+    }
+
+    #[inline(always)]
+    pub fn set_priority(&mut self, prio: usize) {
+        self.priority = prio;
+    }
+
+    #[inline(always)]
+    pub fn get_priority(&self) -> usize {
+        self.priority
+    }
+}
+
+// Finish a ready list operation cut by a crash, the bitmap follows the list
+#[cfg(feature = "opt_list")]
+fn recover_ready_list_insert(task: &Task, old_len: usize) {
+    let rdlist = unsafe { get_sched_list(task.get_priority()) };
+    rdlist
+        .0
+        .recover_from_failed_insert_before_cursor(task.get_sched_node_ptr(), old_len);
+    let j = unsafe { JournalHandle::new_dummy() };
+    crate::task::sync_ready_level(task.get_priority().get_value(), j);
+}
+
+#[cfg(feature = "opt_list")]
+fn recover_ready_list_remove(task: &Task, old_len: usize) {
+    let rdlist = unsafe { get_sched_list(task.get_priority()) };
+    rdlist
+        .0
+        .recover_from_failed_remove(task.get_sched_node_ptr(), old_len);
+    let j = unsafe { JournalHandle::new_dummy() };
+    crate::task::sync_ready_level(task.get_priority().get_value(), j);
 }
 
 #[cfg(feature = "opt_list")]
@@ -323,6 +377,29 @@ pub fn roll_forward_pop_remove_from_waitlist(
     log.commit();
 }
 
+// Wakes every waiter, each pop is rolled forward on its own. Nothing is
+// committed, a replay finds only the waiters which are left.
+#[cfg(feature = "opt_list")]
+pub fn atomic_roll_forward_pop_remove_all_from_waitlist(
+    wait_list: &mut SortedPList<BlockedListItem>,
+    cs: &CriticalSection,
+) {
+    let j = unsafe { JournalHandle::new_dummy() };
+    let log = ListTxOpLog::get_list_tx_op_log();
+    while wait_list.0.head.is_some() {
+        pre_list_op_hook();
+        log.set_block_node_ptr(wait_list.0.head);
+        compiler_pm_fence();
+        log.set_tx_op(ListTxOpCode::WaitListPopRemoval);
+        wait_list.pop_front(cs, j).map(|node| {
+            let task = node.value.get_task();
+            task.remove_from_delayed_list(j, cs);
+            task.add_to_ready_list(j, cs);
+        });
+        log.invalidate();
+    }
+}
+
 #[cfg(feature = "opt_list")]
 pub fn recover_and_roll_forward_of_waitlist_pop_remove(log: &mut ListTxOpLog) {
     let opcode = log.get_micro_op();
@@ -361,10 +438,7 @@ pub fn recover_and_roll_forward_of_waitlist_pop_remove(log: &mut ListTxOpLog) {
 
         ListOpCode::InsertBeforeCursor => {
             let task = node.value.get_task();
-            let sched_list = unsafe { get_sched_list(task.get_priority()) };
-            sched_list
-                .0
-                .recover_from_failed_insert_before_cursor(task.get_sched_node_ptr(), old_len);
+            recover_ready_list_insert(task, old_len);
             // change task state to be ready
             task.set_status(crate::task::TaskState::Ready, j);
         }
@@ -444,10 +518,7 @@ pub fn recover_and_roll_forward_of_unsorted_waitlist_remove(log: &mut ListTxOpLo
 
         ListOpCode::InsertBeforeCursor => {
             let task = node.value.get_task();
-            let sched_list = unsafe { get_sched_list(task.get_priority()) };
-            sched_list
-                .0
-                .recover_from_failed_insert_before_cursor(task.get_sched_node_ptr(), old_len);
+            recover_ready_list_insert(task, old_len);
             // change task state to be ready
             task.set_status(crate::task::TaskState::Ready, j);
         }
@@ -543,10 +614,7 @@ pub fn recover_and_roll_forward_of_waitlist_insert(log: &mut ListTxOpLog) {
     let j = unsafe { JournalHandle::new_dummy() };
     match opcode {
         ListOpCode::Remove => {
-            let sched_list = unsafe { get_sched_list(task.get_priority()) };
-            sched_list
-                .0
-                .recover_from_failed_remove(task.get_sched_node_ptr(), old_len);
+            recover_ready_list_remove(task, old_len);
             wl.insert(&cs, j, task.get_event_node_ptr());
             task.add_to_delayed_list(j, &cs);
         }
@@ -586,10 +654,7 @@ pub fn recover_and_roll_forward_of_unsorted_waitlist_insert(log: &mut ListTxOpLo
     let j = unsafe { JournalHandle::new_dummy() };
     match opcode {
         ListOpCode::Remove => {
-            let sched_list = unsafe { get_sched_list(task.get_priority()) };
-            sched_list
-                .0
-                .recover_from_failed_remove(task.get_sched_node_ptr(), old_len);
+            recover_ready_list_remove(task, old_len);
             wl.insert(&cs, j, task.get_event_node_ptr());
             task.add_to_delayed_list(j, &cs);
         }
@@ -680,10 +745,7 @@ pub fn recover_and_roll_forward_of_delaylist_removal(log: &mut ListTxOpLog) {
 
         ListOpCode::InsertBeforeCursor => {
             let task = node.value.get_task();
-            let sched_list = unsafe { get_sched_list(task.get_priority()) };
-            sched_list
-                .0
-                .recover_from_failed_insert_before_cursor(task.get_sched_node_ptr(), old_len);
+            recover_ready_list_insert(task, old_len);
             // change task state to be ready
             task.set_status(crate::task::TaskState::Ready, j);
         }
@@ -730,10 +792,7 @@ pub fn recover_and_roll_forward_of_delaylist_insert(log: &mut ListTxOpLog) {
 
     match opcode {
         ListOpCode::Remove => {
-            let rdlist = unsafe { get_sched_list(task.get_priority()) };
-            rdlist
-                .0
-                .recover_from_failed_remove(task.get_sched_node_ptr(), old_len);
+            recover_ready_list_remove(task, old_len);
             task.add_to_delayed_list(j, &cs);
         }
         ListOpCode::InsertSortedDelayList => {
@@ -777,10 +836,7 @@ pub fn recover_and_roll_forward_of_readylist_insert(log: &mut ListTxOpLog) {
 
     match opcode {
         ListOpCode::InsertBeforeCursor => {
-            let rdlist = unsafe { get_sched_list(task.get_priority()) };
-            rdlist
-                .0
-                .recover_from_failed_insert_before_cursor(task.get_sched_node_ptr(), old_len);
+            recover_ready_list_insert(task, old_len);
             task.set_status(crate::task::TaskState::Ready, j);
         }
         ListOpCode::Invalid => {}
@@ -792,6 +848,391 @@ pub fn recover_and_roll_forward_of_readylist_insert(log: &mut ListTxOpLog) {
     log.commit();
 }
 
+#[cfg(feature = "opt_list")]
+pub fn atomic_roll_forward_remove_task(task: &mut Task, cs: &CriticalSection) {
+    pre_list_op_hook();
+    let j = unsafe { JournalHandle::new_dummy() };
+    roll_forward_remove_task(task, j, cs);
+}
+
+#[cfg(feature = "opt_list")]
+pub fn roll_forward_remove_task(task: &mut Task, j: JournalHandle, cs: &CriticalSection) {
+    roll_forward_unlink_task(task, ListTxOpCode::TaskRemoval, j, cs);
+}
+
+#[cfg(feature = "opt_list")]
+pub fn atomic_roll_forward_suspend_task(task: &mut Task, cs: &CriticalSection) {
+    pre_list_op_hook();
+    let j = unsafe { JournalHandle::new_dummy() };
+    roll_forward_unlink_task(task, ListTxOpCode::TaskSuspend, j, cs);
+}
+
+#[cfg(feature = "opt_list")]
+fn roll_forward_unlink_task(
+    task: &mut Task,
+    op: ListTxOpCode,
+    j: JournalHandle,
+    cs: &CriticalSection,
+) {
+    let log = ListTxOpLog::get_list_tx_op_log();
+    log.set_task_ptr(Some(unsafe { PMPtr::from_mut_ref(task) }));
+    compiler_pm_fence();
+    log.set_tx_op(op);
+    compiler_pm_fence();
+    remove_task_nodes(task, log, j, cs);
+    finish_task_unlink(task, op, j);
+    log.commit();
+}
+
+#[cfg(feature = "opt_list")]
+fn finish_task_unlink(task: &mut Task, op: ListTxOpCode, j: JournalHandle) {
+    match op {
+        ListTxOpCode::TaskRemoval => task.release(j),
+        ListTxOpCode::TaskSuspend => task.set_status(crate::task::TaskState::Suspended, j),
+        _ => panic!("Impossible Op"),
+    }
+}
+
+// Unlink the sched node and the event node of a task from whatever lists they
+// are in, the node being removed is recorded in the log
+#[cfg(feature = "opt_list")]
+fn remove_task_nodes(
+    task: &Task,
+    log: &mut ListTxOpLog,
+    j: JournalHandle,
+    cs: &CriticalSection,
+) {
+    // ready list or delayed list
+    unlink_node(task.get_sched_node_ptr(), log, j, cs);
+    crate::task::sync_ready_level(task.get_priority().get_value(), j);
+    // wait list
+    unlink_node(task.get_event_node_ptr(), log, j, cs);
+}
+
+#[cfg(feature = "opt_list")]
+fn unlink_node<T: OpLogListItem>(
+    node_ptr: PMPtr<Node<T>>,
+    log: &mut ListTxOpLog,
+    j: JournalHandle,
+    cs: &CriticalSection,
+) {
+    if let Some(mut list) = node_ptr.as_ref().list {
+        log.set_block_node_ptr(Some(node_ptr));
+        compiler_pm_fence();
+        unsafe { list.as_mut_no_logging() }.optimized_remove(node_ptr, cs, j);
+        compiler_pm_fence();
+        log.set_micro_op_old_len(ListOpCode::Invalid, 0);
+        compiler_pm_fence();
+    }
+}
+
+// Finish the node removal cut by a crash, the log tells which node it was
+#[cfg(feature = "opt_list")]
+fn recover_task_node_remove(task: &Task, log: &mut ListTxOpLog) {
+    let old_len = log.get_old_len();
+    let sched_node_ptr = task.get_sched_node_ptr();
+    let event_node_ptr = task.get_event_node_ptr();
+    if log.get_block_node_ptr::<SchedListItem>() == Some(sched_node_ptr) {
+        if let Some(mut list) = sched_node_ptr.as_ref().list {
+            unsafe { list.as_mut_no_logging() }.recover_from_failed_remove(sched_node_ptr, old_len);
+        }
+    } else if let Some(mut list) = event_node_ptr.as_ref().list {
+        unsafe { list.as_mut_no_logging() }.recover_from_failed_remove(event_node_ptr, old_len);
+    }
+    compiler_pm_fence();
+    log.set_micro_op_old_len(ListOpCode::Invalid, 0);
+    compiler_pm_fence();
+}
+
+// Shared by task removal and suspension
+#[cfg(feature = "opt_list")]
+pub fn recover_and_roll_forward_of_task_unlink(log: &mut ListTxOpLog) {
+    let opcode = log.get_micro_op();
+    let task = unsafe { log.get_task_ptr().unwrap_unchecked().as_mut_no_logging() };
+    let cs = unsafe { CriticalSection::new() };
+    let j = unsafe { JournalHandle::new_dummy() };
+
+    match opcode {
+        ListOpCode::Remove => recover_task_node_remove(task, log),
+        ListOpCode::Invalid => {}
+        _ => {
+            panic!("Impossible Op");
+        }
+    }
+    // the rest is idempotent
+    remove_task_nodes(task, log, j, &cs);
+    finish_task_unlink(task, log.get_tx_op(), j);
+    log.commit();
+}
+
+// Set the base priority and the running priority of a task
+#[cfg(feature = "opt_list")]
+pub fn atomic_roll_forward_change_priority(task: &mut Task, prio: Priority, cs: &CriticalSection) {
+    pre_list_op_hook();
+    start_priority_change(task, prio, ListTxOpCode::TaskPriorityChange, cs);
+    ListTxOpLog::get_list_tx_op_log().commit();
+}
+
+// Boost or restore the running priority only. It's part of a semaphore
+// operation, so it doesn't complete the list transaction of the syscall.
+#[cfg(feature = "opt_list")]
+pub fn atomic_roll_forward_inherit_priority(task: &mut Task, prio: Priority, cs: &CriticalSection) {
+    pre_list_op_hook();
+    start_priority_change(task, prio, ListTxOpCode::TaskPriorityInherit, cs);
+    ListTxOpLog::get_list_tx_op_log().invalidate();
+}
+
+#[cfg(feature = "opt_list")]
+fn start_priority_change(
+    task: &mut Task,
+    prio: Priority,
+    op: ListTxOpCode,
+    cs: &CriticalSection,
+) {
+    let j = unsafe { JournalHandle::new_dummy() };
+    let log = ListTxOpLog::get_list_tx_op_log();
+    log.set_task_ptr(Some(unsafe { PMPtr::from_mut_ref(task) }));
+    log.set_priority(prio.get_value());
+    log.set_plist_ptr(None);
+    compiler_pm_fence();
+    log.set_tx_op(op);
+    compiler_pm_fence();
+    roll_forward_change_priority(task, log, j, cs);
+}
+
+// The sched node moves to the ready list of the new priority and the event
+// node is re-sorted in its wait list. Every step checks whether it's done
+// already, so the recovery can run it again.
+#[cfg(feature = "opt_list")]
+fn roll_forward_change_priority(
+    task: &mut Task,
+    log: &mut ListTxOpLog,
+    j: JournalHandle,
+    cs: &CriticalSection,
+) {
+    let prio = Priority::new(log.get_priority());
+    let sched_node_ptr = task.get_sched_node_ptr();
+    let event_node_ptr = task.get_event_node_ptr();
+    if task.get_priority() != prio {
+        // a delayed task stays in the delayed list
+        if task.is_schedulable() {
+            unlink_node(sched_node_ptr, log, j, cs);
+            crate::task::sync_ready_level(task.get_priority().get_value(), j);
+        }
+        if let Some(list) = event_node_ptr.as_ref().list {
+            log.set_plist_ptr(Some(list));
+            compiler_pm_fence();
+            unlink_node(event_node_ptr, log, j, cs);
+        }
+        task.set_priority(prio, j);
+        compiler_pm_fence();
+    }
+    if log.get_tx_op() == ListTxOpCode::TaskPriorityChange {
+        task.set_base_priority(prio, j);
+    }
+    if task.is_schedulable() && sched_node_ptr.as_ref().list.is_none() {
+        let rdlist = unsafe { get_sched_list(prio) };
+        rdlist.0.optimized_insert_before_cursor(sched_node_ptr, cs, j);
+        compiler_pm_fence();
+        log.set_micro_op_old_len(ListOpCode::Invalid, 0);
+        compiler_pm_fence();
+    }
+    if task.is_schedulable() {
+        crate::task::sync_ready_level(prio.get_value(), j);
+    }
+    if let Some(mut list) = log.get_plist_ptr() {
+        if event_node_ptr.as_ref().list.is_none() {
+            let list = unsafe { list.as_mut_no_logging() };
+            BlockedListItem::start_op_log(ListOpCode::InsertSortedWaitList, list.len);
+            compiler_pm_fence();
+            list.optimized_insert_sorted(event_node_ptr, cs, j);
+            compiler_pm_fence();
+            log.set_micro_op_old_len(ListOpCode::Invalid, 0);
+            compiler_pm_fence();
+        }
+    }
+    if log.get_tx_op() == ListTxOpCode::TaskPriorityChange {
+        crate::task::update_boot_task();
+    }
+}
+
+#[cfg(feature = "opt_list")]
+pub fn recover_and_roll_forward_of_priority_change(log: &mut ListTxOpLog) {
+    let opcode = log.get_micro_op();
+    let old_len = log.get_old_len();
+    let task = unsafe { log.get_task_ptr().unwrap_unchecked().as_mut_no_logging() };
+    let cs = unsafe { CriticalSection::new() };
+    let j = unsafe { JournalHandle::new_dummy() };
+
+    match opcode {
+        ListOpCode::Remove => recover_task_node_remove(task, log),
+        ListOpCode::InsertBeforeCursor => {
+            recover_ready_list_insert(task, old_len);
+        }
+        ListOpCode::InsertSortedWaitList => {
+            let mut list = unsafe { log.get_plist_ptr().unwrap_unchecked() };
+            unsafe { list.as_mut_no_logging() }
+                .recover_from_failed_insert_sorted(task.get_event_node_ptr(), old_len);
+        }
+        ListOpCode::Invalid => {}
+        _ => {
+            panic!("Impossible Op");
+        }
+    }
+    compiler_pm_fence();
+    log.set_micro_op_old_len(ListOpCode::Invalid, 0);
+    compiler_pm_fence();
+    roll_forward_change_priority(task, log, j, &cs);
+    if log.get_tx_op() == ListTxOpCode::TaskPriorityChange {
+        log.commit();
+    } else {
+        log.invalidate();
+    }
+}
+
+#[cfg(feature = "opt_list")]
+pub fn atomic_roll_forward_resume_task(task: &mut Task, cs: &CriticalSection) {
+    pre_list_op_hook();
+    let j = unsafe { JournalHandle::new_dummy() };
+    let log = ListTxOpLog::get_list_tx_op_log();
+    log.set_task_ptr(Some(unsafe { PMPtr::from_mut_ref(task) }));
+    compiler_pm_fence();
+    log.set_tx_op(ListTxOpCode::TaskResume);
+    compiler_pm_fence();
+    task.add_to_ready_list(j, cs);
+    log.commit();
+}
+
+#[cfg(feature = "opt_list")]
+pub fn recover_and_roll_forward_of_task_resume(log: &mut ListTxOpLog) {
+    let opcode = log.get_micro_op();
+    let old_len = log.get_old_len();
+    let task = unsafe { log.get_task_ptr().unwrap_unchecked().as_mut_no_logging() };
+    let cs = unsafe { CriticalSection::new() };
+    let j = unsafe { JournalHandle::new_dummy() };
+
+    match opcode {
+        ListOpCode::InsertBeforeCursor => {
+            recover_ready_list_insert(task, old_len);
+            task.set_status(crate::task::TaskState::Ready, j);
+        }
+        // the insertion didn't start
+        ListOpCode::Invalid => {
+            if task.is_suspended() {
+                task.add_to_ready_list(j, &cs);
+            }
+        }
+        _ => {
+            panic!("Impossible Op");
+        }
+    }
+
+    log.commit();
+}
+
+// Move a task blocked on its notification from the delayed list to the
+// ready list
+#[cfg(feature = "opt_list")]
+pub fn atomic_roll_forward_wake_notified_task(task: &mut Task, cs: &CriticalSection) {
+    pre_list_op_hook();
+    let j = unsafe { JournalHandle::new_dummy() };
+    let log = ListTxOpLog::get_list_tx_op_log();
+    log.set_task_ptr(Some(unsafe { PMPtr::from_mut_ref(task) }));
+    compiler_pm_fence();
+    log.set_tx_op(ListTxOpCode::TaskNotifyWakeup);
+    compiler_pm_fence();
+    roll_forward_wake_notified_task(task, log, j, cs);
+    log.commit();
+}
+
+#[cfg(feature = "opt_list")]
+fn roll_forward_wake_notified_task(
+    task: &mut Task,
+    log: &mut ListTxOpLog,
+    j: JournalHandle,
+    cs: &CriticalSection,
+) {
+    // the status turns to ready once the task is in the ready list
+    if task.get_status() == crate::task::TaskState::Blocked {
+        unlink_node(task.get_sched_node_ptr(), log, j, cs);
+        task.add_to_ready_list(j, cs);
+    }
+}
+
+#[cfg(feature = "opt_list")]
+pub fn recover_and_roll_forward_of_notify_wakeup(log: &mut ListTxOpLog) {
+    let opcode = log.get_micro_op();
+    let old_len = log.get_old_len();
+    let task = unsafe { log.get_task_ptr().unwrap_unchecked().as_mut_no_logging() };
+    let cs = unsafe { CriticalSection::new() };
+    let j = unsafe { JournalHandle::new_dummy() };
+
+    match opcode {
+        ListOpCode::Remove => recover_task_node_remove(task, log),
+        ListOpCode::InsertBeforeCursor => {
+            recover_ready_list_insert(task, old_len);
+            task.set_status(crate::task::TaskState::Ready, j);
+        }
+        ListOpCode::Invalid => {}
+        _ => {
+            panic!("Impossible Op");
+        }
+    }
+    roll_forward_wake_notified_task(task, log, j, &cs);
+    log.commit();
+}
+
+// Wake a blocked task from an interrupt handler. The log is only invalidated,
+// the list transaction of the interrupted task isn't ours to complete.
+#[cfg(feature = "opt_list")]
+pub fn atomic_roll_forward_isr_wakeup(task: &mut Task, cs: &CriticalSection) {
+    pre_list_op_hook();
+    let j = unsafe { JournalHandle::new_dummy() };
+    let log = ListTxOpLog::get_list_tx_op_log();
+    log.set_task_ptr(Some(unsafe { PMPtr::from_mut_ref(task) }));
+    compiler_pm_fence();
+    log.set_tx_op(ListTxOpCode::TaskIsrWakeup);
+    compiler_pm_fence();
+    roll_forward_isr_wakeup(task, log, j, cs);
+    log.invalidate();
+}
+
+#[cfg(feature = "opt_list")]
+fn roll_forward_isr_wakeup(
+    task: &mut Task,
+    log: &mut ListTxOpLog,
+    j: JournalHandle,
+    cs: &CriticalSection,
+) {
+    if task.get_status() == crate::task::TaskState::Blocked {
+        remove_task_nodes(task, log, j, cs);
+        task.add_to_ready_list(j, cs);
+    }
+}
+
+#[cfg(feature = "opt_list")]
+pub fn recover_and_roll_forward_of_isr_wakeup(log: &mut ListTxOpLog) {
+    let opcode = log.get_micro_op();
+    let old_len = log.get_old_len();
+    let task = unsafe { log.get_task_ptr().unwrap_unchecked().as_mut_no_logging() };
+    let cs = unsafe { CriticalSection::new() };
+    let j = unsafe { JournalHandle::new_dummy() };
+
+    match opcode {
+        ListOpCode::Remove => recover_task_node_remove(task, log),
+        ListOpCode::InsertBeforeCursor => {
+            recover_ready_list_insert(task, old_len);
+            task.set_status(crate::task::TaskState::Ready, j);
+        }
+        ListOpCode::Invalid => {}
+        _ => {
+            panic!("Impossible Op");
+        }
+    }
+    roll_forward_isr_wakeup(task, log, j, &cs);
+    log.invalidate();
+}
+
 #[cfg(feature = "opt_list")]
 pub fn atomic_roll_forward_remove_reinsert_into_activelist(node_ptr: PMPtr<Node<TimerListItem>>) {
     pre_list_op_hook();
@@ -964,13 +1405,16 @@ pub fn atomic_roll_foward_context_switch(
     let log = ListTxOpLog::get_list_tx_op_log();
     // set prev ptr of task
     log.set_task_ptr(unsafe { Some(PMPtr::from_mut_ref(prev_task)) });
+    // the policy may pick any ready task, not only the one after the cursor
+    let node = task.get_sched_node_ptr();
+    log.set_block_node_ptr(Some(node));
     compiler_pm_fence();
     log.set_micro_op_old_len(ListOpCode::ReadyListNext, 0);
     task.set_status(crate::task::TaskState::Running, j);
-    if prev_task.get_status() != crate::task::TaskState::Blocked {
+    if prev_task.is_running() {
         prev_task.set_status(crate::task::TaskState::Ready, j);
     }
-    readylist.next(cs);
+    readylist.move_cursor_to(node, cs);
     current_ptr.store(task as *mut Task);
     compiler_pm_fence();
     log.set_micro_op_old_len(ListOpCode::Invalid, 0);
@@ -986,19 +1430,15 @@ pub fn recover_and_roll_forward_context_switch(log: &mut ListTxOpLog) {
         ListOpCode::ReadyListNext => {
             let mut prev_task_ptr = log.get_task_ptr().unwrap();
             let prev_task = unsafe { prev_task_ptr.as_mut_no_logging() };
-            let rdlist = unsafe { get_sched_list(prev_task.get_priority()) };
-            let cursor = rdlist.cursor(&cs).unwrap();
-            if cursor.get_task() as *const Task == prev_task as *const Task {
+            let node = log.get_block_node_ptr::<SchedListItem>().unwrap();
+            let task = node.as_ref().value.get_task();
+            let rdlist = unsafe { get_sched_list(task.get_priority()) };
+            if prev_task.is_running() {
                 prev_task.set_status(crate::task::TaskState::Ready, j);
-                let task = rdlist.next(&cs).unwrap().get_task();
-                task.set_status(crate::task::TaskState::Running, j);
-                current_ptr.store(task as *const Task as *mut Task);
-            } else {
-                // cursor already moved
-                let task = cursor.get_task();
-                task.set_status(crate::task::TaskState::Running, j);
-                current_ptr.store(task as *const Task as *mut Task);
             }
+            task.set_status(crate::task::TaskState::Running, j);
+            rdlist.move_cursor_to(node, &cs);
+            current_ptr.store(task as *const Task as *mut Task);
             compiler_pm_fence();
             log.set_micro_op_old_len(ListOpCode::Invalid, 0);
         }
@@ -1357,21 +1797,27 @@ impl<T: OpLogListItem> PList<T> {
         // it's idempotent!
         let removed_node = unsafe { removed_ptr.as_mut_no_logging() };
         let removed_link = Some(removed_ptr);
-        removed_node
-            .prev
-            .map(|mut ptr| unsafe { ptr.as_mut_no_logging() }.next = removed_node.next);
-        removed_node
-            .next
-            .map(|mut ptr| unsafe { ptr.as_mut_no_logging() }.prev = removed_node.prev);
-
-        // if cursor is removed, update the cursor
-        if self.cursor == removed_link {
-            self.cursor = removed_node.next;
-        }
+        // the last node of a circular list links to itself
+        if old_len == 1 {
+            self.cursor = None;
+            self.head = None;
+        } else {
+            removed_node
+                .prev
+                .map(|mut ptr| unsafe { ptr.as_mut_no_logging() }.next = removed_node.next);
+            removed_node
+                .next
+                .map(|mut ptr| unsafe { ptr.as_mut_no_logging() }.prev = removed_node.prev);
+
+            // if cursor is removed, update the cursor
+            if self.cursor == removed_link {
+                self.cursor = removed_node.next;
+            }
 
-        // if head is removed, update the head
-        if self.head == removed_link {
-            self.head = removed_node.next;
+            // if head is removed, update the head
+            if self.head == removed_link {
+                self.head = removed_node.next;
+            }
         }
 
         // set new len
@@ -2201,6 +2647,11 @@ impl<T: OpLogListItem> CircularPList<T> {
     pub fn cursor(&self, cs: &CriticalSection) -> Option<&T> {
         self.0.cursor(cs)
     }
+
+    // A single store, so it is idempotent
+    pub fn move_cursor_to(&mut self, link: PMPtr<Node<T>>, _: &CriticalSection) {
+        self.0.cursor = Some(link);
+    }
 }
 
 impl<T: Display> fmt::Display for CircularPList<T> {
@@ -2278,7 +2729,7 @@ pub mod test {
     };
 
     use super::*;
-    fn dummy(_x: usize) {}
+    fn dummy(_x: usize, _j: JournalHandle) {}
 
     const NO_CRASH: usize = 10000;
 
@@ -2352,6 +2803,88 @@ pub mod test {
         crash_point!(4);
     }
 
+    // Remove a task as roll_forward_remove_task does. `cp1` picks the step to
+    // crash at, 1 and 2 crash at `cp2` in the unlink of the sched node and of
+    // the event node.
+    #[cfg(feature = "opt_list")]
+    pub fn crashed_roll_forward_remove_task(task: &mut Task, cp1: usize, cp2: usize) {
+        set_list_crash_point(cp1);
+        let log = ListTxOpLog::get_list_tx_op_log();
+        log.set_task_ptr(Some(unsafe { PMPtr::from_mut_ref(task) }));
+        compiler_pm_fence();
+        log.set_tx_op(ListTxOpCode::TaskRemoval);
+        compiler_pm_fence();
+        crash_point!(0);
+        let cs = unsafe { CriticalSection::new() };
+        let j = unsafe { JournalHandle::new_dummy() };
+        if get_list_crash_point() == 1 {
+            crashed_unlink_node(task.get_sched_node_ptr(), log, cp2, &cs, j);
+            return;
+        }
+        unlink_node(task.get_sched_node_ptr(), log, j, &cs);
+        crate::task::sync_ready_level(task.get_priority().get_value(), j);
+        if get_list_crash_point() == 2 {
+            crashed_unlink_node(task.get_event_node_ptr(), log, cp2, &cs, j);
+            return;
+        }
+        unlink_node(task.get_event_node_ptr(), log, j, &cs);
+        crash_point!(3);
+        finish_task_unlink(task, ListTxOpCode::TaskRemoval, j);
+        crash_point!(4);
+        log.commit();
+    }
+
+    // Wake a task as roll_forward_wake_notified_task does. `cp1` picks the
+    // step to crash at, 1 and 2 crash at `cp2` in the unlink from the delayed
+    // list and in the insert into the ready list.
+    #[cfg(feature = "opt_list")]
+    pub fn crashed_roll_forward_wake_notified_task(task: &mut Task, cp1: usize, cp2: usize) {
+        set_list_crash_point(cp1);
+        let log = ListTxOpLog::get_list_tx_op_log();
+        log.set_task_ptr(Some(unsafe { PMPtr::from_mut_ref(task) }));
+        compiler_pm_fence();
+        log.set_tx_op(ListTxOpCode::TaskNotifyWakeup);
+        compiler_pm_fence();
+        crash_point!(0);
+        let cs = unsafe { CriticalSection::new() };
+        let j = unsafe { JournalHandle::new_dummy() };
+        if get_list_crash_point() == 1 {
+            crashed_unlink_node(task.get_sched_node_ptr(), log, cp2, &cs, j);
+            return;
+        }
+        unlink_node(task.get_sched_node_ptr(), log, j, &cs);
+        let rdlist = unsafe { crate::task::get_sched_list(task.get_priority()) };
+        let mut link = task.get_sched_node_ptr();
+        if get_list_crash_point() == 2 {
+            set_list_crash_point(cp2);
+            rdlist.0.crashed_optimized_insert_before_cursor(&mut link, &cs, j);
+            return;
+        }
+        rdlist.insert(&cs, j, link);
+        crash_point!(3);
+        crate::task::sync_ready_level(task.get_priority().get_value(), j);
+        crash_point!(4);
+        task.set_status(crate::task::TaskState::Ready, j);
+        crash_point!(5);
+        log.commit();
+    }
+
+    #[cfg(feature = "opt_list")]
+    fn crashed_unlink_node<T: OpLogListItem>(
+        node_ptr: PMPtr<Node<T>>,
+        log: &mut ListTxOpLog,
+        cp: usize,
+        cs: &CriticalSection,
+        j: JournalHandle,
+    ) {
+        if let Some(mut list) = node_ptr.as_ref().list {
+            log.set_block_node_ptr(Some(node_ptr));
+            compiler_pm_fence();
+            set_list_crash_point(cp);
+            unsafe { list.as_mut_no_logging() }.crashed_optimized_remove(node_ptr, cs, j);
+        }
+    }
+
     fn may_crashed_timer_list_remove(
         cp: usize,
         tl: &mut SortedPList<TimerListItem>,
@@ -1,7 +1,5 @@
-// #![cfg_attr(not(test), no_std)]
-// #![cfg_attr(not(test), no_main)]
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(naked_functions)]
 #![feature(auto_traits)]
 #![feature(negative_impls)]
@@ -19,6 +17,9 @@ pub mod arch;
 #[cfg(not(test))]
 pub mod benchmarks;
 pub mod board;
+#[cfg(feature = "jit_checkpoint")]
+pub mod checkpoint;
+pub mod condvar;
 pub mod critical;
 pub mod event_group;
 pub mod heap;
@@ -27,7 +28,10 @@ pub mod marker;
 pub mod pmem;
 pub mod queue;
 pub mod recover;
+pub mod rwlock;
+pub mod sched;
 pub mod semaphore;
+pub mod stream_buffer;
 pub mod syscalls;
 pub mod task;
 pub mod tests;
@@ -125,6 +129,8 @@ fn show_feature() {
     show_additional_features();
     #[cfg(feature = "power_failure")]
     os_print!("[*] Failure Injection");
+    #[cfg(feature = "jit_checkpoint")]
+    os_print!("[*] JIT Checkpointing");
 }
 
 #[cfg(not(test))]
@@ -198,12 +204,15 @@ fn main() {
 pub mod test {
     use super::*;
     use crate::{
+        marker::{TxInSafe, TxOutSafe},
         recover::{increase_generation, init_boot_tx},
+        stream_buffer::{StreamChunk, StreamErr},
         task::{get_task_cnt, mock_task_switch},
         time::TimeManager,
         user::transaction,
     };
     use core::mem::forget;
+    use core::sync::atomic::{AtomicUsize, Ordering};
     pub const NO_CRASH: usize = 10000;
     static TASK_NAMES: [&str; 4] = ["Test1", "Test2", "Test3", "Test4"];
     // Helper functions
@@ -252,6 +261,7 @@ pub mod test {
         task::reset_static_vars();
         recover::reset_static_vars();
         heap::reset_static_vars();
+        rwlock::reset_static_vars();
     }
 
     fn boot_common(task_cnt: usize) {
@@ -261,7 +271,9 @@ pub mod test {
         heap::init();
         os_print!("Creating Test Task");
         for i in 0..task_cnt {
-            let _ = task::create_task_static(TASK_NAMES[i], 1, 0, 0, heap::PM_HEAP_SIZE_PER_TASK)
+            let pm_heap_sz = heap::PM_HEAP_SIZE_PER_TASK;
+            let stack_size = task::DEFAULT_STACK_SIZE;
+            let _ = task::create_task_static(TASK_NAMES[i], 1, 0, 0, pm_heap_sz, stack_size)
                 .unwrap();
         }
     }
@@ -280,9 +292,70 @@ pub mod test {
         syscalls::set_syscall_end_crash_point(10000);
         recover::increase_generation();
         recover::init_boot_tx();
+        time::TIME_MANAGER.set_suppressed_ticks(0);
         arch::start_kernel();
     }
 
+    // Crash `syscall` at every syscall crash point, then replay it after the
+    // reboot. `setup` runs before both runs and is told whether it's the
+    // crashed one, `check` gets the replayed result.
+    fn crash_and_replay_syscall<A, R, F>(
+        boot: impl Fn(),
+        setup: impl Fn(bool) -> A,
+        syscall: F,
+        check: impl Fn(A, R),
+    ) where
+        A: Copy + TxInSafe,
+        R: TxOutSafe,
+        F: Fn(A, SyscallToken) -> R + Copy + TxInSafe,
+    {
+        for scp in 0..SYSCALL_END_NUM_CRASH_POINT + 1 {
+            os_print!("\n[syscall crash point {}]\n", scp);
+            boot();
+            let a = setup(true);
+            set_syscall_end_crash_point(scp);
+            transaction::may_crashed_run_sys(true, move |_, t| syscall(a, t));
+            mock_reboot();
+            current().jit_recovery();
+            let a = setup(false);
+            let r = transaction::may_crashed_run_sys(false, move |_, t| syscall(a, t));
+            check(a, r);
+        }
+    }
+
+    fn spawn_task(prio: usize) -> task::TaskHandle {
+        transaction::run_sys(|_, t| sys_create_task("calib", prio, task_noop, 0usize, t).unwrap())
+    }
+
+    fn spawn_noop_task() -> PMPtr<task::Task> {
+        spawn_task(1).get_task_ptr().unwrap()
+    }
+
+    fn boot_with_task(prio: usize) -> task::TaskHandle {
+        mock_boot(1);
+        spawn_task(prio)
+    }
+
+    fn switch_to(task: PMPtr<task::Task>) {
+        for _ in 0..task::TASK_NUM_LIMIT {
+            if current().as_pm_ptr() == task {
+                return;
+            }
+            mock_task_switch();
+        }
+        assert!(current().as_pm_ptr() == task);
+    }
+
+    // Run `wait` as `waiter` until it blocks, then switch back to the caller
+    fn block_in<T>(waiter: PMPtr<task::Task>, wait: impl FnOnce() -> T) {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let me = current().as_pm_ptr();
+        switch_to(waiter);
+        // the waiter sleeps, a yield can't return on the host
+        assert!(catch_unwind(AssertUnwindSafe(wait)).is_err());
+        switch_to(me);
+    }
+
     #[test]
     fn test_tx_execution() {
         mock_boot(1);
@@ -728,6 +801,662 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn test_chunked_run() {
+        mock_boot(1);
+        let px = transaction::run_sys(|j, t| {
+            let px = PBox::new(0, t);
+            px
+        });
+        transaction::run_chunked(100, |range, j, _| {
+            let x = px.as_mut(j);
+            *x += range.len();
+        });
+        let x = unsafe { *px.as_ref_no_journal() };
+        assert_eq!(x, 100);
+        assert_eq!(user_tx_stack_top(), 0);
+        forget(px);
+    }
+
+    declare_pm_atomic!(ATOMIC_CNT, PAtomicUsize, 0);
+
+    fn task_atomic_counter() {
+        ATOMIC_CNT.store(0);
+        nv_for_loop!(LOOP_CNT_1, i, 0 => 10, {
+            ATOMIC_CNT.fetch_add(2);
+        });
+        assert_eq!(ATOMIC_CNT.compare_exchange(20, 1), Ok(20));
+        assert_eq!(ATOMIC_CNT.compare_exchange(20, 2), Err(1));
+    }
+
+    #[test]
+    fn test_patomic_replay() {
+        mock_boot(1);
+        task_atomic_counter();
+        assert_eq!(ATOMIC_CNT.load(), 1);
+        // replaying the whole task bypasses all operations
+        mock_reboot();
+        current().jit_recovery();
+        task_atomic_counter();
+        assert_eq!(ATOMIC_CNT.load(), 1);
+    }
+
+    fn task_noop(_: usize) {}
+
+    fn task_delete_and_respawn() -> (task::TaskHandle, task::TaskHandle) {
+        let victim = transaction::run_sys(|_, t| {
+            sys_create_task("victim", 1, task_noop, 0usize, t).unwrap()
+        });
+        let r = transaction::run_sys(|_, t| sys_task_delete(victim, t));
+        assert!(r.is_ok());
+        let respawned = transaction::run_sys(|_, t| {
+            sys_create_task("respawned", 1, task_noop, 0usize, t).unwrap()
+        });
+        (victim, respawned)
+    }
+
+    #[test]
+    fn test_task_delete_slot_reuse() {
+        mock_boot(1);
+        let (victim, respawned) = task_delete_and_respawn();
+        let task = respawned.get_task_ptr().unwrap();
+        assert_eq!(task.as_ref().get_name(), "respawned");
+        // the handle of the deleted task is stale
+        assert!(victim.get_task_ptr().is_none());
+        assert_eq!(unsafe { get_task_cnt() }, 2);
+        // the deletion is bypassed on replay
+        mock_reboot();
+        current().jit_recovery();
+        let _ = task_delete_and_respawn();
+        assert_eq!(unsafe { get_task_cnt() }, 2);
+        assert_eq!(respawned.get_task_ptr().unwrap().as_ref().get_name(), "respawned");
+    }
+
+    #[test]
+    fn test_task_suspend_resume() {
+        mock_boot(1);
+        let h = transaction::run_sys(|_, t| {
+            sys_create_task("sensing", 1, task_noop, 0usize, t).unwrap()
+        });
+        let task = h.get_task_ptr().unwrap();
+        assert!(transaction::run_sys(|_, t| sys_task_suspend(h, t)).is_ok());
+        assert!(task.as_ref().is_suspended());
+        assert!(task.as_ref().get_sched_node_ptr().as_ref().list.is_none());
+        assert!(transaction::run_sys(|_, t| sys_task_resume(h, t)).is_ok());
+        assert!(task.as_ref().is_ready());
+    }
+
+    #[test]
+    fn test_task_set_priority() {
+        let h = boot_with_task(1);
+        assert!(transaction::run_sys(|_, t| sys_task_set_priority(h, 3, t)).is_ok());
+        assert_eq!(transaction::run_sys(|_, t| sys_task_get_priority(h, t)), Ok(3));
+        let task = h.get_task_ptr().unwrap();
+        let sched_list = unsafe { task::get_sched_list(task.as_ref().get_priority()) };
+        assert_eq!(sched_list.length(), 1);
+        assert!(transaction::run_sys(|_, t| sys_task_set_priority(h, 8, t)).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "opt_list")]
+    fn test_inherited_priority_recovery() {
+        let h = boot_with_task(3);
+        let mut task = h.get_task_ptr().unwrap();
+        let cs = unsafe { critical::CriticalSection::new() };
+        let prio = task::Priority::new(1);
+        list::atomic_roll_forward_inherit_priority(unsafe { task.as_mut_no_logging() }, prio, &cs);
+        assert!(task.as_ref().is_priority_inherited());
+        // a boosted task keeps its priority while it's higher than the base one
+        assert!(transaction::run_sys(|_, t| sys_task_set_priority(h, 2, t)).is_ok());
+        assert!(task.as_ref().get_priority() == prio);
+        // no mutex is held, so the boost is dropped after a crash
+        task::recover_inherited_priorities();
+        assert!(task.as_ref().get_priority() == task::Priority::new(2));
+    }
+
+    #[test]
+    fn test_task_notify() {
+        let h = boot_with_task(1);
+        let notify = |value, action| {
+            transaction::run_sys(|_, t| sys_task_notify(h, value, action, t))
+        };
+        assert!(notify(0b01, task::NotifyAction::SetBits).is_ok());
+        assert!(notify(0b10, task::NotifyAction::SetBits).is_ok());
+        assert_eq!(h.get_task_ptr().unwrap().as_ref().get_notify_value(), 0b11);
+        // the first notification is still pending
+        assert!(notify(7, task::NotifyAction::SetValueWithoutOverwrite).is_err());
+        assert!(notify(7, task::NotifyAction::SetValueWithOverwrite).is_ok());
+        assert_eq!(h.get_task_ptr().unwrap().as_ref().get_notify_value(), 7);
+    }
+
+    #[test]
+    fn test_task_stack_size() {
+        mock_boot(1);
+        let create = |stack_size| {
+            transaction::run_sys(|_, t| {
+                let heap_sz = heap::PM_HEAP_SIZE_PER_TASK;
+                sys_create_task_custom("calib", 1, task_noop, 0usize, heap_sz, stack_size, t)
+            })
+        };
+        assert!(create(task::MIN_STACK_SIZE - 1).is_err());
+        let h = create(64).unwrap();
+        assert_eq!(h.get_task_ptr().unwrap().as_ref().stack_capacity(), 64);
+        // nothing has run on the stack yet
+        let hwm = transaction::run_sys(|_, t| sys_task_stack_high_water_mark(h, t));
+        assert_eq!(hwm, Ok(64));
+    }
+
+    #[test]
+    #[cfg(feature = "dynamic_tasks")]
+    fn test_dynamic_tasks() {
+        mock_boot(1);
+        // uncached, the results of many creations don't fit in the tx cache
+        let create = || {
+            transaction::run_sys_once(|_, t| {
+                sys_create_task_custom("dyn", 1, task_noop, 0usize, 0, task::MIN_STACK_SIZE, t)
+            })
+        };
+        let mut last = create().unwrap();
+        while last.get_task_ptr().unwrap().as_ref().get_task_id() < task::TASK_NUM_LIMIT {
+            last = create().unwrap();
+        }
+        let task = last.get_task_ptr().unwrap();
+        let tid = task.as_ref().get_task_id();
+        // the stack is carved from the PM heap together with the TCB
+        let (_, bottom) = task.as_ref().get_stack_region();
+        let limit = task.as_ptr() as usize + core::mem::size_of::<task::Task>();
+        let word = core::mem::size_of::<usize>();
+        assert_eq!(bottom, limit + (task::MIN_STACK_SIZE - 1) * word);
+        transaction::run_sys_once(|_, t| sys_task_delete(last, t)).unwrap();
+        assert!(last.get_task_ptr().is_none());
+        // the released TCB is taken over by the next task
+        let h = create().unwrap();
+        assert_eq!(h.get_task_ptr().unwrap().as_ref().get_task_id(), tid);
+    }
+
+    #[test]
+    fn test_missed_periods() {
+        use task::{next_period, MissedPeriodPolicy::*};
+        assert_eq!(next_period(100, 10, 105, Skip), (110, 0));
+        // woke up late, but still within the period
+        assert_eq!(next_period(100, 10, 115, Skip), (110, 0));
+        // an outage of several periods
+        assert_eq!(next_period(100, 10, 145, Skip), (140, 3));
+        assert_eq!(next_period(100, 10, 145, Report), (140, 3));
+        assert_eq!(next_period(100, 10, 145, CatchUp), (110, 3));
+    }
+
+    #[test]
+    fn test_edf_pick_next() {
+        use sched::{EarliestDeadlineFirst, SchedPolicy};
+        let (urgent, relaxed) = (boot_with_task(3), spawn_task(2));
+        task::set_task_deadline(urgent, Some(10)).unwrap();
+        task::set_task_deadline(relaxed, Some(50)).unwrap();
+        let cs = unsafe { critical::CriticalSection::new() };
+        let (level, next) = EarliestDeadlineFirst::pick_next(&cs).unwrap();
+        assert_eq!(level, 3);
+        assert!(next == urgent.get_task_ptr().unwrap());
+    }
+
+    #[test]
+    fn test_ready_bitmap() {
+        let h = boot_with_task(3);
+        let cs = unsafe { critical::CriticalSection::new() };
+        let next_level = |from| task::next_ready_level(from, &cs);
+        assert_eq!(next_level(2), Some(3));
+        assert!(!task::is_ready_level_marked(2));
+        // the bit goes with the last ready task of the level
+        assert!(task::suspend_task(h).is_ok());
+        assert!(!task::is_ready_level_marked(3));
+        let lower = next_level(2);
+        assert!(lower.map_or(true, |l| l > 3));
+        current().reset_list_transaction();
+        assert!(task::resume_task(h).is_ok());
+        assert_eq!(next_level(2), Some(3));
+        // a crash between the unlink and the bit update is rolled forward
+        #[cfg(feature = "opt_list")]
+        {
+            current().reset_list_transaction();
+            let task = h.get_task_ptr().unwrap();
+            let node = task.as_ref().get_sched_node_ptr();
+            let log = list::ListTxOpLog::get_list_tx_op_log();
+            log.set_task_ptr(Some(task));
+            log.set_block_node_ptr(Some(node));
+            log.set_tx_op(list::ListTxOpCode::TaskSuspend);
+            let j = unsafe { JournalHandle::new_dummy() };
+            unsafe { task::get_sched_list(task::Priority::new(3)) }.remove(&cs, j, node);
+            assert!(task::is_ready_level_marked(3));
+            recover::recover_list_transaction();
+            assert!(!task::is_ready_level_marked(3));
+            assert_eq!(next_level(2), lower);
+        }
+        spawn_task(0);
+        assert_eq!(next_level(0), Some(0));
+    }
+
+    #[test]
+    fn test_edf_expired_deadline() {
+        use sched::{EarliestDeadlineFirst, SchedPolicy};
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        mock_boot(1);
+        // through the kernel, the syscalls would yield to the new tasks
+        let create = |prio| {
+            let heap_sz = heap::PM_HEAP_SIZE_PER_TASK;
+            task::create_task_static("calib", prio, 0, 0, heap_sz, task::DEFAULT_STACK_SIZE)
+        };
+        let (urgent, relaxed) = (create(0).unwrap(), create(2).unwrap());
+        task::set_task_deadline(urgent, Some(10)).unwrap();
+        task::set_task_deadline(relaxed, Some(50)).unwrap();
+        let urgent = urgent.get_task_ptr().unwrap();
+        let relaxed = relaxed.get_task_ptr().unwrap();
+        let cs = unsafe { critical::CriticalSection::new() };
+        let pick_next = || EarliestDeadlineFirst::pick_next(&cs).unwrap().1;
+        for _ in 0..20 {
+            task::process_tick();
+        }
+        // the deadline passed, but the job isn't done yet
+        assert!(pick_next() == urgent);
+        // once it sleeps the next job is due 10 ticks after the wakeup
+        switch_to(urgent);
+        assert!(catch_unwind(AssertUnwindSafe(|| sys_task_delay(5))).is_err());
+        let now = time::TIME_MANAGER.get_ticks();
+        assert_eq!(urgent.as_ref().get_deadline(), Some(now + 15));
+        assert!(pick_next() == relaxed);
+        for _ in 0..5 {
+            task::process_tick();
+        }
+        assert!(pick_next() == urgent);
+    }
+
+    #[test]
+    fn test_delay_until_cut_by_power_failure() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use task::MissedPeriodPolicy::Skip;
+        mock_boot(1);
+        let start = time::TIME_MANAGER.get_ticks();
+        let alloc = || transaction::run(|j| heap::pm_new(start, j)).unwrap();
+        let last_wake = alloc();
+        let delay = || transaction::run_pure_sys(|t| sys_task_delay_until(last_wake, 10, Skip, t));
+        // the task sleeps, a yield can't return on the host
+        assert!(catch_unwind(AssertUnwindSafe(delay)).is_err());
+        assert_eq!(current().get_wakeup_time(), start + 10);
+        assert_eq!(*last_wake.as_ref(), start);
+        // power fails while the task sleeps
+        mock_reboot();
+        for _ in 0..10 {
+            task::process_tick();
+        }
+        current().jit_recovery();
+        // the task runs again from its start
+        assert!(alloc() == last_wake);
+        assert_eq!(delay(), Ok(()));
+        assert_eq!(*last_wake.as_ref(), start + 10);
+        // no period is skipped
+        assert!(catch_unwind(AssertUnwindSafe(delay)).is_err());
+        assert_eq!(current().get_wakeup_time(), start + 20);
+    }
+
+    #[test]
+    fn test_stack_overflow_kills_task() {
+        mock_boot(2);
+        let victim = current().as_pm_ptr();
+        let (_, bottom) = current().get_stack_region();
+        let limit = bottom - (current().stack_capacity() - 1) * core::mem::size_of::<usize>();
+        // overwrite the canary
+        unsafe { *(limit as *mut usize) = 0 };
+        mock_task_switch();
+        assert!(victim.as_ref().is_killed());
+        assert!(current().as_pm_ptr() != victim);
+    }
+
+    static THREAD_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    fn thread_body() -> usize {
+        THREAD_RUNS.fetch_add(1, Ordering::SeqCst) + 42
+    }
+
+    fn create_thread() -> pthread::JoinHandle<usize> {
+        transaction::run_sys(|_, t| pthread::create("thread", 1, t, thread_body)).unwrap()
+    }
+
+    fn spawn_thread() -> (PMPtr<task::Task>, pthread::JoinHandle<usize>) {
+        THREAD_RUNS.store(0, Ordering::SeqCst);
+        let h = create_thread();
+        (h.task().get_task_ptr().unwrap(), h)
+    }
+
+    // Run `thread` until it exits, then come back to `main`
+    fn run_thread(thread: PMPtr<task::Task>, main: PMPtr<task::Task>) {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        switch_to(thread);
+        let entry = || task::mock_task_entry(thread.as_ref());
+        assert!(catch_unwind(AssertUnwindSafe(entry)).is_err());
+        switch_to(main);
+    }
+
+    fn is_event_group_alive(eg: EventGroupHandle) -> bool {
+        transaction::run_sys_once(|_, t| sys_event_group_get(eg, t)).is_ok()
+    }
+
+    #[test]
+    fn test_pthread_join() {
+        mock_boot(1);
+        let main = current().as_pm_ptr();
+        let (thread, h) = spawn_thread();
+        let done = h.done_event_group();
+        // the thread didn't run yet, the handle is given back
+        let h = transaction::run_sys_once(|_, t| pthread::join(h, 0, t)).err().unwrap();
+        run_thread(thread, main);
+        let r = transaction::run_sys_once(|_, t| pthread::join(h, 0, t));
+        assert_eq!(r.ok(), Some(42));
+        assert_eq!(THREAD_RUNS.load(Ordering::SeqCst), 1);
+        assert!(!is_event_group_alive(done));
+    }
+
+    #[test]
+    fn test_pthread_crash_after_result() {
+        mock_boot(1);
+        let main = current().as_pm_ptr();
+        let (thread, h) = spawn_thread();
+        // power fails after the result is written, before the thread finishes
+        pthread::set_crash_after_result(true);
+        switch_to(thread);
+        task::mock_task_entry(thread.as_ref());
+        pthread::set_crash_after_result(false);
+        mock_reboot();
+        current().jit_recovery();
+        // the thread starts over and runs its closure again
+        run_thread(thread, main);
+        assert_eq!(THREAD_RUNS.load(Ordering::SeqCst), 2);
+        // the main task replays the creation of the thread
+        forget(h);
+        let h = create_thread();
+        let r = transaction::run_sys(|_, t| pthread::join(h, 0, t));
+        assert_eq!(r.ok(), Some(43));
+    }
+
+    #[test]
+    fn test_pthread_detach_frees_slot() {
+        mock_boot(1);
+        let main = current().as_pm_ptr();
+        // detached before the thread finishes, the thread frees the slot
+        let (thread, h) = spawn_thread();
+        let done = h.done_event_group();
+        transaction::run_sys_once(|_, t| pthread::detach(h, t));
+        assert!(is_event_group_alive(done));
+        run_thread(thread, main);
+        assert!(!is_event_group_alive(done));
+        // dropped after the thread finishes, the handle frees the slot
+        let (thread, h) = spawn_thread();
+        let done = h.done_event_group();
+        run_thread(thread, main);
+        assert!(is_event_group_alive(done));
+        drop(h);
+        assert!(!is_event_group_alive(done));
+    }
+
+    #[test]
+    fn test_suppressed_ticks_compensation() {
+        mock_boot(1);
+        let before = time::TIME_MANAGER.get_ticks();
+        // the idle task skipped 3 ticks before this one fired
+        time::TIME_MANAGER.set_suppressed_ticks(3);
+        task::process_tick();
+        assert_eq!(time::TIME_MANAGER.get_ticks(), before + 4);
+        task::process_tick();
+        assert_eq!(time::TIME_MANAGER.get_ticks(), before + 5);
+        // a power failure while the ticks were suppressed doesn't carry them over
+        time::TIME_MANAGER.set_suppressed_ticks(3);
+        mock_reboot();
+        task::process_tick();
+        assert_eq!(time::TIME_MANAGER.get_ticks(), before + 6);
+    }
+
+    fn block_on_notify(waiter: PMPtr<task::Task>) {
+        block_in(waiter, || {
+            transaction::run_sys_once(|_, t| sys_task_notify_wait(0, 0, 100, t))
+        });
+    }
+
+    #[test]
+    fn test_task_notify_wakes_waiter() {
+        let h = boot_with_task(1);
+        let waiter = h.get_task_ptr().unwrap();
+        block_on_notify(waiter);
+        assert!(!waiter.as_ref().is_ready());
+        assert_eq!(unsafe { task::get_delay_list() }.len(), 1);
+        let action = task::NotifyAction::SetValueWithOverwrite;
+        assert!(transaction::run_sys(|_, t| sys_task_notify(h, 5, action, t)).is_ok());
+        // the waiter is woken by the notification
+        assert!(waiter.as_ref().is_ready());
+        assert_eq!(unsafe { task::get_delay_list() }.len(), 0);
+        let ready = unsafe { task::get_sched_list(task::Priority::new(1)) };
+        assert_eq!(ready.length(), 2);
+        assert_eq!(waiter.as_ref().get_notify_value(), 5);
+    }
+
+    #[test]
+    #[cfg(feature = "opt_list")]
+    fn test_crashed_notify_wakeup() {
+        // alone in its ready list or next to another task
+        for prio in [0, 1] {
+            for cp1 in 0..6 {
+                for cp2 in 0..10 {
+                    let h = boot_with_task(prio);
+                    let mut waiter = h.get_task_ptr().unwrap();
+                    block_on_notify(waiter);
+                    let task = unsafe { waiter.as_mut_no_logging() };
+                    list::test::crashed_roll_forward_wake_notified_task(task, cp1, cp2);
+                    list::set_list_crash_point(NO_CRASH);
+                    recover::recover_list_transaction();
+                    assert!(waiter.as_ref().is_ready());
+                    assert_eq!(unsafe { task::get_delay_list() }.len(), 0);
+                    let ready = unsafe { task::get_sched_list(task::Priority::new(prio)) };
+                    assert_eq!(ready.length(), 1 + (prio == 1) as usize);
+                    assert!(task::is_ready_level_marked(prio));
+                    current().reset_list_transaction();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_priority_before_scheduler_start() {
+        boot_common(0);
+        let create = |name, prio| {
+            let heap_sz = heap::PM_HEAP_SIZE_PER_TASK;
+            task::create_task_static(name, prio, 0, 0, heap_sz, task::DEFAULT_STACK_SIZE)
+                .unwrap()
+        };
+        let first = create(TASK_NAMES[0], 2);
+        let second = create(TASK_NAMES[1], 2);
+        assert!(current() as *mut task::Task == first.get_task_ptr().unwrap().as_ptr());
+        // the boot task follows the priority change
+        assert!(task::set_task_priority(second, 1).is_ok());
+        assert!(current() as *mut task::Task == second.get_task_ptr().unwrap().as_ptr());
+        // a crash right after the change is logged, the recovery picks the
+        // boot task as well
+        #[cfg(feature = "opt_list")]
+        {
+            let log = list::ListTxOpLog::get_list_tx_op_log();
+            log.set_task_ptr(first.get_task_ptr());
+            log.set_priority(0);
+            log.set_plist_ptr(None);
+            log.set_tx_op(list::ListTxOpCode::TaskPriorityChange);
+            recover::recover_list_transaction();
+            assert!(current() as *mut task::Task == first.get_task_ptr().unwrap().as_ptr());
+        }
+        arch::start_kernel();
+    }
+
+    #[test]
+    fn test_task_delete_keeps_pm_objects() {
+        mock_boot(1);
+        let victim = transaction::run_sys(|_, t| {
+            sys_create_task("victim", 1, task_noop, 0usize, t).unwrap()
+        });
+        mock_task_switch();
+        assert_eq!(current().get_name(), "victim");
+        let sem = transaction::run_sys(|_, t| sys_create_semaphore(3, t)).unwrap();
+        mock_task_switch();
+        assert!(transaction::run_sys(|_, t| sys_task_delete(victim, t)).is_ok());
+        transaction::run_sys(|_, t| {
+            sys_create_task("respawned", 1, task_noop, 0usize, t).unwrap()
+        });
+        mock_task_switch();
+        assert_eq!(current().get_name(), "respawned");
+        // the heap of the deleted task is not handed out again
+        let other = transaction::run_sys(|_, t| sys_create_semaphore(1, t)).unwrap();
+        assert!(other != sem);
+        assert_eq!(sem.as_ref().capacity(), 3);
+    }
+
+    #[test]
+    fn test_crashed_creation_in_reused_slot() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        mock_boot(1);
+        let (victim, _) = task_delete_and_respawn();
+        let respawned = transaction::run_sys(|_, t| {
+            sys_create_task("doomed", 1, task_noop, 0usize, t).unwrap()
+        });
+        let slot = respawned.get_task_ptr().unwrap();
+        assert!(transaction::run_sys(|_, t| sys_task_delete(respawned, t)).is_ok());
+        // power fails before the creation in the freed slot commits
+        task::set_crash_in_create(true);
+        let stack_size = task::DEFAULT_STACK_SIZE;
+        let heap_sz = heap::PM_HEAP_SIZE_PER_TASK;
+        let create = || task::create_task_static("lost", 1, 0, 0, heap_sz, stack_size);
+        assert!(catch_unwind(AssertUnwindSafe(create)).is_err());
+        task::set_crash_in_create(false);
+        assert!(task::task_by_id(slot.as_ref().get_task_id()) == Some(slot));
+        mock_reboot();
+        current().jit_recovery();
+        // the slot is still the one of the deleted task
+        assert!(slot.as_ref().is_killed());
+        assert!(respawned.get_task_ptr().is_none());
+        assert!(victim.get_task_ptr().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "opt_list")]
+    fn test_crashed_task_removal() {
+        // alone in its ready list, next to another task or blocked on a wait list
+        for (prio, blocked) in [(3, false), (1, false), (1, true)] {
+            for cp1 in 0..6 {
+                for cp2 in 0..8 {
+                    mock_boot(1);
+                    let h = spawn_task(prio);
+                    let mut victim = h.get_task_ptr().unwrap();
+                    if blocked {
+                        let eg = transaction::run_sys_once(|_, t| sys_event_group_create(t));
+                        block_on_event_group(victim, eg.unwrap(), 0b1);
+                    }
+                    let task = unsafe { victim.as_mut_no_logging() };
+                    list::test::crashed_roll_forward_remove_task(task, cp1, cp2);
+                    list::set_list_crash_point(NO_CRASH);
+                    recover::recover_list_transaction();
+                    assert!(victim.as_ref().is_killed());
+                    assert!(h.get_task_ptr().is_none());
+                    assert!(victim.as_ref().get_sched_node_ptr().as_ref().list.is_none());
+                    assert!(!is_waiting(victim));
+                    let ready = unsafe { task::get_sched_list(task::Priority::new(prio)) };
+                    assert_eq!(ready.length(), (prio == 1) as usize);
+                    assert_eq!(task::is_ready_level_marked(prio), prio == 1);
+                    // the slot is taken by the next task
+                    current().reset_list_transaction();
+                    assert!(spawn_task(prio).get_task_ptr().unwrap() == victim);
+                }
+            }
+        }
+    }
+
+    // Two loops in a row, power fails in the second one at item `crash_at`
+    fn task_consecutive_chunked_runs(crash_at: usize) -> (usize, usize) {
+        let (pa, pb) = transaction::run_sys(|_, t| (PBox::new(0, t), PBox::new(0, t)));
+        transaction::run_chunked(10, |range, j, _| *pa.as_mut(j) += range.len());
+        transaction::run_chunked(20, |range, j, _| {
+            assert!(!range.contains(&crash_at), "power failure");
+            *pb.as_mut(j) += range.len();
+        });
+        let sums = unsafe { (*pa.as_ref_no_journal(), *pb.as_ref_no_journal()) };
+        forget(pa);
+        forget(pb);
+        sums
+    }
+
+    #[test]
+    fn test_consecutive_chunked_runs() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        for crash_at in [0, 5, 19] {
+            mock_boot(1);
+            let task = || task_consecutive_chunked_runs(crash_at);
+            assert!(catch_unwind(AssertUnwindSafe(task)).is_err());
+            mock_reboot();
+            current().jit_recovery();
+            // the first loop is not run again, the second one goes on
+            assert_eq!(task_consecutive_chunked_runs(NO_CRASH), (10, 20));
+            assert_eq!(user_tx_stack_top(), 0);
+        }
+    }
+
+    #[test]
+    fn test_chunk_sizing_cut_while_updated() {
+        use crate::transaction::set_chunk_crash_point;
+        mock_boot(1);
+        let ctl = || current().get_mut_user_tx_info().get_chunk_ctl();
+        ctl().start(0);
+        for _ in 0..3 {
+            ctl().begin_chunk(100);
+            ctl().end_chunk(1, 0);
+        }
+        assert_eq!(ctl().get_chunk_size(), 8);
+        // a chunk is cut, then power fails again while the cut is accounted
+        ctl().begin_chunk(100);
+        recover::increase_generation();
+        set_chunk_crash_point(0);
+        ctl().begin_chunk(100);
+        set_chunk_crash_point(NO_CRASH);
+        assert_eq!(ctl().get_chunk_size(), 8);
+        recover::increase_generation();
+        assert_eq!(ctl().begin_chunk(100), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "tickless_idle")]
+    fn test_expected_idle_ticks_with_delayed_task() {
+        time::reset_timer_manager();
+        let h = boot_with_task(1);
+        assert_eq!(task::expected_idle_ticks(), 0);
+        task::task_delay(10, false);
+        // the other task can still run
+        assert_eq!(task::expected_idle_ticks(), 0);
+        switch_to(h.get_task_ptr().unwrap());
+        task::task_delay(4, false);
+        // the idle period ends with the earlier delay
+        assert_eq!(task::expected_idle_ticks(), 4);
+        task::process_tick();
+        assert_eq!(task::expected_idle_ticks(), 3);
+    }
+
+    static IDLE_HOOK_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    fn idle_hook() {
+        IDLE_HOOK_RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_idle_hook() {
+        mock_boot(1);
+        IDLE_HOOK_RUNS.store(0, Ordering::SeqCst);
+        task::register_idle_hook(idle_hook);
+        // registering a hook again doesn't run it twice
+        task::register_idle_hook(idle_hook);
+        task::run_idle_hooks();
+        assert_eq!(IDLE_HOOK_RUNS.load(Ordering::SeqCst), 1);
+        task::run_idle_hooks();
+        assert_eq!(IDLE_HOOK_RUNS.load(Ordering::SeqCst), 2);
+    }
+
     fn task_double_for_loop_tx(crash_point_x: usize, crash_point_y: usize, crash_after: bool) {
         debug_user_tx_cache();
         let px = transaction::run_sys(|j, t| {
@@ -839,6 +1568,7 @@ pub mod test {
             return;
         }
         init_boot_tx();
+        time::TIME_MANAGER.set_suppressed_ticks(0);
         if cp == 2 {
             return;
         }
@@ -1005,6 +1735,207 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn test_pmutex_poisoned_by_deleted_holder() {
+        use crate::user::pmutex::{PMutexError, PMutexStatus};
+        mock_boot(1);
+        let m = transaction::run_sys(|_, t| PArc::new(PMutex::new(7, t), t));
+        let holder = transaction::run_sys(|_, t| {
+            sys_create_task("holder", 1, task_noop, 0usize, t).unwrap()
+        });
+        mock_task_switch();
+        assert_eq!(current().get_name(), "holder");
+        forget(m.try_lock().unwrap());
+        mock_task_switch();
+        assert_eq!(m.try_lock().err(), Some(PMutexError::WouldBlock));
+        assert_eq!(m.status(), PMutexStatus::Locked);
+
+        assert!(transaction::run_sys(|_, t| sys_task_delete(holder, t)).is_ok());
+        assert_eq!(m.status(), PMutexStatus::Poisoned);
+        assert_eq!(m.lock().err(), Some(PMutexError::Poisoned));
+        // repair the data
+        let guard = m.lock_ignore_poison();
+        transaction::run(|j| *guard.as_mut(j) = 8);
+        guard.clear_poison();
+        drop(guard);
+        assert_eq!(*m.lock().unwrap(), 8);
+        assert_eq!(m.status(), PMutexStatus::Unlocked);
+    }
+
+    #[test]
+    fn test_pcondvar_wait_timeout_keeps_lock() {
+        use crate::user::pcondvar::PCondvar;
+        use crate::user::pmutex::PMutexStatus;
+        mock_boot(1);
+        let m = transaction::run_sys(|_, t| PArc::new(PMutex::new(0, t), t));
+        let cv = transaction::run_sys(|_, t| PArc::new(PCondvar::new(t), t));
+        // nobody waits, the notification is not kept
+        cv.notify_one();
+        let guard = m.lock().unwrap();
+        let (guard, r) = cv.wait_timeout(guard, 0);
+        assert!(r.timed_out());
+        assert_eq!(m.status(), PMutexStatus::Locked);
+        drop(guard);
+        assert_eq!(m.status(), PMutexStatus::Unlocked);
+    }
+
+    #[test]
+    fn test_pcondvar_notified_then_crashed() {
+        use crate::user::pmutex::PMutexStatus;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        mock_boot(2);
+        let m = transaction::run_sys(|_, t| PArc::new(PMutex::new(0, t), t));
+        let cv = transaction::run_sys(|_, t| sys_condvar_create(t)).unwrap();
+        let waiter = current().as_pm_ptr();
+        let guard = m.lock().unwrap();
+        let sem = guard.semaphore_handle();
+        forget(guard);
+        // the waiter sleeps, a yield can't return on the host
+        assert!(catch_unwind(AssertUnwindSafe(|| sys_condvar_wait(cv, sem, 100))).is_err());
+        mock_task_switch();
+        assert!(current().as_pm_ptr() != waiter);
+        sys_condvar_notify(cv, false);
+        // power fails before the waiter takes the mutex again
+        mock_reboot();
+        mock_task_switch();
+        assert!(current().as_pm_ptr() == waiter);
+        // the replayed waiter holds the mutex again when it waits
+        assert!(semaphore::semaphore_take(sem, 0).is_ok());
+        assert_eq!(sys_condvar_wait(cv, sem, 100), Ok(()));
+        assert_eq!(m.status(), PMutexStatus::Locked);
+        // the notification is used up
+        assert_eq!(sys_condvar_wait(cv, sem, 0), Err(()));
+    }
+
+    #[test]
+    fn test_prwlock_readers_and_writer() {
+        use crate::user::prwlock::PRwLock;
+        mock_boot(1);
+        let l = transaction::run_sys(|_, t| PArc::new(PRwLock::new(5, t), t));
+        let h = l.handle();
+        let _other = transaction::run_sys(|_, t| {
+            sys_create_task("reader", 1, task_noop, 0usize, t).unwrap()
+        });
+        let r1 = l.read();
+        mock_task_switch();
+        let r2 = l.read();
+        assert_eq!(l.reader_count(), 2);
+        assert_eq!(*r2, 5);
+        // a reader can't turn into a writer
+        let w = transaction::run_sys(|_, t| sys_rwlock_acquire(h, 0, true, t));
+        assert_eq!(w, Err(rwlock::RwLockErr::Deadlock));
+        drop(r2);
+        // a writer can't get in while anyone reads
+        let w = transaction::run_sys(|_, t| sys_rwlock_acquire(h, 0, true, t));
+        assert_eq!(w, Err(rwlock::RwLockErr::Timeout));
+        mock_task_switch();
+        // the holder can't take it again
+        let r = transaction::run_sys(|_, t| sys_rwlock_acquire(h, 0, false, t));
+        assert_eq!(r, Err(rwlock::RwLockErr::Deadlock));
+        assert_eq!(l.reader_count(), 1);
+        drop(r1);
+        assert_eq!(l.reader_count(), 0);
+
+        let w = l.write();
+        assert!(l.is_write_locked());
+        transaction::run(|j| *w.as_mut(j) = 6);
+        drop(w);
+        assert_eq!(*l.read(), 6);
+    }
+
+    #[test]
+    fn test_prwlock_abandoned_by_deleted_reader() {
+        use crate::user::prwlock::PRwLock;
+        mock_boot(1);
+        let l = transaction::run_sys(|_, t| PArc::new(PRwLock::new(5, t), t));
+        let reader = transaction::run_sys(|_, t| {
+            sys_create_task("reader", 1, task_noop, 0usize, t).unwrap()
+        });
+        mock_task_switch();
+        forget(l.read());
+        mock_task_switch();
+        assert_eq!(l.reader_count(), 1);
+        assert!(transaction::run_sys(|_, t| sys_task_delete(reader, t)).is_ok());
+        // the slot of the deleted reader doesn't hold back writers
+        assert_eq!(l.reader_count(), 0);
+        drop(l.write());
+    }
+
+    #[test]
+    fn test_crashed_rwlock_acquire() {
+        use crate::user::prwlock::PRwLock;
+        let setup = |_| {
+            let l = transaction::run_sys(|_, t| PArc::new(PRwLock::new(5, t), t));
+            let h = l.handle();
+            forget(l);
+            h
+        };
+        let acquire = |h, t: SyscallToken| sys_rwlock_acquire(h, 0, false, t);
+        crash_and_replay_syscall(|| mock_boot(1), setup, acquire, |h: RwLockHandle, r| {
+            // the replayed acquire didn't take a second slot or fail as a
+            // re-acquire
+            assert!(r.is_ok());
+            assert_eq!(h.as_ref().reader_count(), 1);
+            transaction::run_sys(|_, t| sys_rwlock_release(h, t));
+            assert_eq!(h.as_ref().reader_count(), 0);
+        });
+    }
+
+    #[test]
+    fn test_binary_semaphore_is_not_a_mutex() {
+        mock_boot(1);
+        let sem = transaction::run_sys(|_, t| sys_create_semaphore(1, t)).unwrap();
+        let holder = transaction::run_sys(|_, t| {
+            sys_create_task("holder", 1, task_noop, 0usize, t).unwrap()
+        });
+        mock_task_switch();
+        assert!(sys_semaphore_take(sem, 0).is_ok());
+        // a signal is not taken twice by the same task
+        assert!(sys_semaphore_take(sem, 0).is_err());
+        assert_eq!(current().get_mutexes_held(), 0);
+        mock_task_switch();
+        assert!(transaction::run_sys(|_, t| sys_task_delete(holder, t)).is_ok());
+        assert!(!sem.as_ref().is_poisoned());
+        assert!(sem.as_ref().is_empty());
+    }
+
+    #[test]
+    fn test_pmutex_poisoned_by_skipped_tx() {
+        use crate::user::pmutex::{PMutexError, PMutexStatus};
+        use crate::user::transaction::{
+            set_watchdog_policy, set_watchdog_threshold, WatchdogAction,
+        };
+        fn skip(_: &str, _: usize, _: usize) -> WatchdogAction {
+            WatchdogAction::Skip
+        }
+        mock_boot(1);
+        let m = transaction::run_sys(|_, t| PArc::new(PMutex::new(1, t), t));
+        let guard = m.lock().unwrap();
+        set_watchdog_threshold(0);
+        // a tx which is not skipped leaves the lock alone
+        let r = transaction::run_with_fallback(|_| 1, |_| 0);
+        assert_eq!(r, 1);
+        set_watchdog_policy(skip);
+        // without a fallback there is nothing to skip to
+        transaction::run(|_| {});
+        assert_eq!(m.status(), PMutexStatus::Locked);
+        let r = transaction::run_with_fallback(
+            |j| {
+                *guard.as_mut(j) = 2;
+                1
+            },
+            |_| 0,
+        );
+        set_watchdog_threshold(8);
+        set_watchdog_policy(|_, _, _| WatchdogAction::Log);
+        assert_eq!(r, 0);
+        assert_eq!(*guard, 1);
+        // the holder keeps the lock, later lockers see the poison
+        assert_eq!(m.status(), PMutexStatus::Poisoned);
+        drop(guard);
+        assert_eq!(m.lock().err(), Some(PMutexError::Poisoned));
+    }
+
     fn task_crashed_queue_operations(cp: usize, scp: usize) {
         let (q, b, c) = transaction::may_crashed_run_sys(cp == 0, |j, t| {
             let q = syscalls::sys_queue_create::<i32>(1, t);
@@ -1024,7 +1955,7 @@ pub mod test {
             set_syscall_end_crash_point(scp);
         }
         transaction::may_crashed_run_sys(cp == 1, |j, t| {
-            sys_queue_send_back(q, &data1, 0xfff, t);
+            sys_queue_send_back(q, data1, 0xfff, t);
         });
         if cp == 1 {
             forget(b);
@@ -1052,7 +1983,7 @@ pub mod test {
             set_syscall_end_crash_point(scp);
         }
         transaction::may_crashed_run_sys(cp == 3, |j, t| {
-            sys_queue_send_back(q, &data1, 0xfff, t);
+            sys_queue_send_back(q, data1, 0xfff, t);
         });
         if cp == 3 {
             forget(b);
@@ -1092,6 +2023,234 @@ pub mod test {
         forget(c);
     }
 
+    fn receive_from(q: QueueHandle<i32>) -> Result<i32, queue::QueueErr> {
+        transaction::run_sys_once(|_, t| sys_queue_receive::<i32>(q, 100, t))
+    }
+
+    #[test]
+    fn test_queue_delete() {
+        let setup = |crash| {
+            let q = transaction::run_sys(|_, t| syscalls::sys_queue_create::<i32>(1, t)).unwrap();
+            let reader = spawn_noop_task();
+            if crash {
+                block_in(reader, || receive_from(q));
+            } else {
+                // the crashed delete went through completely or not at all
+                assert!(!q.is_deleted() || !is_waiting(reader));
+            }
+            (q, reader)
+        };
+        let delete = |(q, _), t: SyscallToken| sys_queue_delete(q, t);
+        crash_and_replay_syscall(|| mock_boot(1), setup, delete, |(q, reader), r| {
+            assert!(r.is_ok());
+            assert!(q.is_deleted());
+            // the reader is woken and finds the queue gone
+            assert!(reader.as_ref().is_ready());
+            switch_to(reader);
+            assert!(matches!(receive_from(q), Err(queue::QueueErr::QueueDeleted)));
+        });
+    }
+
+    #[test]
+    fn test_event_group_clear_get() {
+        mock_boot(1);
+        let eg = transaction::run_sys(|_, t| sys_event_group_create(t)).unwrap();
+        transaction::run_sys(|_, t| {
+            sys_event_group_set(eg, 0b101, t);
+            assert_eq!(sys_event_group_clear(eg, 0b001, t), Ok(0b101));
+            assert_eq!(sys_event_group_get(eg, t), Ok(0b100));
+        });
+    }
+
+    fn block_on_event_group(waiter: PMPtr<task::Task>, eg: EventGroupHandle, bits: usize) {
+        block_in(waiter, || {
+            transaction::run_sys_once(|_, t| sys_event_group_wait(eg, bits, false, false, 100, t))
+        });
+    }
+
+    fn is_waiting(task: PMPtr<task::Task>) -> bool {
+        task.as_ref().get_event_node_ptr().as_ref().list.is_some()
+    }
+
+    // A waiter blocked on `eg` for `bits` by the crashed run
+    fn event_group_with_waiter(crash: bool, bits: usize) -> (EventGroupHandle, PMPtr<task::Task>) {
+        let eg = transaction::run_sys(|_, t| sys_event_group_create(t)).unwrap();
+        let waiter = spawn_noop_task();
+        if crash {
+            block_on_event_group(waiter, eg, bits);
+        }
+        (eg, waiter)
+    }
+
+    #[test]
+    fn test_event_group_delete() {
+        let setup = |crash| {
+            let (eg, waiter) = event_group_with_waiter(crash, 0b1);
+            // the crashed delete went through completely or not at all
+            let deleted = event_group::event_group_get(eg).is_err();
+            assert!(crash || !deleted || !is_waiting(waiter));
+            (eg, waiter)
+        };
+        let delete = |(eg, _), t: SyscallToken| sys_event_group_delete(eg, t);
+        crash_and_replay_syscall(|| mock_boot(1), setup, delete, |(eg, mut waiter), r| {
+            assert!(r.is_ok());
+            // the waiter is woken by the delete
+            assert!(waiter.as_ref().is_ready());
+            let value = unsafe { waiter.as_mut_no_logging() }.get_block_item_value();
+            assert!(value & event_group::UNBLOCKED_DUE_TO_DELETE != 0);
+            // the handle is stale now
+            transaction::run_sys(|_, t| {
+                assert!(sys_event_group_get(eg, t).is_err());
+                assert!(sys_event_group_wait(eg, 0b1, false, false, 0, t).is_err());
+                assert!(sys_event_group_delete(eg, t).is_err());
+            });
+        });
+    }
+
+    #[test]
+    fn test_event_group_deferred_set() {
+        let setup = |crash| {
+            let (eg, waiter) = event_group_with_waiter(crash, 0b10);
+            // the crashed set went through completely or not at all
+            let set = event_group::event_group_get(eg) == Ok(0b10);
+            assert!(crash || set != is_waiting(waiter));
+            assert!(event_group::event_group_set_from_isr(eg, 0b10).is_ok());
+            (eg, waiter)
+        };
+        let apply = |_, t: SyscallToken| sys_event_group_apply_deferred(t);
+        let boot = || mock_boot_with_timer_daemon(1);
+        crash_and_replay_syscall(boot, setup, apply, |(eg, mut waiter), r| {
+            assert!(r);
+            // a set left over by a crashed round is applied as well
+            while transaction::run_sys(|_, t| sys_event_group_apply_deferred(t)) {}
+            assert_eq!(transaction::run_sys(|_, t| sys_event_group_get(eg, t)), Ok(0b10));
+            // the waiter is woken by the set
+            assert!(waiter.as_ref().is_ready());
+            let value = unsafe { waiter.as_mut_no_logging() }.get_block_item_value();
+            assert!(value & event_group::UNBLOCKED_DUE_TO_BIT_SET != 0);
+        });
+    }
+
+    #[test]
+    fn test_queue_mailbox() {
+        mock_boot(1);
+        let q = transaction::run_sys(|_, t| syscalls::sys_queue_create::<i32>(1, t)).unwrap();
+        transaction::run_sys(|_, t| {
+            assert_eq!(sys_queue_spaces_available(q, t).ok(), Some(1));
+            sys_queue_overwrite(q, 1, t).unwrap();
+            sys_queue_overwrite(q, 2, t).unwrap();
+            assert_eq!(sys_queue_messages_waiting(q, t).ok(), Some(1));
+            // peeking leaves the latest reading in place
+            assert_eq!(sys_queue_peek(q, 0, t).ok(), Some(2));
+            assert_eq!(sys_queue_receive(q, 0, t).ok(), Some(2));
+            assert_eq!(sys_queue_messages_waiting(q, t).ok(), Some(0));
+        });
+    }
+
+    #[test]
+    fn test_stream_and_message_buffers() {
+        mock_boot(1);
+        let (sb, mb) = transaction::run_sys(|_, t| {
+            let sb = sys_stream_buffer_create(4, 2, t).unwrap();
+            let mb = sys_message_buffer_create(16, t).unwrap();
+            (sb, mb)
+        });
+        // a stream takes what fits and wraps around its end
+        transaction::run_sys(|_, t| {
+            assert_eq!(sys_stream_buffer_send(sb, &[1, 2, 3, 4, 5], 0, t), Ok(4));
+            let chunk = sys_stream_buffer_receive::<3>(sb, 0, t).unwrap();
+            assert_eq!(*chunk, [1, 2, 3]);
+            assert_eq!(sys_stream_buffer_send(sb, &[6, 7], 0, t), Ok(2));
+        });
+        transaction::run_sys(|_, t| {
+            let chunk = sys_stream_buffer_receive::<8>(sb, 0, t).unwrap();
+            assert_eq!(*chunk, [4, 6, 7]);
+            assert!(sys_stream_buffer_receive::<8>(sb, 0, t).is_err());
+        });
+        // a message is received whole or not at all
+        transaction::run_sys(|_, t| {
+            assert_eq!(sys_stream_buffer_send(mb, &[9; 5], 0, t), Ok(5));
+            assert!(sys_stream_buffer_send(mb, &[9; 5], 0, t).is_err());
+            assert!(sys_stream_buffer_receive::<4>(mb, 0, t).is_err());
+        });
+        transaction::run_sys(|_, t| {
+            assert_eq!(*sys_stream_buffer_receive::<8>(mb, 0, t).unwrap(), [9; 5]);
+            assert_eq!(sys_stream_buffer_bytes_available(mb, t), 0);
+        });
+    }
+
+    fn create_message_buffer() -> StreamBufferHandle {
+        transaction::run_sys(|_, t| sys_message_buffer_create(16, t)).unwrap()
+    }
+
+    fn receive_message(mb: StreamBufferHandle) -> Result<StreamChunk<8>, StreamErr> {
+        transaction::run_sys(|_, t| sys_stream_buffer_receive::<8>(mb, 0, t))
+    }
+
+    #[test]
+    fn test_crashed_message_buffer() {
+        let boot = || mock_boot(1);
+        // the message went in and came out whole, exactly once
+        let received_once = |mb, r: Result<StreamChunk<8>, StreamErr>| {
+            assert_eq!(*r.unwrap(), [1, 2, 3]);
+            assert_eq!(transaction::run_sys(|_, t| sys_stream_buffer_bytes_available(mb, t)), 0);
+        };
+        let send = |mb, t: SyscallToken| sys_stream_buffer_send(mb, &[1, 2, 3], 0, t);
+        crash_and_replay_syscall(boot, |_| create_message_buffer(), send, |mb, r| {
+            assert_eq!(r, Ok(3));
+            received_once(mb, receive_message(mb));
+        });
+        let setup = |_| {
+            let mb = create_message_buffer();
+            assert_eq!(transaction::run_sys(|_, t| send(mb, t)), Ok(3));
+            mb
+        };
+        let receive = |mb, t: SyscallToken| sys_stream_buffer_receive::<8>(mb, 0, t);
+        crash_and_replay_syscall(boot, setup, receive, received_once);
+    }
+
+    #[test]
+    fn test_queue_set_select() {
+        mock_boot(1);
+        let (set, q) = transaction::run_sys(|_, t| {
+            let set = sys_queue_set_create(2, t).unwrap();
+            let q = syscalls::sys_queue_create::<i32>(2, t).unwrap();
+            (set, q)
+        });
+        transaction::run_sys(|_, t| {
+            assert!(sys_queue_add_to_set(q, set, t).is_ok());
+            // already a member
+            assert!(sys_queue_add_to_set(q, set, t).is_err());
+            assert!(sys_queue_select(set, 0, t).is_err());
+            sys_queue_send_back(q, 7, 0, t).unwrap();
+            let member = sys_queue_select(set, 0, t).ok().unwrap();
+            assert!(q.is_member(member));
+            assert_eq!(sys_queue_receive(q, 0, t).ok(), Some(7));
+        });
+    }
+
+    #[test]
+    fn test_queue_set_select_woken_by_semaphore() {
+        let waiter = boot_with_task(1).get_task_ptr().unwrap();
+        let sem = transaction::run_sys(|_, t| sys_create_semaphore(1, t)).unwrap();
+        // members join empty
+        assert!(sys_semaphore_take(sem, 0).is_ok());
+        let set = transaction::run_sys(|_, t| {
+            let set = sys_queue_set_create(1, t).unwrap();
+            assert!(sys_semaphore_add_to_set(sem, set, t).is_ok());
+            set
+        });
+        block_in(waiter, || transaction::run_sys_once(|_, t| sys_queue_select(set, 100, t)));
+        assert!(!waiter.as_ref().is_ready());
+        sys_semaphore_give(sem);
+        // the selector is woken, the count is left for it to take
+        assert!(waiter.as_ref().is_ready());
+        assert!(!sem.as_ref().is_empty());
+        let member = transaction::run_sys_once(|_, t| sys_queue_select(set, 0, t));
+        assert!(member.ok() == Some(queue::QueueSetMember::Semaphore(sem)));
+        assert!(sys_semaphore_take(sem, 0).is_ok());
+    }
+
     #[test]
     fn test_queue_operations() {
         fn run(cp: usize, scp: usize) {
@@ -1114,4 +2273,74 @@ pub mod test {
             }
         }
     }
+
+    #[test]
+    #[cfg(feature = "jit_checkpoint")]
+    fn test_jit_checkpoint_save_restore() {
+        use checkpoint::ExecMode;
+        mock_boot(1);
+        crate::transaction::run(|j| current().set_exec_mode(ExecMode::Checkpoint, j));
+        let (stack_top, stack_bottom) = current().get_stack_region();
+        let stack = || unsafe {
+            let n = (stack_bottom - stack_top) / core::mem::size_of::<usize>();
+            std::slice::from_raw_parts_mut(stack_top as *mut usize, n)
+        };
+        stack().iter_mut().enumerate().for_each(|(i, w)| *w = i);
+        let cs = unsafe { critical::CriticalSection::new() };
+        unsafe { checkpoint::take_checkpoint(&cs) };
+        // the volatile stack is gone with the power
+        stack().fill(0);
+        mock_reboot();
+        current().jit_recovery();
+        assert!(!current().is_crashed());
+        assert_eq!(current().get_stack_region(), (stack_top, stack_bottom));
+        assert!(stack().iter().enumerate().all(|(i, w)| *w == i));
+        // a checkpoint is only resumed once
+        let (tid, gen) = (current().get_task_id(), current().get_generation());
+        assert!(checkpoint::restore_stack(tid, gen, stack_bottom).is_none());
+    }
+
+    #[test]
+    fn test_watchdog_threshold_per_tx() {
+        use crate::user::transaction::{
+            set_watchdog_policy, set_watchdog_threshold, WatchdogAction,
+        };
+        mock_boot(1);
+        let info = || current().get_mut_user_tx_info();
+        // the next tx is cut twice, another one commits before each
+        // re-execution
+        let cut_twice = || {
+            let tx_id = info().get_tx_cache().get_tx_id_of_ptr();
+            for _ in 0..2 {
+                assert!(!info().get_mut_watchdog().tx_begin(tx_id, 2));
+                recover::increase_generation();
+                assert!(!info().get_mut_watchdog().tx_begin(tx_id + 1, 2));
+                info().get_mut_watchdog().tx_end();
+            }
+        };
+        set_watchdog_threshold(2);
+        let tx_id = info().get_tx_cache().get_tx_id_of_ptr();
+        cut_twice();
+        set_watchdog_policy(|_, _, cnt| {
+            assert_eq!(cnt, 2);
+            WatchdogAction::Skip
+        });
+        assert_eq!(transaction::run_with_fallback(|_| 1, |_| 0), 0);
+        assert_eq!(info().get_watchdog().get_trips(), 1);
+        // the count is gone once the tx committed
+        assert!(!info().get_mut_watchdog().tx_begin(tx_id, 2));
+        info().get_mut_watchdog().tx_end();
+        // a split falls back to chunks of a single item
+        for _ in 0..3 {
+            info().get_chunk_ctl().end_chunk(1, 0);
+        }
+        assert_eq!(info().get_chunk_ctl().get_chunk_size(), 8);
+        cut_twice();
+        set_watchdog_policy(|_, _, _| WatchdogAction::Split);
+        transaction::run_once(|_| ());
+        assert_eq!(info().get_chunk_ctl().get_chunk_size(), 1);
+        assert_eq!(info().get_watchdog().get_trips(), 2);
+        set_watchdog_threshold(8);
+        set_watchdog_policy(|_, _, _| WatchdogAction::Log);
+    }
 }
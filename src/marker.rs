@@ -28,3 +28,7 @@ unsafe impl<T: ?Sized + TxRefInSafe> TxInSafe for &T {}
 unsafe impl<T: ?Sized> TxRefInSafe for crate::pmem::PMPtr<T> {}
 unsafe impl<T: ?Sized> TxRefInSafe for NonNull<T> {}
 unsafe impl<T: ?Sized> TxRefInSafe for PhantomData<T> {}
+
+// Invariant lifetime used to brand the journal handle and the syscall token
+// of a transaction, a fresh one for each invocation of `run`/`run_sys`
+pub type InvariantLifetime<'id> = PhantomData<fn(&'id ()) -> &'id ()>;
@@ -1,11 +1,12 @@
 use core::cell::UnsafeCell;
 use core::fmt::Display;
+use core::marker::PhantomData;
 use core::mem::size_of;
 use core::ops::Deref;
 use core::ptr::NonNull;
 use vcell::VolatileCell;
 
-use crate::marker::{PSafe, TxInSafe, TxOutSafe};
+use crate::marker::{InvariantLifetime, PSafe, TxInSafe, TxOutSafe};
 use crate::task::{current, is_scheduler_started, task_get_stats};
 use crate::util::compiler_pm_fence;
 use crate::util::{arch_addr_align_up, benchmark_clock};
@@ -239,16 +240,27 @@ const JOURNAL_SIZE: usize = board::PM_JOURNAL_SIZE;
 #[cfg(sram_baseline)]
 const JOURNAL_SIZE: usize = 0;
 
+/// The handle is only valid inside the transaction whose brand it carries.
+/// The relaxed run skips the marker traits, only the brand stops an escape:
+///
+/// ```compile_fail,E0521
+/// let mut escaped = None;
+/// unsafe { intos::transaction::run_relaxed(|j| escaped = Some(j)) };
+/// ```
 #[derive(Clone, Copy)]
-pub struct JournalHandle(*mut Journal);
+pub struct JournalHandle<'id>(*mut Journal, InvariantLifetime<'id>);
 
-impl JournalHandle {
-    pub fn new(ptr: *const Journal) -> Self {
-        Self(ptr as *mut Journal)
+impl<'id> JournalHandle<'id> {
+    pub unsafe fn new(ptr: *const Journal) -> Self {
+        Self(ptr as *mut Journal, PhantomData)
     }
 
     pub unsafe fn new_dummy() -> Self {
-        Self(unsafe { core::ptr::null_mut() })
+        Self(unsafe { core::ptr::null_mut() }, PhantomData)
+    }
+
+    pub fn as_ptr(&self) -> *const Journal {
+        self.0
     }
 
     #[inline(always)]
@@ -311,9 +323,9 @@ pub fn get_ulog_sz() -> u32 {
 
 fn is_jounral_of_kernel(j: &Journal) -> bool {
     let j_ptr = j as *const Journal as *mut Journal;
-    if j_ptr == crate::recover::get_boot_tx().get_journal().0 {
+    if j_ptr == unsafe { crate::recover::get_boot_tx().get_journal() }.0 {
         return true;
-    } else if j_ptr == current().get_mut_tx().get_journal().0 {
+    } else if j_ptr == unsafe { current().get_mut_tx().get_journal() }.0 {
         return true;
     } else {
         return false;
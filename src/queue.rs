@@ -1,12 +1,14 @@
 use crate::critical::{self, CriticalSection};
 use crate::debug_print;
+use crate::event_group;
 use crate::list::{self, InsertSortedPList};
 use crate::list::{Node, SortedPList};
 use crate::pmem::{JournalHandle, PMPtr, PMVar};
+use crate::semaphore::Semaphore;
 use crate::task::{BlockedListItem, ErrorCode, SchedListItem};
 use crate::vec::PArray;
 use crate::{
-    arch, heap, task,
+    arch, declare_pm_var, heap, task,
     time::{self, Time},
     transaction,
 };
@@ -22,8 +24,48 @@ pub struct Queue {
     items_enqueued: PMVar<usize>,
     blocked_readers: PMVar<SortedPList<BlockedListItem>>,
     blocked_writers: PMVar<SortedPList<BlockedListItem>>,
+    // matched against the handles, 0 once the queue is deleted
+    generation: usize,
+    // the set this queue is a member of
+    set: Option<PMPtr<Queue>>,
+    // of a set, the room its members may fill
+    set_reserved: usize,
 }
 
+// An entry of a queue set, the member which got an item or a count
+#[derive(Clone, Copy, PartialEq)]
+pub enum QueueSetMember {
+    Queue(PMPtr<Queue>),
+    Semaphore(PMPtr<Semaphore>),
+}
+
+impl QueueSetMember {
+    fn set_link(&mut self) -> &mut Option<PMPtr<Queue>> {
+        match self {
+            Self::Queue(q) => unsafe { &mut q.as_mut_no_logging().set },
+            Self::Semaphore(s) => unsafe { s.as_mut_no_logging().set_link() },
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            Self::Queue(q) => q.as_ref().length,
+            Self::Semaphore(s) => s.as_ref().capacity(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Queue(q) => q.as_ref().is_empty(),
+            Self::Semaphore(s) => s.as_ref().is_empty(),
+        }
+    }
+}
+
+// Every queue gets a distinct generation, so a stale handle doesn't reach a
+// queue created later at the same address
+declare_pm_var!(NEXT_QUEUE_GENERATION, usize, 1);
+
 #[derive(Debug, Copy, Clone)]
 pub enum QueueErr {
     NoMemory,
@@ -50,16 +92,19 @@ impl Queue {
                 Some(p) => p,
             };
 
-            // allocate buffer
-
-            let buffer = PArray::<u8>::new(length * item_size, j);
+            // allocate buffer, a mailbox keeps a spare slot for overwrites
+            let slots = if length == 1 { 2 } else { length };
+            let buffer = PArray::<u8>::new(slots * item_size, j);
             let buffer = match buffer {
                 None => {
                     return Err(ErrorCode::NoSpace);
                 }
                 Some(b) => b,
             };
+            let next_gen = unsafe { NEXT_QUEUE_GENERATION.borrow_mut(j) };
             let q = unsafe { ptr.as_mut_no_logging() };
+            q.generation = *next_gen;
+            *next_gen += 1;
             unsafe {
                 q.length = length;
                 q.item_size = item_size;
@@ -67,9 +112,11 @@ impl Queue {
                 q.write_pos = PMVar::new(0);
                 q.items_enqueued = PMVar::new(0);
                 q.buffer = buffer;
-                q.tail = item_size * length;
+                q.tail = item_size * slots;
                 q.blocked_readers = PMVar::new(SortedPList::new());
                 q.blocked_writers = PMVar::new(SortedPList::new());
+                q.set = None;
+                q.set_reserved = 0;
             }
             Ok(r)
         })
@@ -124,11 +171,18 @@ impl Queue {
         &mut self,
         item_ptr: NonNull<u8>,
         j: JournalHandle,
-        _: &CriticalSection,
+        cs: &CriticalSection,
     ) -> Result<(), QueueErr> {
         if self.is_full() {
             return Err(QueueErr::QueueFull);
         }
+        self.enqueue_back(item_ptr, j);
+        let member = QueueSetMember::Queue(unsafe { PMPtr::from_mut_ref(self) });
+        post_to_set(self.set, member, j, cs);
+        Ok(())
+    }
+
+    fn enqueue_back(&mut self, item_ptr: NonNull<u8>, j: JournalHandle) {
         unsafe {
             let dst = self.buffer.as_ptr().add(*self.write_pos);
             ptr::copy_nonoverlapping(item_ptr.as_ptr(), dst, self.item_size);
@@ -139,14 +193,13 @@ impl Queue {
             *write_pos = 0;
         }
         *self.items_enqueued.borrow_mut(j) += 1;
-        Ok(())
     }
 
     pub fn push_front(
         &mut self,
         item_ptr: NonNull<u8>,
         j: JournalHandle,
-        _: &CriticalSection,
+        cs: &CriticalSection,
     ) -> Result<(), QueueErr> {
         if self.is_full() {
             return Err(QueueErr::QueueFull);
@@ -163,7 +216,8 @@ impl Queue {
         }
 
         *self.items_enqueued.borrow_mut(j) += 1;
-
+        let member = QueueSetMember::Queue(unsafe { PMPtr::from_mut_ref(self) });
+        post_to_set(self.set, member, j, cs);
         Ok(())
     }
 
@@ -186,6 +240,59 @@ impl Queue {
         Ok(item)
     }
 
+    pub fn peek<T>(&self, _: &CriticalSection) -> Result<T, QueueErr> {
+        if self.is_empty() {
+            return Err(QueueErr::QueueEmpty);
+        }
+        let item = unsafe {
+            let src = self.buffer.as_ptr().add(*self.read_pos);
+            ptr::read::<T>(src as *const T)
+        };
+        Ok(item)
+    }
+
+    // Only for mailboxes. The new item goes to the spare slot and the old
+    // one is dropped in the same transaction, so it's never torn. A
+    // replaced item is already in the set of the queue, if any.
+    pub fn overwrite(
+        &mut self,
+        item_ptr: NonNull<u8>,
+        j: JournalHandle,
+        cs: &CriticalSection,
+    ) -> Result<(), QueueErr> {
+        if self.length != 1 {
+            return Err(QueueErr::InvalidParam);
+        }
+        if self.is_full() {
+            let read_pos = self.read_pos.borrow_mut(j);
+            *read_pos += self.item_size;
+            if *read_pos == self.tail {
+                *read_pos = 0;
+            }
+            *self.items_enqueued.borrow_mut(j) -= 1;
+            self.enqueue_back(item_ptr, j);
+            return Ok(());
+        }
+        self.push_back(item_ptr, j, cs)
+    }
+
+    pub fn get_generation(&self) -> usize {
+        self.generation
+    }
+
+    #[inline(always)]
+    pub fn is_alive(&self, gen: usize) -> bool {
+        self.generation != 0 && self.generation == gen
+    }
+
+    // Mark the queue dead. Its memory is not reclaimed: the PM heaps only
+    // bump, and the queue may come from the heap of another task. Stale
+    // handles can still read the generation this way.
+    fn release(&mut self, j: JournalHandle) {
+        j.get_mut().append_log_of(&mut self.generation as *mut usize);
+        self.generation = 0;
+    }
+
     #[inline(always)]
     fn is_full(&self) -> bool {
         *self.items_enqueued == self.length
@@ -203,6 +310,7 @@ pub fn queue_create(length: usize, item_size: usize) -> Option<PMPtr<Queue>> {
 #[cfg(not(feature = "opt_list"))]
 fn queue_send_impl(
     q: &mut Queue,
+    gen: usize,
     item_ptr: NonNull<u8>,
     mut wait_ticks: Time,
     is_back: bool,
@@ -214,6 +322,10 @@ fn queue_send_impl(
 
     loop {
         let r = critical::with_no_interrupt(|cs| unsafe {
+            // also checked after each wakeup, the queue may be gone by then
+            if !q.is_alive(gen) {
+                return Ok(Err(QueueErr::QueueDeleted));
+            }
             transaction::run_relaxed(|j| {
                 let r = if is_back {
                     q.push_back(item_ptr, j, cs)
@@ -266,6 +378,7 @@ fn queue_send_impl(
 #[cfg(feature = "opt_list")]
 fn queue_send_impl(
     q: &mut Queue,
+    gen: usize,
     item_ptr: NonNull<u8>,
     mut wait_ticks: Time,
     is_back: bool,
@@ -277,6 +390,12 @@ fn queue_send_impl(
     let mut exit_loop = false;
     loop {
         let yld = critical::with_no_interrupt(|cs| {
+            // also checked after each wakeup, the queue may be gone by then
+            if !q.is_alive(gen) {
+                res = Err(QueueErr::QueueDeleted);
+                exit_loop = true;
+                return false;
+            }
             let r = unsafe {
                 transaction::try_run_relaxed(|j| {
                     let r = if is_back {
@@ -318,6 +437,7 @@ fn queue_send_impl(
                 Ok(value) => {
                     let wl = unsafe { q.blocked_readers.borrow_mut_no_logging() };
                     list::atomic_roll_forward_pop_remove_from_waitlist(wl, cs);
+                    wake_set_selectors(q.set, cs);
                     res = Ok(());
                     exit_loop = true;
                 }
@@ -339,34 +459,48 @@ fn queue_send_impl(
 
 pub fn queue_send_back(
     mut q: PMPtr<Queue>,
+    gen: usize,
     item_ptr: NonNull<u8>,
     wait_ticks: Time,
 ) -> Result<(), QueueErr> {
     let q = unsafe { q.as_mut_no_logging() };
-    queue_send_impl(q, item_ptr, wait_ticks, true)
+    queue_send_impl(q, gen, item_ptr, wait_ticks, true)
 }
 
 pub fn queue_send_front(
     mut q: PMPtr<Queue>,
+    gen: usize,
     item_ptr: NonNull<u8>,
     wait_ticks: Time,
 ) -> Result<(), QueueErr> {
     let q = unsafe { q.as_mut_no_logging() };
-    queue_send_impl(q, item_ptr, wait_ticks, false)
+    queue_send_impl(q, gen, item_ptr, wait_ticks, false)
 }
 
+// A peek leaves the item in place, so it passes the wakeup on to the next
+// blocked reader instead of a writer
 #[cfg(not(feature = "opt_list"))]
-pub fn queue_receive<T>(mut q: PMPtr<Queue>, mut wait_ticks: Time) -> Result<T, QueueErr> {
+fn queue_receive_impl<T>(
+    mut q: PMPtr<Queue>,
+    gen: usize,
+    mut wait_ticks: Time,
+    remove: bool,
+) -> Result<T, QueueErr> {
     let mut res;
     let mut wakeup_time_set = false;
     let mut wakeup_time = 0;
+    let mut deleted = false;
     let task = task::current();
 
     loop {
         res = critical::with_no_interrupt(|cs| unsafe {
+            if !q.as_ref().is_alive(gen) {
+                deleted = true;
+                return Err(ErrorCode::InvalidParam);
+            }
             transaction::run_relaxed(|j| {
                 let q = unsafe { q.as_mut_no_logging() };
-                let r = q.dequeue(j, cs);
+                let r = if remove { q.dequeue(j, cs) } else { q.peek(cs) };
                 match r {
                     Err(_) => {
                         if wait_ticks != 0 {
@@ -384,12 +518,15 @@ pub fn queue_receive<T>(mut q: PMPtr<Queue>, mut wait_ticks: Time) -> Result<T,
                         Err(ErrorCode::TxRetry)
                     }
                     Ok(v) => {
-                        q.wakeup_blocked_task(false, j, cs);
+                        q.wakeup_blocked_task(!remove, j, cs);
                         Ok(v)
                     }
                 }
             })
         });
+        if deleted {
+            return Err(QueueErr::QueueDeleted);
+        }
         match res {
             Ok(_) => {
                 break;
@@ -412,19 +549,30 @@ pub fn queue_receive<T>(mut q: PMPtr<Queue>, mut wait_ticks: Time) -> Result<T,
 }
 
 #[cfg(feature = "opt_list")]
-pub fn queue_receive<T>(mut q: PMPtr<Queue>, mut wait_ticks: Time) -> Result<T, QueueErr> {
+fn queue_receive_impl<T>(
+    mut q: PMPtr<Queue>,
+    gen: usize,
+    mut wait_ticks: Time,
+    remove: bool,
+) -> Result<T, QueueErr> {
     let mut res;
     let mut wakeup_time_set = false;
     let mut wakeup_time = 0;
     let task = task::current();
     let mut exit_loop = false;
     let mut yld = false;
+    let mut deleted = false;
     loop {
         res = critical::with_no_interrupt(|cs| {
+            if !q.as_ref().is_alive(gen) {
+                deleted = true;
+                exit_loop = true;
+                return Err(ErrorCode::InvalidParam);
+            }
             let r = unsafe {
                 transaction::try_run_relaxed(move |j| {
                     let q = unsafe { q.as_mut_no_logging() };
-                    let r = q.dequeue(j, cs);
+                    let r = if remove { q.dequeue(j, cs) } else { q.peek(cs) };
                     match r {
                         Err(_) => Err(ErrorCode::TxRetry),
                         Ok(v) => Ok(v),
@@ -461,9 +609,12 @@ pub fn queue_receive<T>(mut q: PMPtr<Queue>, mut wait_ticks: Time) -> Result<T,
                 }
                 Ok(_) => {
                     let wait_list = unsafe {
-                        q.as_mut_no_logging()
-                            .blocked_writers
-                            .borrow_mut_no_logging()
+                        let q = q.as_mut_no_logging();
+                        if remove {
+                            q.blocked_writers.borrow_mut_no_logging()
+                        } else {
+                            q.blocked_readers.borrow_mut_no_logging()
+                        }
                     };
                     list::atomic_roll_forward_pop_remove_from_waitlist(wait_list, cs);
                     exit_loop = true;
@@ -471,6 +622,9 @@ pub fn queue_receive<T>(mut q: PMPtr<Queue>, mut wait_ticks: Time) -> Result<T,
             }
             r
         });
+        if deleted {
+            return Err(QueueErr::QueueDeleted);
+        }
         if exit_loop {
             break;
         }
@@ -488,6 +642,207 @@ pub fn queue_receive<T>(mut q: PMPtr<Queue>, mut wait_ticks: Time) -> Result<T,
     }
 }
 
+pub fn queue_receive<T>(q: PMPtr<Queue>, gen: usize, wait_ticks: Time) -> Result<T, QueueErr> {
+    queue_receive_impl(q, gen, wait_ticks, true)
+}
+
+pub fn queue_peek<T>(q: PMPtr<Queue>, gen: usize, wait_ticks: Time) -> Result<T, QueueErr> {
+    queue_receive_impl(q, gen, wait_ticks, false)
+}
+
+// Never blocks, a blocked reader is woken
+#[cfg(not(feature = "opt_list"))]
+pub fn queue_overwrite(
+    mut q: PMPtr<Queue>,
+    gen: usize,
+    item_ptr: NonNull<u8>,
+) -> Result<(), QueueErr> {
+    critical::with_no_interrupt(|cs| unsafe {
+        transaction::run_relaxed(|j| {
+            let q = q.as_mut_no_logging();
+            if !q.is_alive(gen) {
+                return Err(QueueErr::QueueDeleted);
+            }
+            q.overwrite(item_ptr, j, cs)?;
+            q.wakeup_blocked_task(true, j, cs);
+            Ok(())
+        })
+    })
+}
+
+#[cfg(feature = "opt_list")]
+pub fn queue_overwrite(
+    mut q: PMPtr<Queue>,
+    gen: usize,
+    item_ptr: NonNull<u8>,
+) -> Result<(), QueueErr> {
+    critical::with_no_interrupt(|cs| {
+        let q = unsafe { q.as_mut_no_logging() };
+        if !q.is_alive(gen) {
+            return Err(QueueErr::QueueDeleted);
+        }
+        let r = unsafe {
+            transaction::try_run_relaxed(|j| {
+                q.overwrite(item_ptr, j, cs)
+                    .map_err(|_| ErrorCode::InvalidParam)
+            })
+        };
+        if r.is_err() {
+            return Err(QueueErr::InvalidParam);
+        }
+        let wait_list = unsafe { q.blocked_readers.borrow_mut_no_logging() };
+        list::atomic_roll_forward_pop_remove_from_waitlist(wait_list, cs);
+        wake_set_selectors(q.set, cs);
+        Ok(())
+    })
+}
+
+// Called in the transaction which made the member ready. The set has room,
+// it was reserved when the member was added.
+pub(crate) fn post_to_set(
+    set: Option<PMPtr<Queue>>,
+    member: QueueSetMember,
+    j: JournalHandle,
+    cs: &CriticalSection,
+) {
+    if let Some(mut set) = set {
+        let set = unsafe { set.as_mut_no_logging() };
+        let _ = set.push_back(NonNull::from(&member).cast(), j, cs);
+        #[cfg(not(feature = "opt_list"))]
+        set.wakeup_blocked_task(true, j, cs);
+    }
+}
+
+// Every selector is woken, the ones which find the set empty block again
+#[cfg(feature = "opt_list")]
+pub(crate) fn wake_set_selectors(set: Option<PMPtr<Queue>>, cs: &CriticalSection) {
+    if let Some(mut set) = set {
+        let wait_list = unsafe { set.as_mut_no_logging().blocked_readers.borrow_mut_no_logging() };
+        list::atomic_roll_forward_pop_remove_all_from_waitlist(wait_list, cs);
+    }
+}
+
+// A queue set is a queue of members. Members join and leave it empty, so the
+// set holds one entry for each item or count in its members.
+pub fn queue_set_create(length: usize) -> Option<PMPtr<Queue>> {
+    Queue::new(length, core::mem::size_of::<QueueSetMember>())
+}
+
+pub fn queue_set_add(
+    mut member: QueueSetMember,
+    mut set_ptr: PMPtr<Queue>,
+    set_gen: usize,
+) -> Result<(), QueueErr> {
+    critical::with_no_interrupt(|_| {
+        transaction::run(move |j| {
+            let set = unsafe { set_ptr.as_mut_no_logging() };
+            if !set.is_alive(set_gen) {
+                return Err(QueueErr::QueueDeleted);
+            }
+            let capacity = member.capacity();
+            let joinable = member.set_link().is_none() && member.is_empty();
+            if !joinable || set.set_reserved + capacity > set.length {
+                return Err(QueueErr::InvalidParam);
+            }
+            let link = member.set_link();
+            j.get_mut().append_log_of(link as *mut Option<PMPtr<Queue>>);
+            *link = Some(set_ptr);
+            j.get_mut().append_log_of(&mut set.set_reserved as *mut usize);
+            set.set_reserved += capacity;
+            Ok(())
+        })
+    })
+}
+
+pub fn queue_set_remove(
+    mut member: QueueSetMember,
+    mut set_ptr: PMPtr<Queue>,
+    set_gen: usize,
+) -> Result<(), QueueErr> {
+    critical::with_no_interrupt(|_| {
+        transaction::run(move |j| {
+            let set = unsafe { set_ptr.as_mut_no_logging() };
+            if !set.is_alive(set_gen) {
+                return Err(QueueErr::QueueDeleted);
+            }
+            if *member.set_link() != Some(set_ptr) || !member.is_empty() {
+                return Err(QueueErr::InvalidParam);
+            }
+            let capacity = member.capacity();
+            let link = member.set_link();
+            j.get_mut().append_log_of(link as *mut Option<PMPtr<Queue>>);
+            *link = None;
+            j.get_mut().append_log_of(&mut set.set_reserved as *mut usize);
+            set.set_reserved -= capacity;
+            Ok(())
+        })
+    })
+}
+
+// (items waiting, length) of a live queue
+fn queue_occupancy(q: PMPtr<Queue>, gen: usize) -> Result<(usize, usize), QueueErr> {
+    critical::with_no_interrupt(|_| {
+        let q = q.as_ref();
+        if !q.is_alive(gen) {
+            return Err(QueueErr::QueueDeleted);
+        }
+        Ok((*q.items_enqueued, q.length))
+    })
+}
+
+pub fn queue_messages_waiting(q: PMPtr<Queue>, gen: usize) -> Result<usize, QueueErr> {
+    queue_occupancy(q, gen).map(|(items, _)| items)
+}
+
+pub fn queue_spaces_available(q: PMPtr<Queue>, gen: usize) -> Result<usize, QueueErr> {
+    queue_occupancy(q, gen).map(|(items, length)| length - items)
+}
+
+// Wakes all blocked readers and writers, they get `QueueDeleted`. The queue
+// is gone only once the transaction which releases it commits.
+#[cfg(not(feature = "opt_list"))]
+pub fn queue_delete(mut q_ptr: PMPtr<Queue>, gen: usize) -> Result<(), QueueErr> {
+    critical::with_no_interrupt(|cs| {
+        transaction::run(move |j| {
+            let q = unsafe { q_ptr.as_mut_no_logging() };
+            if !q.is_alive(gen) {
+                return Err(QueueErr::QueueDeleted);
+            }
+            while q.blocked_readers.len() > 0 {
+                q.wakeup_blocked_task(true, j, cs);
+            }
+            while q.blocked_writers.len() > 0 {
+                q.wakeup_blocked_task(false, j, cs);
+            }
+            q.release(j);
+            Ok(())
+        })
+    })
+}
+
+// The waiters are woken while the queue is still alive, so a crash in between
+// leaves a live queue. They find it gone once they run.
+#[cfg(feature = "opt_list")]
+pub fn queue_delete(mut q_ptr: PMPtr<Queue>, gen: usize) -> Result<(), QueueErr> {
+    critical::with_no_interrupt(|cs| {
+        // cached, so a replay after the release still succeeds
+        if !transaction::run(move |_| q_ptr.as_ref().is_alive(gen)) {
+            return Err(QueueErr::QueueDeleted);
+        }
+        unsafe {
+            let q = q_ptr.as_mut_no_logging();
+            let readers = q.blocked_readers.borrow_mut_no_logging();
+            list::atomic_roll_forward_pop_remove_all_from_waitlist(readers, cs);
+            let writers = q.blocked_writers.borrow_mut_no_logging();
+            list::atomic_roll_forward_pop_remove_all_from_waitlist(writers, cs);
+        }
+        transaction::run(move |j| {
+            unsafe { q_ptr.as_mut_no_logging() }.release(j);
+        });
+        Ok(())
+    })
+}
+
 #[cfg(not(feature = "opt_list"))]
 pub fn queue_block_until_not_empty(mut q: PMPtr<Queue>, wait_ticks: Time, forever: bool) {
     debug_print!("Running block until");
@@ -495,7 +850,8 @@ pub fn queue_block_until_not_empty(mut q: PMPtr<Queue>, wait_ticks: Time, foreve
         transaction::run(move |j| {
             let task = task::current();
             let q = unsafe { q.as_mut_no_logging() };
-            if q.is_empty() {
+            // the timer daemon also wakes for sets deferred by interrupt handlers
+            if q.is_empty() && !event_group::has_deferred_sets() {
                 let wakeup_time;
                 if forever {
                     wakeup_time = Time::MAX;
@@ -529,7 +885,8 @@ pub fn queue_block_until_not_empty(mut q: PMPtr<Queue>, wait_ticks: Time, foreve
     let task = task::current();
     let q = unsafe { q.as_mut_no_logging() };
     let yld = critical::with_no_interrupt(|cs| {
-        if q.is_empty() {
+        // the timer daemon also wakes for sets deferred by interrupt handlers
+        if q.is_empty() && !event_group::has_deferred_sets() {
             let wakeup_time;
             if forever {
                 wakeup_time = Time::MAX;
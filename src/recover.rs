@@ -110,6 +110,21 @@ pub fn recover_list_transaction() {
         ListTxOpCode::ReadyListInsert => {
             list::recover_and_roll_forward_of_readylist_insert(log);
         }
+        ListTxOpCode::TaskRemoval | ListTxOpCode::TaskSuspend => {
+            list::recover_and_roll_forward_of_task_unlink(log);
+        }
+        ListTxOpCode::TaskResume => {
+            list::recover_and_roll_forward_of_task_resume(log);
+        }
+        ListTxOpCode::TaskPriorityChange | ListTxOpCode::TaskPriorityInherit => {
+            list::recover_and_roll_forward_of_priority_change(log);
+        }
+        ListTxOpCode::TaskNotifyWakeup => {
+            list::recover_and_roll_forward_of_notify_wakeup(log);
+        }
+        ListTxOpCode::TaskIsrWakeup => {
+            list::recover_and_roll_forward_of_isr_wakeup(log);
+        }
         _ => {
             panic!("Impossible log type");
         }
@@ -187,6 +202,9 @@ pub fn recover() {
         debug_print!("Recover for Boot Done!");
     } else {
         kernel_recovery_begin_stat();
+        #[cfg(feature = "sched_energy_aware")]
+        crate::sched::record_power_failure();
+        crate::user::patomic::recover();
         #[cfg(feature = "opt_list")]
         {
             recover_list_transaction();
@@ -208,6 +226,8 @@ pub fn recover() {
                 exit_all_critical();
             }
         }
+        #[cfg(feature = "opt_list")]
+        crate::task::recover_inherited_priorities();
         kernel_recovery_end_stat();
         if !current().is_schedulable() {
             crate::os_dbg_print!("Reschedule before start....");
@@ -0,0 +1,338 @@
+use crate::critical::{self, CriticalSection};
+use crate::list::{self, InsertSortedPList, Node, SortedPList};
+use crate::pmem::{JournalHandle, PMPtr};
+use crate::task::{self, current, BlockedListItem, ErrorCode, SchedListItem, Task, TASK_NUM_LIMIT};
+use crate::{
+    arch::arch_yield,
+    declare_pm_var, heap,
+    time::{self, Time},
+    transaction,
+};
+
+// Every lock ever created, so the slots of a deleted task can be found
+declare_pm_var!(RWLOCKS, Option<PMPtr<RwLock>>, None);
+
+// A reader-writer lock which records who holds it. A task can't take it
+// twice, a replay after a crash skips the acquires and releases which went
+// through as their results are cached. At most TASK_NUM_LIMIT tasks read at
+// once, more readers wait.
+pub struct RwLock {
+    readers: [Option<PMPtr<Task>>; TASK_NUM_LIMIT],
+    writer: Option<PMPtr<Task>>,
+    blocked_readers: SortedPList<BlockedListItem>,
+    blocked_writers: SortedPList<BlockedListItem>,
+    next: Option<PMPtr<RwLock>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RwLockErr {
+    // the caller holds the lock already
+    Deadlock,
+    Timeout,
+}
+
+impl RwLock {
+    pub fn new(j: JournalHandle) -> Option<PMPtr<RwLock>> {
+        let head = unsafe { RWLOCKS.borrow_mut(j) };
+        let lock = heap::pm_new(
+            RwLock {
+                readers: [None; TASK_NUM_LIMIT],
+                writer: None,
+                blocked_readers: SortedPList::new(),
+                blocked_writers: SortedPList::new(),
+                next: *head,
+            },
+            j,
+        )?;
+        *head = Some(lock);
+        Some(lock)
+    }
+
+    pub fn reader_count(&self) -> usize {
+        self.readers.iter().filter(|r| r.is_some()).count()
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    fn is_held_by(&self, task: PMPtr<Task>) -> bool {
+        self.writer == Some(task) || self.readers.contains(&Some(task))
+    }
+
+    fn set_reader(&mut self, slot: usize, reader: Option<PMPtr<Task>>, j: JournalHandle) {
+        j.get_mut()
+            .append_log_of(&mut self.readers[slot] as *mut Option<PMPtr<Task>>);
+        self.readers[slot] = reader;
+    }
+
+    fn set_writer(&mut self, writer: Option<PMPtr<Task>>, j: JournalHandle) {
+        j.get_mut()
+            .append_log_of(&mut self.writer as *mut Option<PMPtr<Task>>);
+        self.writer = writer;
+    }
+
+    fn wait_list(&mut self, writer: bool) -> &mut SortedPList<BlockedListItem> {
+        if writer {
+            &mut self.blocked_writers
+        } else {
+            &mut self.blocked_readers
+        }
+    }
+
+    // `TxRetry` if the caller has to wait. New readers wait behind a waiting
+    // writer, so writers don't starve.
+    fn try_acquire(&mut self, writer: bool, j: JournalHandle) -> Result<(), ErrorCode> {
+        let me = current().as_pm_ptr();
+        if self.is_held_by(me) {
+            return Err(ErrorCode::InvalidParam);
+        }
+        if writer {
+            if self.writer.is_some() || self.reader_count() > 0 {
+                return Err(ErrorCode::TxRetry);
+            }
+            self.set_writer(Some(me), j);
+            return Ok(());
+        }
+        if self.writer.is_some() || self.blocked_writers.len() > 0 {
+            return Err(ErrorCode::TxRetry);
+        }
+        match self.readers.iter().position(|r| r.is_none()) {
+            Some(slot) => {
+                self.set_reader(slot, Some(me), j);
+                Ok(())
+            }
+            None => Err(ErrorCode::TxRetry),
+        }
+    }
+
+    // Whatever `holder` holds, read or write
+    fn release(&mut self, holder: PMPtr<Task>, j: JournalHandle) {
+        if self.writer == Some(holder) {
+            self.set_writer(None, j);
+        }
+        if let Some(slot) = self.readers.iter().position(|r| *r == Some(holder)) {
+            self.set_reader(slot, None, j);
+        }
+    }
+
+    // Which side may go on: a single writer once the lock is free, or all
+    // readers if no writer waits. Only depends on the state of the lock, so
+    // a replayed release wakes the same side.
+    fn waiters_to_wake(&self) -> Option<bool> {
+        if self.writer.is_some() {
+            return None;
+        }
+        if self.blocked_writers.len() > 0 {
+            if self.reader_count() == 0 {
+                return Some(true);
+            }
+            return None;
+        }
+        if self.blocked_readers.len() > 0 {
+            return Some(false);
+        }
+        None
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    fn block(
+        &mut self,
+        writer: bool,
+        j: JournalHandle,
+        cs: &CriticalSection,
+    ) -> &mut Node<SchedListItem> {
+        let task = task::current();
+        let link = task.get_event_node_ptr();
+        let node = task.remove_from_ready_list(j, cs);
+        self.wait_list(writer).insert(cs, j, link);
+        node
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    fn wakeup_blocked_task(&mut self, writer: bool, j: JournalHandle, cs: &CriticalSection) {
+        self.wait_list(writer).pop_front(cs, j).map(|node| {
+            let task = node.value.get_mut_task();
+            let n = task.remove_from_delayed_list(j, cs);
+            task.add_node_to_ready_list(n, j, cs);
+        });
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    fn wake_waiters(&mut self, j: JournalHandle, cs: &CriticalSection) {
+        match self.waiters_to_wake() {
+            Some(true) => self.wakeup_blocked_task(true, j, cs),
+            Some(false) => {
+                while self.blocked_readers.len() > 0 {
+                    self.wakeup_blocked_task(false, j, cs);
+                }
+            }
+            None => {}
+        }
+    }
+
+    #[cfg(feature = "opt_list")]
+    fn wake_waiters(&mut self, cs: &CriticalSection) {
+        match self.waiters_to_wake() {
+            Some(true) => {
+                list::atomic_roll_forward_pop_remove_from_waitlist(&mut self.blocked_writers, cs)
+            }
+            Some(false) => list::atomic_roll_forward_pop_remove_all_from_waitlist(
+                &mut self.blocked_readers,
+                cs,
+            ),
+            None => {}
+        }
+    }
+}
+
+pub fn create_rwlock() -> Option<PMPtr<RwLock>> {
+    transaction::run(|j| RwLock::new(j))
+}
+
+fn to_rwlock_err(e: ErrorCode) -> RwLockErr {
+    match e {
+        ErrorCode::InvalidParam => RwLockErr::Deadlock,
+        _ => RwLockErr::Timeout,
+    }
+}
+
+#[cfg(not(feature = "opt_list"))]
+pub fn rwlock_acquire(
+    mut lock_ptr: PMPtr<RwLock>,
+    mut wait_ticks: Time,
+    writer: bool,
+) -> Result<(), RwLockErr> {
+    let mut wakeup_time_set = false;
+    let mut wakeup_time = 0;
+    let task = task::current();
+    loop {
+        let r = critical::with_no_interrupt(|cs| unsafe {
+            transaction::try_run_relaxed(|j| {
+                let lock = lock_ptr.as_mut_no_logging();
+                let r = lock.try_acquire(writer, j);
+                if r == Err(ErrorCode::TxRetry) && wait_ticks != 0 {
+                    if !wakeup_time_set {
+                        wakeup_time = time::TIME_MANAGER
+                            .get_ticks()
+                            .checked_add(wait_ticks)
+                            .unwrap();
+                        wakeup_time_set = true;
+                    }
+                    let sched_node = lock.block(writer, j, cs);
+                    task.set_wakeup_time(wakeup_time);
+                    task.add_node_to_delayed_list(sched_node, j, cs);
+                }
+                r
+            })
+        });
+        match r {
+            Err(ErrorCode::TxRetry) if wait_ticks != 0 => {
+                arch_yield();
+                // update wait_ticks
+                time::update_countdown(&mut wait_ticks, wakeup_time);
+            }
+            r => return r.map_err(to_rwlock_err),
+        }
+    }
+}
+
+#[cfg(feature = "opt_list")]
+pub fn rwlock_acquire(
+    mut lock_ptr: PMPtr<RwLock>,
+    mut wait_ticks: Time,
+    writer: bool,
+) -> Result<(), RwLockErr> {
+    let mut wakeup_time_set = false;
+    let mut wakeup_time = 0;
+    let task = task::current();
+    loop {
+        let r = critical::with_no_interrupt(|cs| {
+            let lock = unsafe { lock_ptr.as_mut_no_logging() };
+            let r = unsafe { transaction::try_run_relaxed(|j| lock.try_acquire(writer, j)) };
+            if r == Err(ErrorCode::TxRetry) && wait_ticks != 0 {
+                if !wakeup_time_set {
+                    wakeup_time = time::TIME_MANAGER
+                        .get_ticks()
+                        .checked_add(wait_ticks)
+                        .unwrap();
+                    wakeup_time_set = true;
+                }
+                list::atomic_roll_forward_insert_into_waitlist(
+                    lock.wait_list(writer),
+                    task,
+                    wakeup_time,
+                    cs,
+                );
+            }
+            r
+        });
+        match r {
+            Err(ErrorCode::TxRetry) if wait_ticks != 0 => {
+                arch_yield();
+                // update wait_ticks
+                time::update_countdown(&mut wait_ticks, wakeup_time);
+            }
+            r => return r.map_err(to_rwlock_err),
+        }
+    }
+}
+
+#[cfg(not(feature = "opt_list"))]
+pub fn rwlock_release(mut lock_ptr: PMPtr<RwLock>) {
+    critical::with_no_interrupt(|cs| unsafe {
+        transaction::run_relaxed(|j| {
+            let lock = lock_ptr.as_mut_no_logging();
+            lock.release(current().as_pm_ptr(), j);
+            lock.wake_waiters(j, cs);
+        })
+    });
+    arch_yield();
+}
+
+#[cfg(feature = "opt_list")]
+pub fn rwlock_release(mut lock_ptr: PMPtr<RwLock>) {
+    critical::with_no_interrupt(|cs| {
+        let lock = unsafe { lock_ptr.as_mut_no_logging() };
+        unsafe { transaction::run_relaxed(|j| lock.release(current().as_pm_ptr(), j)) };
+        lock.wake_waiters(cs);
+    });
+}
+
+// The locks held by a task which goes away are released, so its read slots
+// don't hold back writers forever
+#[cfg(not(feature = "opt_list"))]
+pub fn abandon_rwlocks(task: &Task, j: JournalHandle, cs: &CriticalSection) {
+    let holder = task.as_pm_ptr();
+    let mut next = unsafe { *RWLOCKS };
+    while let Some(mut lock_ptr) = next {
+        let lock = unsafe { lock_ptr.as_mut_no_logging() };
+        if lock.is_held_by(holder) {
+            lock.release(holder, j);
+        }
+        lock.wake_waiters(j, cs);
+        next = lock.next;
+    }
+}
+
+// The waiters of every lock are woken, as a replay only finds the locks which
+// are still held
+#[cfg(feature = "opt_list")]
+pub fn abandon_rwlocks(task: &Task, cs: &CriticalSection) {
+    let holder = task.as_pm_ptr();
+    let mut next = unsafe { *RWLOCKS };
+    while let Some(mut lock_ptr) = next {
+        let lock = unsafe { lock_ptr.as_mut_no_logging() };
+        if lock.is_held_by(holder) {
+            current()
+                .get_mut_tx()
+                .run_no_replay(|j| lock.release(holder, j));
+        }
+        lock.wake_waiters(cs);
+        next = lock.next;
+    }
+}
+
+pub fn reset_static_vars() {
+    unsafe { *RWLOCKS.borrow_mut_no_logging() = None };
+}
@@ -0,0 +1,148 @@
+use crate::critical::CriticalSection;
+use crate::pmem::PMPtr;
+use crate::task::{get_sched_list, next_ready_level, Priority, Task};
+#[cfg(feature = "sched_energy_aware")]
+use crate::{declare_pm_var_unsafe, task::NUM_PRIORITY_LEVELS, time::Time, time::TIME_MANAGER};
+#[cfg(feature = "sched_energy_aware")]
+use crate::util::compiler_pm_fence;
+
+// A scheduling policy only decides which ready task runs next, the ready
+// lists and the context switch protocol are shared by all policies
+pub trait SchedPolicy {
+    // The ready task to run next and the priority level of its ready list
+    fn pick_next(cs: &CriticalSection) -> Option<(usize, PMPtr<Task>)>;
+
+    // Whether `task` should take the CPU from the running task `cur`
+    fn preempts(task: &Task, cur: &Task) -> bool;
+}
+
+// Round-robin within the highest non-empty priority level
+pub struct FixedPriority;
+
+impl FixedPriority {
+    fn pick_next_in(
+        cs: &CriticalSection,
+        eligible: impl Fn(usize) -> bool,
+    ) -> Option<(usize, PMPtr<Task>)> {
+        let mut from = 0;
+        while let Some(i) = next_ready_level(from, cs) {
+            if eligible(i) {
+                let list = unsafe { get_sched_list(Priority::new(i)) };
+                return list.peek_next(cs).map(|item| (i, item.get_task_ptr()));
+            }
+            from = i + 1;
+        }
+        None
+    }
+}
+
+impl SchedPolicy for FixedPriority {
+    fn pick_next(cs: &CriticalSection) -> Option<(usize, PMPtr<Task>)> {
+        Self::pick_next_in(cs, |_| true)
+    }
+
+    fn preempts(task: &Task, cur: &Task) -> bool {
+        task.get_priority().is_higher_than(&cur.get_priority())
+    }
+}
+
+// Tasks with a deadline run first, earliest deadline first. The others run
+// by fixed priority when no deadline is pending
+pub struct EarliestDeadlineFirst;
+
+impl SchedPolicy for EarliestDeadlineFirst {
+    // Only the next task of each ready level competes, so the pick is
+    // bounded by the number of levels. Within a level tasks take turns.
+    fn pick_next(cs: &CriticalSection) -> Option<(usize, PMPtr<Task>)> {
+        let mut earliest: Option<(usize, PMPtr<Task>)> = None;
+        let mut from = 0;
+        while let Some(i) = next_ready_level(from, cs) {
+            let list = unsafe { get_sched_list(Priority::new(i)) };
+            if let Some(item) = list.peek_next(cs) {
+                let task = item.get_task_ptr();
+                let deadline = task.as_ref().get_deadline();
+                let earlier = earliest.map_or(true, |(_, t)| {
+                    deadline < t.as_ref().get_deadline()
+                });
+                if deadline.is_some() && earlier {
+                    earliest = Some((i, task));
+                }
+            }
+            from = i + 1;
+        }
+        earliest.or_else(|| FixedPriority::pick_next(cs))
+    }
+
+    fn preempts(task: &Task, cur: &Task) -> bool {
+        match (task.get_deadline(), cur.get_deadline()) {
+            (Some(d), Some(cur_d)) => d < cur_d,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => FixedPriority::preempts(task, cur),
+        }
+    }
+}
+
+// Fixed priority, but tasks at `DEFERRABLE_PRIORITY` or below are held
+// back while power failures are frequent, so that the energy goes to the
+// important tasks and the idle task can sleep
+#[cfg(feature = "sched_energy_aware")]
+pub struct EnergyAware;
+
+#[cfg(feature = "sched_energy_aware")]
+const DEFERRABLE_PRIORITY: usize = NUM_PRIORITY_LEVELS / 2;
+// failures are frequent if the last `FAILURE_HISTORY` ones all happened
+// within `FAILURE_WINDOW` ticks
+#[cfg(feature = "sched_energy_aware")]
+const FAILURE_HISTORY: usize = 4;
+#[cfg(feature = "sched_energy_aware")]
+const FAILURE_WINDOW: Time = 2000;
+
+#[cfg(feature = "sched_energy_aware")]
+declare_pm_var_unsafe!(
+    RECENT_FAILURES,
+    [Option<Time>; FAILURE_HISTORY],
+    [None; FAILURE_HISTORY]
+);
+#[cfg(feature = "sched_energy_aware")]
+declare_pm_var_unsafe!(OLDEST_FAILURE, usize, 0);
+
+// Called once per reboot from a power failure
+#[cfg(feature = "sched_energy_aware")]
+pub fn record_power_failure() {
+    // the slot goes first, a failure before the index moves only records
+    // the same slot again
+    unsafe {
+        RECENT_FAILURES[OLDEST_FAILURE] = Some(TIME_MANAGER.get_ticks());
+        compiler_pm_fence();
+        OLDEST_FAILURE = (OLDEST_FAILURE + 1) % FAILURE_HISTORY;
+    }
+}
+
+#[cfg(feature = "sched_energy_aware")]
+pub fn failures_frequent() -> bool {
+    let oldest = unsafe { RECENT_FAILURES[OLDEST_FAILURE] };
+    oldest.map_or(false, |t| TIME_MANAGER.get_ticks() - t <= FAILURE_WINDOW)
+}
+
+#[cfg(feature = "sched_energy_aware")]
+impl SchedPolicy for EnergyAware {
+    fn pick_next(cs: &CriticalSection) -> Option<(usize, PMPtr<Task>)> {
+        if !failures_frequent() {
+            return FixedPriority::pick_next(cs);
+        }
+        let idle_level = NUM_PRIORITY_LEVELS - 1;
+        FixedPriority::pick_next_in(cs, |i| i < DEFERRABLE_PRIORITY || i == idle_level)
+    }
+
+    fn preempts(task: &Task, cur: &Task) -> bool {
+        FixedPriority::preempts(task, cur)
+    }
+}
+
+#[cfg(not(any(feature = "sched_edf", feature = "sched_energy_aware")))]
+pub type Scheduler = FixedPriority;
+#[cfg(feature = "sched_edf")]
+pub type Scheduler = EarliestDeadlineFirst;
+#[cfg(all(feature = "sched_energy_aware", not(feature = "sched_edf")))]
+pub type Scheduler = EnergyAware;
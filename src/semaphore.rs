@@ -2,6 +2,7 @@ use crate::critical::CriticalSection;
 use crate::list::InsertSortedPList;
 use crate::list::{self, Node, SortedPList};
 use crate::pmem::{JournalHandle, PMPtr, PMVar};
+use crate::queue::{self, Queue, QueueSetMember};
 use crate::task::{self, ErrorCode};
 use crate::task::{current, BlockedListItem, SchedListItem, Task};
 use crate::{
@@ -15,22 +16,35 @@ use crate::{
 pub struct Semaphore {
     count: usize,
     mutex_holder: Option<PMPtr<Task>>, // used only if this is a mutex
+    // the holder went away while holding the mutex
+    poisoned: bool,
+    // the mutex its holder took before this one
+    next_held: Option<PMPtr<Semaphore>>,
     is_mutex: bool,
     size: usize,
     wait_list: SortedPList<BlockedListItem>,
+    // the queue set this semaphore is a member of
+    set: Option<PMPtr<Queue>>,
 }
 
 #[cfg(feature = "opt_list")]
 pub struct Semaphore {
     count: PMVar<usize>,
     mutex_holder: PMVar<Option<PMPtr<Task>>>, // used only if this is a mutex
+    // the holder went away while holding the mutex
+    poisoned: bool,
+    // the mutex its holder took before this one
+    next_held: Option<PMPtr<Semaphore>>,
     is_mutex: bool,
     size: usize,
     wait_list: SortedPList<BlockedListItem>,
+    // the queue set this semaphore is a member of
+    set: Option<PMPtr<Queue>>,
 }
 
 impl Semaphore {
-    pub fn new(count: usize, j: JournalHandle) -> Option<PMPtr<Semaphore>> {
+    // Only a mutex tracks its holder, poisoning and priority inheritance
+    pub fn new(count: usize, is_mutex: bool, j: JournalHandle) -> Option<PMPtr<Semaphore>> {
         let ptr = heap::pm_new(
             Semaphore {
                 #[cfg(not(feature = "opt_list"))]
@@ -42,8 +56,11 @@ impl Semaphore {
                 mutex_holder: None,
                 #[cfg(feature = "opt_list")]
                 mutex_holder: unsafe { PMVar::new(None) },
+                poisoned: false,
+                next_held: None,
                 wait_list: SortedPList::new(),
-                is_mutex: count == 1,
+                set: None,
+                is_mutex,
             },
             j,
         );
@@ -51,10 +68,77 @@ impl Semaphore {
         ptr
     }
 
+    pub(crate) fn set_link(&mut self) -> &mut Option<PMPtr<Queue>> {
+        &mut self.set
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.size
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    #[cfg(feature = "opt_list")]
+    pub(crate) fn is_empty(&self) -> bool {
+        *self.count == 0
+    }
+
     pub fn mutex_holder(&self) -> Option<&Task> {
         self.mutex_holder.map(|p| p.as_ref())
     }
 
+    pub(crate) fn is_held_by_current(&self) -> bool {
+        #[cfg(feature = "opt_list")]
+        let holder = *self.mutex_holder;
+        #[cfg(not(feature = "opt_list"))]
+        let holder = self.mutex_holder;
+        holder == Some(current().as_pm_ptr())
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    fn set_poisoned(&mut self, poisoned: bool, j: JournalHandle) {
+        j.get_mut().append_log_of(&mut self.poisoned as *mut bool);
+        self.poisoned = poisoned;
+    }
+
+    fn set_next_held(&mut self, next: Option<PMPtr<Semaphore>>, j: JournalHandle) {
+        j.get_mut()
+            .append_log_of(&mut self.next_held as *mut Option<PMPtr<Semaphore>>);
+        self.next_held = next;
+    }
+
+    // Chain the mutex in front of the ones `holder` took before
+    fn link_holder(&mut self, holder: &mut Task, j: JournalHandle) {
+        self.set_next_held(holder.held_mutexes(), j);
+        holder.set_held_mutexes(Some(unsafe { PMPtr::from_mut_ref(self) }), j);
+    }
+
+    // Mutexes are mostly given in the reverse order, so this is the head
+    fn unlink_holder(&mut self, holder: &mut Task, j: JournalHandle) {
+        let this = unsafe { PMPtr::from_mut_ref(self) };
+        let mut prev: Option<PMPtr<Semaphore>> = None;
+        let mut cur = holder.held_mutexes();
+        while let Some(c) = cur {
+            if c == this {
+                match prev {
+                    None => holder.set_held_mutexes(self.next_held, j),
+                    Some(mut p) => {
+                        unsafe { p.as_mut_no_logging() }.set_next_held(self.next_held, j)
+                    }
+                }
+                return;
+            }
+            prev = cur;
+            cur = c.as_ref().next_held;
+        }
+    }
+
     // debug function
     #[cfg(feature = "opt_list")]
     pub fn print(&self) {
@@ -96,10 +180,14 @@ impl Semaphore {
     }
 
     #[cfg(not(feature = "opt_list"))]
-    pub fn try_take(&mut self, _: &CriticalSection) -> Result<(), ()> {
+    pub fn try_take(&mut self, j: JournalHandle, _: &CriticalSection) -> Result<(), ()> {
         if self.count > 0 {
             self.count -= 1;
-            self.mutex_holder = Some(current().as_pm_ptr());
+            if self.is_mutex {
+                self.mutex_holder = Some(current().as_pm_ptr());
+                current().inc_mutexes_held(j);
+                self.link_holder(current(), j);
+            }
             Ok(())
         } else {
             Err(())
@@ -111,8 +199,12 @@ impl Semaphore {
         if *self.count > 0 {
             let cnt = self.count.borrow_mut(j);
             *cnt -= 1;
-            let mutex_holder = self.mutex_holder.borrow_mut(j);
-            *mutex_holder = Some(current().as_pm_ptr());
+            if self.is_mutex {
+                let mutex_holder = self.mutex_holder.borrow_mut(j);
+                *mutex_holder = Some(current().as_pm_ptr());
+                current().inc_mutexes_held(j);
+                self.link_holder(current(), j);
+            }
             Ok(())
         } else {
             // let holder_name = if self.mutex_holder.is_none() {
@@ -132,6 +224,9 @@ impl Semaphore {
         }
         assert!(self.count < self.size);
         self.count += 1;
+        self.release_holder(j);
+        let member = QueueSetMember::Semaphore(unsafe { PMPtr::from_mut_ref(self) });
+        queue::post_to_set(self.set, member, j, cs);
         self.wakeup_blocked_task(j, cs)
     }
 
@@ -143,12 +238,111 @@ impl Semaphore {
         }
         assert!(*self.count < self.size);
         *self.count.borrow_mut(j) += 1;
+        self.release_holder(j);
+        let member = QueueSetMember::Semaphore(unsafe { PMPtr::from_mut_ref(self) });
+        queue::post_to_set(self.set, member, j, cs);
         return true;
     }
+
+    #[cfg(not(feature = "opt_list"))]
+    fn release_holder(&mut self, j: JournalHandle) {
+        if !self.is_mutex {
+            return;
+        }
+        if let Some(mut holder) = self.mutex_holder {
+            let holder = holder.as_mut(j);
+            holder.dec_mutexes_held(j);
+            self.unlink_holder(holder, j);
+        }
+        self.mutex_holder = None;
+    }
+
+    #[cfg(feature = "opt_list")]
+    fn release_holder(&mut self, j: JournalHandle) {
+        if !self.is_mutex {
+            return;
+        }
+        if let Some(mut holder) = *self.mutex_holder {
+            let holder = unsafe { holder.as_mut_no_logging() };
+            holder.dec_mutexes_held(j);
+            self.unlink_holder(holder, j);
+        }
+        *self.mutex_holder.borrow_mut(j) = None;
+    }
+
+    // The holder of a mutex runs at least at the priority of the waiter
+    fn holder_to_boost(&self, waiter: &Task) -> Option<PMPtr<Task>> {
+        if !self.is_mutex {
+            return None;
+        }
+        #[cfg(feature = "opt_list")]
+        let holder = *self.mutex_holder;
+        #[cfg(not(feature = "opt_list"))]
+        let holder = self.mutex_holder;
+        holder.filter(|h| h.as_ref().less_important_than(waiter))
+    }
 }
 
 pub fn create_semaphore(n: usize) -> Option<PMPtr<Semaphore>> {
-    transaction::run(|j| Semaphore::new(n, j))
+    transaction::run(|j| Semaphore::new(n, false, j))
+}
+
+pub fn create_mutex() -> Option<PMPtr<Semaphore>> {
+    transaction::run(|j| Semaphore::new(1, true, j))
+}
+
+// The mutexes of a task which goes away are poisoned and handed on
+#[cfg(not(feature = "opt_list"))]
+pub fn abandon_mutexes(task: &mut Task, j: JournalHandle, cs: &CriticalSection) {
+    while let Some(mut sem) = task.held_mutexes() {
+        let sem = sem.as_mut(j);
+        sem.set_poisoned(true, j);
+        sem.give(j, cs);
+    }
+}
+
+// The waiters are woken before the mutex is given, they retry once the
+// deletion is over. A replay only finds the mutexes which are left.
+#[cfg(feature = "opt_list")]
+pub fn abandon_mutexes(task: &mut Task, cs: &CriticalSection) {
+    while let Some(mut sem_ptr) = task.held_mutexes() {
+        let sem = unsafe { sem_ptr.as_mut_no_logging() };
+        list::atomic_roll_forward_pop_remove_all_from_waitlist(&mut sem.wait_list, cs);
+        current().get_mut_tx().run_no_replay(|j| {
+            sem.set_poisoned(true, j);
+            sem.give(j, cs);
+        });
+        queue::wake_set_selectors(sem.set, cs);
+    }
+}
+
+// A task which gives up on a transaction keeps its mutexes, but the data
+// behind them may be half updated
+pub fn poison_held_mutexes(task: &Task) {
+    critical::with_no_interrupt(|_| {
+        current().get_mut_tx().run_no_replay(|j| {
+            let mut next = task.held_mutexes();
+            while let Some(mut sem_ptr) = next {
+                let sem = unsafe { sem_ptr.as_mut_no_logging() };
+                sem.set_poisoned(true, j);
+                next = sem.next_held;
+            }
+        })
+    });
+}
+
+// Only the holder may tell that the data behind the mutex is consistent again
+pub fn semaphore_clear_poison(mut sem: PMPtr<Semaphore>) -> Result<(), ()> {
+    critical::with_no_interrupt(|_| {
+        current().get_mut_tx().run_no_replay(|j| {
+            let sem = unsafe { sem.as_mut_no_logging() };
+            if !sem.is_held_by_current() {
+                return Err(());
+            }
+            sem.set_poisoned(false, j);
+            Ok(())
+        })
+    })
 }
 
 #[cfg(feature = "opt_list")]
@@ -182,6 +376,14 @@ pub fn semaphore_take(mut sem: PMPtr<Semaphore>, mut wait_ticks: Time) -> Result
                                 .unwrap();
                             wakeup_time_set = true;
                         }
+                        if let Some(mut holder) = sem.holder_to_boost(task) {
+                            let holder = unsafe { holder.as_mut_no_logging() };
+                            list::atomic_roll_forward_inherit_priority(
+                                holder,
+                                task.get_priority(),
+                                cs,
+                            );
+                        }
                         // go to sleep
                         list::atomic_roll_forward_insert_into_waitlist(
                             &mut sem.wait_list,
@@ -213,36 +415,38 @@ pub fn semaphore_take(mut sem: PMPtr<Semaphore>, mut wait_ticks: Time) -> Result
     }
 }
 
+// true if a more important task was woken
 #[cfg(feature = "opt_list")]
-pub fn semaphore_give(mut sem: PMPtr<Semaphore>) {
+fn give_no_yield(mut sem: PMPtr<Semaphore>, cs: &CriticalSection) -> bool {
     let sem = unsafe { sem.as_mut_no_logging() };
     let mut yield_now = false;
-    critical::with_no_interrupt(|cs| {
-        let unblock_task = current().get_mut_tx().run_no_replay(|j| {
-            let t = sem.give(j, cs);
-            t
-        });
+    let unblock_task = current().get_mut_tx().run_no_replay(|j| {
+        let t = sem.give(j, cs);
+        t
+    });
+    // a crash before this point is handled by recover_inherited_priorities
+    let current = task::current();
+    if current.get_mutexes_held() == 0 && current.is_priority_inherited() {
+        list::atomic_roll_forward_inherit_priority(current, current.get_base_priority(), cs);
+    }
 
-        if unblock_task {
-            let item = sem.wait_list.peek_front(cs);
+    if unblock_task {
+        let item = sem.wait_list.peek_front(cs);
 
-            let current = task::current();
-            match item {
-                Some(n) => {
-                    let t = n.get_task();
-                    if current.less_important_than(t) && t.is_ready() {
-                        yield_now = true;
-                    }
+        let current = task::current();
+        match item {
+            Some(n) => {
+                let t = n.get_task();
+                if current.less_important_than(t) && t.is_ready() {
+                    yield_now = true;
                 }
-                None => {}
             }
-            list::atomic_roll_forward_pop_remove_from_waitlist(&mut sem.wait_list, cs);
+            None => {}
         }
-    });
-
-    if yield_now {
-        arch_yield();
+        list::atomic_roll_forward_pop_remove_from_waitlist(&mut sem.wait_list, cs);
+        queue::wake_set_selectors(sem.set, cs);
     }
+    yield_now
 }
 
 #[cfg(not(feature = "opt_list"))]
@@ -261,11 +465,15 @@ pub fn semaphore_take(mut sem: PMPtr<Semaphore>, mut wait_ticks: Time) -> Result
             current().get_mut_tx().run_no_replay(|j| {
                 debug_print!("derefercing semaphore");
                 let sem = sem.as_mut(j);
-                let r = sem.try_take(cs);
+                let r = sem.try_take(j, cs);
                 match r {
                     Err(_) => {
                         debug_print!("Can't lock target lock...");
                         if wait_ticks != 0 {
+                            if let Some(mut holder) = sem.holder_to_boost(task) {
+                                let prio = task.get_priority();
+                                task::change_task_priority(holder.as_mut(j), prio, j, cs);
+                            }
                             let sched_node = sem.block_taker(j, cs);
                             if !wakeup_time_set {
                                 wakeup_time = time::TIME_MANAGER
@@ -305,25 +513,41 @@ pub fn semaphore_take(mut sem: PMPtr<Semaphore>, mut wait_ticks: Time) -> Result
     }
 }
 
+// true if a more important task was woken
 #[cfg(not(feature = "opt_list"))]
-pub fn semaphore_give(mut sem: PMPtr<Semaphore>) {
+fn give_no_yield(mut sem: PMPtr<Semaphore>, cs: &CriticalSection) -> bool {
     let mut yield_now = false;
-    critical::with_no_interrupt(|cs| {
-        current().get_mut_tx().run_no_replay(|j| {
-            let sem = sem.as_mut(j);
-            let t = sem.give(j, cs);
-            match t {
-                None => {}
-                Some(task) => {
-                    let current = task::current();
-                    if current.less_important_than(task) {
-                        yield_now = true;
-                    }
+    current().get_mut_tx().run_no_replay(|j| {
+        let sem = sem.as_mut(j);
+        let t = sem.give(j, cs);
+        let current = task::current();
+        if current.get_mutexes_held() == 0 && current.is_priority_inherited() {
+            let prio = current.get_base_priority();
+            task::change_task_priority(current, prio, j, cs);
+        }
+        match t {
+            None => {}
+            Some(task) => {
+                let current = task::current();
+                if current.less_important_than(task) {
+                    yield_now = true;
                 }
-            };
-        });
+            }
+        };
     });
-    if yield_now {
+    yield_now
+}
+
+pub fn semaphore_give(sem: PMPtr<Semaphore>) {
+    if critical::with_no_interrupt(|cs| give_no_yield(sem, cs)) {
         arch_yield();
     }
 }
+
+// A replay after a crash must not give the mutex away from its next holder
+pub(crate) fn mutex_give_if_held(sem: PMPtr<Semaphore>, cs: &CriticalSection) -> bool {
+    if !sem.as_ref().is_held_by_current() {
+        return false;
+    }
+    give_no_yield(sem, cs)
+}
@@ -0,0 +1,351 @@
+use crate::critical::{self, CriticalSection};
+use crate::list::{self, InsertSortedPList};
+use crate::list::{Node, SortedPList};
+use crate::pmem::{JournalHandle, PMPtr, PMVar};
+use crate::task::{BlockedListItem, ErrorCode, SchedListItem};
+use crate::util::min;
+use crate::vec::PArray;
+use crate::{
+    arch, heap, task,
+    time::{self, Time},
+    transaction,
+};
+use core::mem::size_of;
+use core::ops::Deref;
+use core::ptr;
+
+// every message of a message buffer is prefixed with its length
+const LEN_PREFIX_SZ: usize = size_of::<usize>();
+
+// A byte ring in PM. Bytes are copied to the free part of the ring first and
+// only become visible when `used` is updated in the transaction of the send,
+// so neither a message nor a stream chunk is ever half-visible after a crash.
+pub struct StreamBuffer {
+    buffer: PArray<u8>,
+    capacity: usize,
+    head: PMVar<usize>,
+    used: PMVar<usize>,
+    // a blocked receiver is woken once this many bytes are in
+    trigger_level: usize,
+    is_message: bool,
+    blocked_readers: PMVar<SortedPList<BlockedListItem>>,
+    blocked_writers: PMVar<SortedPList<BlockedListItem>>,
+}
+
+// The bytes of one receive, a replayed receive gets the same ones from the
+// syscall replay cache
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StreamChunk<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Deref for StreamChunk<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StreamErr {
+    InvalidParam,
+    // nothing could be sent or received in time
+    Timeout,
+}
+
+unsafe impl Sync for StreamBuffer {}
+
+impl StreamBuffer {
+    pub fn new(capacity: usize, trigger_level: usize, is_message: bool) -> Option<PMPtr<Self>> {
+        if capacity == 0 || trigger_level == 0 || trigger_level > capacity {
+            return None;
+        }
+        transaction::run(|j| {
+            let mut ptr = unsafe { heap::palloc::<StreamBuffer>(j) }?;
+            let buffer = PArray::<u8>::new(capacity, j)?;
+            let sb = unsafe { ptr.as_mut_no_logging() };
+            unsafe {
+                sb.buffer = buffer;
+                sb.capacity = capacity;
+                sb.head = PMVar::new(0);
+                sb.used = PMVar::new(0);
+                sb.trigger_level = trigger_level;
+                sb.is_message = is_message;
+                sb.blocked_readers = PMVar::new(SortedPList::new());
+                sb.blocked_writers = PMVar::new(SortedPList::new());
+            }
+            Some(ptr)
+        })
+    }
+
+    fn free_space(&self) -> usize {
+        self.capacity - *self.used
+    }
+
+    // Copy to the free part of the ring, `off` bytes past its start
+    fn write_at(&self, off: usize, src: &[u8]) {
+        let start = (*self.head + *self.used + off) % self.capacity;
+        let first = min(src.len(), self.capacity - start);
+        unsafe {
+            let buf = self.buffer.as_ptr();
+            ptr::copy_nonoverlapping(src.as_ptr(), buf.add(start), first);
+            ptr::copy_nonoverlapping(src.as_ptr().add(first), buf, src.len() - first);
+        }
+    }
+
+    fn read_at(&self, off: usize, dst: &mut [u8]) {
+        let start = (*self.head + off) % self.capacity;
+        let first = min(dst.len(), self.capacity - start);
+        unsafe {
+            let buf = self.buffer.as_ptr();
+            ptr::copy_nonoverlapping(buf.add(start), dst.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(buf, dst.as_mut_ptr().add(first), dst.len() - first);
+        }
+    }
+
+    fn consume(&mut self, n: usize, j: JournalHandle) {
+        let head = self.head.borrow_mut(j);
+        *head = (*head + n) % self.capacity;
+        *self.used.borrow_mut(j) -= n;
+    }
+
+    // `TxRetry` if the sender has to wait for room
+    pub fn try_send(&mut self, data: &[u8], j: JournalHandle) -> Result<usize, ErrorCode> {
+        let n = if self.is_message {
+            if LEN_PREFIX_SZ + data.len() > self.capacity {
+                return Err(ErrorCode::InvalidParam);
+            }
+            if LEN_PREFIX_SZ + data.len() > self.free_space() {
+                return Err(ErrorCode::TxRetry);
+            }
+            self.write_at(0, &data.len().to_ne_bytes());
+            self.write_at(LEN_PREFIX_SZ, data);
+            data.len()
+        } else {
+            let n = min(data.len(), self.free_space());
+            if n == 0 {
+                return Err(ErrorCode::TxRetry);
+            }
+            self.write_at(0, &data[..n]);
+            n
+        };
+        let prefix = if self.is_message { LEN_PREFIX_SZ } else { 0 };
+        *self.used.borrow_mut(j) += prefix + n;
+        Ok(n)
+    }
+
+    // `TxRetry` if the receiver has to wait for data. A message which doesn't
+    // fit into `dst` stays in the buffer.
+    pub fn try_receive(&mut self, dst: &mut [u8], j: JournalHandle) -> Result<usize, ErrorCode> {
+        if dst.is_empty() {
+            return Err(ErrorCode::InvalidParam);
+        }
+        if *self.used == 0 {
+            return Err(ErrorCode::TxRetry);
+        }
+        if !self.is_message {
+            let n = min(dst.len(), *self.used);
+            self.read_at(0, &mut dst[..n]);
+            self.consume(n, j);
+            return Ok(n);
+        }
+        let mut len = [0; LEN_PREFIX_SZ];
+        self.read_at(0, &mut len);
+        let len = usize::from_ne_bytes(len);
+        if len > dst.len() {
+            return Err(ErrorCode::InvalidParam);
+        }
+        self.read_at(LEN_PREFIX_SZ, &mut dst[..len]);
+        self.consume(LEN_PREFIX_SZ + len, j);
+        Ok(len)
+    }
+
+    pub fn bytes_available(&self) -> usize {
+        *self.used
+    }
+
+    // Whether the tasks blocked on the other side may go on
+    fn should_wake(&self, readers: bool) -> bool {
+        !readers || *self.used >= self.trigger_level
+    }
+
+    fn wait_list(&mut self, readers: bool) -> &mut PMVar<SortedPList<BlockedListItem>> {
+        if readers {
+            &mut self.blocked_readers
+        } else {
+            &mut self.blocked_writers
+        }
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    fn block(
+        &mut self,
+        reader: bool,
+        j: JournalHandle,
+        cs: &CriticalSection,
+    ) -> &mut Node<SchedListItem> {
+        let task = task::current();
+        let link = task.get_event_node_ptr();
+        let node = task.remove_from_ready_list(j, cs);
+        self.wait_list(reader).borrow_mut(j).insert(cs, j, link);
+        node
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    fn wakeup_blocked_task(&mut self, readers: bool, j: JournalHandle, cs: &CriticalSection) {
+        let wait_list = self.wait_list(readers).borrow_mut(j);
+        wait_list.pop_front(cs, j).map(|node| {
+            let task = node.value.get_task();
+            let n = task.remove_from_delayed_list(j, cs);
+            task.add_node_to_ready_list(n, j, cs);
+        });
+    }
+}
+
+fn to_stream_err(e: ErrorCode) -> StreamErr {
+    match e {
+        ErrorCode::TxRetry => StreamErr::Timeout,
+        _ => StreamErr::InvalidParam,
+    }
+}
+
+// Retry `op` until it goes through, blocking on the wait list of its side
+// in between. Success wakes a task blocked on the other side.
+#[cfg(not(feature = "opt_list"))]
+fn run_blocking<F, T>(
+    mut sb_ptr: PMPtr<StreamBuffer>,
+    mut wait_ticks: Time,
+    reader: bool,
+    mut op: F,
+) -> Result<T, StreamErr>
+where
+    F: FnMut(&mut StreamBuffer, JournalHandle) -> Result<T, ErrorCode>,
+{
+    let mut wakeup_time_set = false;
+    let mut wakeup_time = 0;
+    let task = task::current();
+    loop {
+        let r = critical::with_no_interrupt(|cs| unsafe {
+            transaction::run_relaxed(|j| {
+                let sb = sb_ptr.as_mut_no_logging();
+                let r = op(sb, j);
+                match r {
+                    Err(ErrorCode::TxRetry) if wait_ticks != 0 => {
+                        if !wakeup_time_set {
+                            wakeup_time = time::TIME_MANAGER
+                                .get_ticks()
+                                .checked_add(wait_ticks)
+                                .unwrap();
+                            wakeup_time_set = true;
+                        }
+                        let sched_node = sb.block(reader, j, cs);
+                        task.set_wakeup_time(wakeup_time);
+                        task.add_node_to_delayed_list(sched_node, j, cs);
+                    }
+                    Ok(_) if sb.should_wake(!reader) => {
+                        sb.wakeup_blocked_task(!reader, j, cs);
+                    }
+                    _ => {}
+                }
+                r
+            })
+        });
+        match r {
+            Err(ErrorCode::TxRetry) if wait_ticks != 0 => {
+                arch::arch_yield();
+                // update wait_ticks
+                time::update_countdown(&mut wait_ticks, wakeup_time);
+            }
+            r => return r.map_err(to_stream_err),
+        }
+    }
+}
+
+#[cfg(feature = "opt_list")]
+fn run_blocking<F, T>(
+    mut sb_ptr: PMPtr<StreamBuffer>,
+    mut wait_ticks: Time,
+    reader: bool,
+    mut op: F,
+) -> Result<T, StreamErr>
+where
+    F: FnMut(&mut StreamBuffer, JournalHandle) -> Result<T, ErrorCode>,
+{
+    let mut wakeup_time_set = false;
+    let mut wakeup_time = 0;
+    let task = task::current();
+    loop {
+        let r = critical::with_no_interrupt(|cs| {
+            let sb = unsafe { sb_ptr.as_mut_no_logging() };
+            let r = unsafe { transaction::try_run_relaxed(|j| op(sb, j)) };
+            match r {
+                Err(ErrorCode::TxRetry) if wait_ticks != 0 => {
+                    if !wakeup_time_set {
+                        wakeup_time = time::TIME_MANAGER
+                            .get_ticks()
+                            .checked_add(wait_ticks)
+                            .unwrap();
+                        wakeup_time_set = true;
+                    }
+                    let wait_list = unsafe { sb.wait_list(reader).borrow_mut_no_logging() };
+                    list::atomic_roll_forward_insert_into_waitlist(
+                        wait_list,
+                        task,
+                        wakeup_time,
+                        cs,
+                    );
+                }
+                Ok(_) if sb.should_wake(!reader) => {
+                    let wait_list = unsafe { sb.wait_list(!reader).borrow_mut_no_logging() };
+                    list::atomic_roll_forward_pop_remove_from_waitlist(wait_list, cs);
+                }
+                _ => {}
+            }
+            r
+        });
+        match r {
+            Err(ErrorCode::TxRetry) if wait_ticks != 0 => {
+                arch::arch_yield();
+                // update wait_ticks
+                time::update_countdown(&mut wait_ticks, wakeup_time);
+            }
+            r => return r.map_err(to_stream_err),
+        }
+    }
+}
+
+pub fn stream_buffer_create(capacity: usize, trigger_level: usize) -> Option<PMPtr<StreamBuffer>> {
+    StreamBuffer::new(capacity, trigger_level, false)
+}
+
+// `capacity` includes the length prefix of each message
+pub fn message_buffer_create(capacity: usize) -> Option<PMPtr<StreamBuffer>> {
+    StreamBuffer::new(capacity, 1, true)
+}
+
+// Returns the number of bytes sent, a stream takes what fits
+pub fn stream_buffer_send(
+    sb: PMPtr<StreamBuffer>,
+    data: &[u8],
+    wait_ticks: Time,
+) -> Result<usize, StreamErr> {
+    run_blocking(sb, wait_ticks, false, |sb, j| sb.try_send(data, j))
+}
+
+pub fn stream_buffer_receive<const N: usize>(
+    sb: PMPtr<StreamBuffer>,
+    wait_ticks: Time,
+) -> Result<StreamChunk<N>, StreamErr> {
+    // the bytes are part of the result of the transaction, so a replayed
+    // one returns them too
+    run_blocking(sb, wait_ticks, true, |sb, j| {
+        let mut chunk = StreamChunk {
+            bytes: [0; N],
+            len: 0,
+        };
+        chunk.len = sb.try_receive(&mut chunk.bytes, j)?;
+        Ok(chunk)
+    })
+}
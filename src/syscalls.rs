@@ -1,10 +1,13 @@
 use crate::arch::ARCH_ALIGN;
+use crate::condvar::{self, CondVar};
 use crate::event_group::{self, EventBits, EventGroup, EventGroupHandle};
 use crate::heap::MemStat;
-use crate::marker::PSafe;
+use crate::marker::{InvariantLifetime, PSafe};
 use crate::pmem::{JournalHandle, PMPtr};
-use crate::queue::{self, Queue, QueueErr};
+use crate::queue::{self, Queue, QueueErr, QueueSetMember};
+use crate::rwlock::{self, RwLock, RwLockErr};
 use crate::semaphore::{self, Semaphore};
+use crate::stream_buffer::{self, StreamBuffer, StreamChunk, StreamErr};
 use crate::task::{self, current, task_enter_kernel, task_exit_kernel, ErrorCode, TaskHandle};
 use crate::time::{self, Time, Timer, TimerCallBackFnType, TimerErr};
 use crate::user::pbox::{PBox, PRef, PRefRW, Ptr};
@@ -23,14 +26,17 @@ const SYSCALL_REPLAY_CACHE_SZ: usize = core::mem::size_of::<usize>() * N_SYSCALL
 #[cfg(sram_baseline)]
 const SYSCALL_REPLAY_CACHE_SZ: usize = 0;
 
+// Branded like `JournalHandle`, can't be used after its `run_sys` returned
 #[derive(Clone, Copy)]
-pub struct SyscallToken {
-    opaque: (),
+pub struct SyscallToken<'id> {
+    opaque: InvariantLifetime<'id>,
 }
 
-impl SyscallToken {
+impl<'id> SyscallToken<'id> {
     pub unsafe fn new() -> Self {
-        SyscallToken { opaque: () }
+        SyscallToken {
+            opaque: PhantomData,
+        }
     }
 }
 
@@ -357,6 +363,25 @@ macro_rules! syscall_end {
         post_syscall_hook();
         return;
     };
+    // a crash point skips the epilogue, like a power failure would
+    ($t: ident, $r: expr, $eplilogue: block) => {
+        let r = (|| {
+            syscall_end!($t, $r);
+        })();
+        if syscall_end_crash_point() > 3 {
+            $eplilogue;
+        }
+        return r;
+    };
+    ($eplilogue: block) => {
+        (|| {
+            syscall_end!();
+        })();
+        if syscall_end_crash_point() > 3 {
+            $eplilogue;
+        }
+        return;
+    };
 }
 
 // Task syscalls
@@ -366,10 +391,11 @@ pub unsafe fn sys_create_task_unsafe(
     func: usize,
     param: usize,
     pm_heap_sz: usize,
+    stack_size: usize,
     _: SyscallToken,
 ) -> Result<TaskHandle, ErrorCode> {
     syscall_begin!(task_create);
-    let ret = task::create_task_static(name, prio, func, param, pm_heap_sz);
+    let ret = task::create_task_static(name, prio, func, param, pm_heap_sz, stack_size);
     syscall_end!(task_create, ret);
 }
 
@@ -379,6 +405,7 @@ pub fn sys_create_task_custom<T>(
     func: fn(T),
     param: T,
     pm_heap_sz: usize,
+    stack_size: usize,
     _: SyscallToken,
 ) -> Result<TaskHandle, ErrorCode>
 where
@@ -390,7 +417,8 @@ where
     forget(param);
     // assert_sz!(T, core::mem::size_of::<usize>());
     syscall_begin!(task_create);
-    let ret = task::create_task_static(name, prio, func as usize, param_usize, pm_heap_sz);
+    let func = func as usize;
+    let ret = task::create_task_static(name, prio, func, param_usize, pm_heap_sz, stack_size);
     syscall_end!(task_create, ret);
 }
 
@@ -404,7 +432,128 @@ pub fn sys_create_task<T>(
 where
     T: Send + 'static,
 {
-    sys_create_task_custom(name, prio, func, param, heap::PM_HEAP_SIZE_PER_TASK, t)
+    let pm_heap_sz = heap::PM_HEAP_SIZE_PER_TASK;
+    sys_create_task_custom(name, prio, func, param, pm_heap_sz, task::DEFAULT_STACK_SIZE, t)
+}
+
+// Delete another task, a task ends itself with sys_task_exit
+pub fn sys_task_delete(handle: TaskHandle, _: SyscallToken) -> Result<(), ErrorCode> {
+    syscall_begin!(task_delete);
+    let ret = task::delete_task(handle);
+    syscall_end!(task_delete, ret);
+}
+
+// A task may suspend itself, it yields right away then
+pub fn sys_task_suspend(handle: TaskHandle, _: SyscallToken) -> Result<(), ErrorCode> {
+    syscall_begin!(task_suspend);
+    let ret = task::suspend_task(handle);
+    syscall_end!(task_suspend, ret, {
+        if !task::current().is_schedulable() {
+            crate::task::task_yield();
+        }
+    });
+}
+
+pub fn sys_task_resume(handle: TaskHandle, _: SyscallToken) -> Result<(), ErrorCode> {
+    syscall_begin!(task_resume);
+    let ret = task::resume_task(handle);
+    syscall_end!(task_resume, ret);
+}
+
+pub fn sys_task_set_priority(
+    handle: TaskHandle,
+    prio: usize,
+    _: SyscallToken,
+) -> Result<(), ErrorCode> {
+    syscall_begin!(task_set_priority);
+    let ret = task::set_task_priority(handle, prio);
+    syscall_end!(task_set_priority, ret, {
+        if task::need_reschedule() {
+            crate::task::task_yield();
+        }
+    });
+}
+
+pub fn sys_task_get_priority(handle: TaskHandle, _: SyscallToken) -> Result<usize, ErrorCode> {
+    syscall_begin!(task_get_priority);
+    let ret = task::get_task_priority(handle);
+    syscall_end!(task_get_priority, ret);
+}
+
+// Only the EDF policy looks at deadlines, `None` clears the deadline
+pub fn sys_task_set_deadline(
+    handle: TaskHandle,
+    ticks: Option<Time>,
+    _: SyscallToken,
+) -> Result<(), ErrorCode> {
+    syscall_begin!(task_set_deadline);
+    let ret = task::set_task_deadline(handle, ticks);
+    syscall_end!(task_set_deadline, ret, {
+        if task::need_reschedule() {
+            crate::task::task_yield();
+        }
+    });
+}
+
+pub fn sys_task_notify(
+    handle: TaskHandle,
+    value: usize,
+    action: task::NotifyAction,
+    _: SyscallToken,
+) -> Result<(), ErrorCode> {
+    syscall_begin!(task_notify);
+    let ret = task::notify_task(handle, value, action);
+    syscall_end!(task_notify, ret, {
+        if task::need_reschedule() {
+            crate::task::task_yield();
+        }
+    });
+}
+
+pub fn sys_task_notify_wait(
+    clear_on_entry: usize,
+    clear_on_exit: usize,
+    ticks: Time,
+    _: SyscallToken,
+) -> Result<usize, usize> {
+    syscall_begin!(task_notify_wait);
+    let ret = task::notify_wait(clear_on_entry, clear_on_exit, ticks);
+    syscall_end!(task_notify_wait, ret);
+}
+
+// The number of stack words the task never used so far
+pub fn sys_task_stack_high_water_mark(
+    handle: TaskHandle,
+    _: SyscallToken,
+) -> Result<usize, ErrorCode> {
+    syscall_begin!(task_stack_hwm);
+    let ret = task::get_stack_high_water_mark(handle);
+    syscall_end!(task_stack_hwm, ret);
+}
+
+pub fn sys_task_exit(t: SyscallToken) -> ! {
+    remove_current_task(t);
+    // a killed task is never scheduled again
+    loop {
+        task::task_yield();
+    }
+}
+
+fn remove_current_task(_: SyscallToken) {
+    syscall_begin!(noret, task_exit);
+    task::task_exit();
+    syscall_end!();
+}
+
+#[cfg(feature = "jit_checkpoint")]
+pub fn sys_task_set_exec_mode(
+    handle: TaskHandle,
+    mode: crate::checkpoint::ExecMode,
+    _: SyscallToken,
+) -> Result<(), ErrorCode> {
+    syscall_begin!(task_set_exec_mode);
+    let ret = task::set_task_exec_mode(handle, mode);
+    syscall_end!(task_set_exec_mode, ret);
 }
 
 pub fn sys_task_delay_in_tx(nticks: Time, _: SyscallToken) {
@@ -421,6 +570,18 @@ pub fn sys_task_delay(nticks: Time) {
     syscall_end_outside_tx!();
 }
 
+// Sleep until `period` ticks after `last_wake`, which must be kept in PM
+pub fn sys_task_delay_until(
+    last_wake: PMPtr<Time>,
+    period: Time,
+    policy: task::MissedPeriodPolicy,
+    _: SyscallToken,
+) -> Result<(), Time> {
+    syscall_begin!(task_delay_until);
+    let r = task::task_delay_until(last_wake, period, policy);
+    syscall_end!(task_delay_until, r);
+}
+
 pub fn sys_yield() {
     task::task_yield();
 }
@@ -429,6 +590,8 @@ pub fn sys_yield() {
 
 pub struct QueueHandle<T> {
     ptr: PMPtr<Queue>,
+    // stale once the queue is deleted
+    gen: usize,
     phantom: PhantomData<T>,
 }
 
@@ -447,18 +610,25 @@ impl<T> QueueHandle<T> {
     pub unsafe fn new(ptr: PMPtr<Queue>) -> Self {
         Self {
             ptr,
+            gen: ptr.as_ref().get_generation(),
             phantom: PhantomData,
         }
     }
+
+    // Whether `member`, as returned by `sys_queue_select`, is this queue
+    pub fn is_member(&self, member: QueueSetMember) -> bool {
+        member == QueueSetMember::Queue(self.ptr)
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        !self.ptr.as_ref().is_alive(self.gen)
+    }
 }
 
 pub fn sys_queue_create<T>(length: usize, _: SyscallToken) -> Option<QueueHandle<T>> {
     syscall_begin!(queue_create);
     let ret = queue::queue_create(length, core::mem::size_of::<T>());
-    let ret = ret.map(|ptr| QueueHandle {
-        ptr,
-        phantom: PhantomData,
-    });
+    let ret = ret.map(|ptr| unsafe { QueueHandle::new(ptr) });
     syscall_end!(queue_create, ret);
 }
 
@@ -469,7 +639,7 @@ pub fn sys_queue_send_back<T>(
     _: SyscallToken,
 ) -> Result<(), QueueErr> {
     syscall_begin!(queue, { forget(item) });
-    let ret = queue::queue_send_back(q.ptr, cast_to_u8_ptr(&item), wait_ticks);
+    let ret = queue::queue_send_back(q.ptr, q.gen, cast_to_u8_ptr(&item), wait_ticks);
     forget(item);
     syscall_end!(queue, ret);
 }
@@ -481,7 +651,7 @@ pub fn sys_queue_send_front<T>(
     _: SyscallToken,
 ) -> Result<(), QueueErr> {
     syscall_begin!(queue, { forget(item) });
-    let ret = queue::queue_send_front(q.ptr, cast_to_u8_ptr(&item), wait_ticks);
+    let ret = queue::queue_send_front(q.ptr, q.gen, cast_to_u8_ptr(&item), wait_ticks);
     forget(item);
     syscall_end!(queue, ret);
 }
@@ -492,10 +662,56 @@ pub fn sys_queue_receive<T>(
     _: SyscallToken,
 ) -> Result<T, QueueErr> {
     syscall_begin!(queue);
-    let ret = queue::queue_receive(q.ptr, wait_ticks);
+    let ret = queue::queue_receive(q.ptr, q.gen, wait_ticks);
+    syscall_end!(queue, ret);
+}
+
+// Like a receive, but the item stays in the queue
+pub fn sys_queue_peek<T: Copy>(
+    q: QueueHandle<T>,
+    wait_ticks: Time,
+    _: SyscallToken,
+) -> Result<T, QueueErr> {
+    syscall_begin!(queue);
+    let ret = queue::queue_peek(q.ptr, q.gen, wait_ticks);
+    syscall_end!(queue, ret);
+}
+
+// Replace the item of a queue of length 1, e.g. the latest sensor reading.
+// Fails with `InvalidParam` on longer queues.
+pub fn sys_queue_overwrite<T>(q: QueueHandle<T>, item: T, _: SyscallToken) -> Result<(), QueueErr> {
+    syscall_begin!(queue, { forget(item) });
+    let ret = queue::queue_overwrite(q.ptr, q.gen, cast_to_u8_ptr(&item));
+    forget(item);
+    syscall_end!(queue, ret);
+}
+
+pub fn sys_queue_messages_waiting<T>(
+    q: QueueHandle<T>,
+    _: SyscallToken,
+) -> Result<usize, QueueErr> {
+    syscall_begin!(queue);
+    let ret = queue::queue_messages_waiting(q.ptr, q.gen);
     syscall_end!(queue, ret);
 }
 
+pub fn sys_queue_spaces_available<T>(
+    q: QueueHandle<T>,
+    _: SyscallToken,
+) -> Result<usize, QueueErr> {
+    syscall_begin!(queue);
+    let ret = queue::queue_spaces_available(q.ptr, q.gen);
+    syscall_end!(queue, ret);
+}
+
+// Blocked senders and receivers get `QueueDeleted`, as does any later use
+// of a handle to the queue
+pub fn sys_queue_delete<T>(q: QueueHandle<T>, _: SyscallToken) -> Result<(), QueueErr> {
+    syscall_begin!(queue_delete);
+    let ret = queue::queue_delete(q.ptr, q.gen);
+    syscall_end!(queue_delete, ret);
+}
+
 pub type SemaphoreHandle = PMPtr<Semaphore>;
 
 // Semaphore syscalls
@@ -505,6 +721,12 @@ pub fn sys_create_semaphore(n: usize, _: SyscallToken) -> Option<SemaphoreHandle
     syscall_end!(sema_create, ret);
 }
 
+pub fn sys_create_mutex(_: SyscallToken) -> Option<SemaphoreHandle> {
+    syscall_begin!(mutex_create);
+    let ret = semaphore::create_mutex();
+    syscall_end!(mutex_create, ret);
+}
+
 pub fn sys_semaphore_take(sem: SemaphoreHandle, wait_ticks: Time) -> Result<(), ()> {
     syscall_begin_outside_tx!();
     let r = semaphore::semaphore_take(sem, wait_ticks);
@@ -518,6 +740,183 @@ pub fn sys_semaphore_give(sem: SemaphoreHandle) {
     syscall_end_outside_tx!();
 }
 
+// Fails unless the caller holds the mutex
+pub fn sys_semaphore_clear_poison(sem: SemaphoreHandle) -> Result<(), ()> {
+    syscall_begin_outside_tx!();
+    let r = semaphore::semaphore_clear_poison(sem);
+    syscall_end_outside_tx!();
+    r
+}
+
+// Reader-writer lock syscalls
+pub type RwLockHandle = PMPtr<RwLock>;
+
+pub fn sys_rwlock_create(_: SyscallToken) -> Option<RwLockHandle> {
+    syscall_begin!(rwlock_create);
+    let ret = rwlock::create_rwlock();
+    syscall_end!(rwlock_create, ret);
+}
+
+// Fails with `Deadlock` if the caller holds the lock already
+pub fn sys_rwlock_acquire(
+    lock: RwLockHandle,
+    wait_ticks: Time,
+    writer: bool,
+    _: SyscallToken,
+) -> Result<(), RwLockErr> {
+    syscall_begin!(rwlock_acquire);
+    let ret = rwlock::rwlock_acquire(lock, wait_ticks, writer);
+    syscall_end!(rwlock_acquire, ret);
+}
+
+pub fn sys_rwlock_release(lock: RwLockHandle, _: SyscallToken) {
+    syscall_begin!(noret, rwlock_release);
+    rwlock::rwlock_release(lock);
+    syscall_end!();
+}
+
+// Condition variable syscalls
+pub type CondVarHandle = PMPtr<CondVar>;
+
+pub fn sys_condvar_create(_: SyscallToken) -> Option<CondVarHandle> {
+    syscall_begin!(condvar_create);
+    let ret = condvar::create_condvar();
+    syscall_end!(condvar_create, ret);
+}
+
+// `sem` is the mutex held by the caller, it is held again on return
+pub fn sys_condvar_wait(
+    cv: CondVarHandle,
+    sem: SemaphoreHandle,
+    wait_ticks: Time,
+) -> Result<(), ()> {
+    syscall_begin_outside_tx!();
+    let r = condvar::condvar_wait(cv, sem, wait_ticks);
+    syscall_end_outside_tx!();
+    r
+}
+
+pub fn sys_condvar_notify(cv: CondVarHandle, all: bool) {
+    syscall_begin_outside_tx!();
+    condvar::condvar_notify(cv, all);
+    syscall_end_outside_tx!();
+}
+
+// Queue set syscalls
+
+// The set must be long enough for all items and counts of its members
+pub type QueueSetHandle = QueueHandle<QueueSetMember>;
+
+pub fn sys_queue_set_create(length: usize, _: SyscallToken) -> Option<QueueSetHandle> {
+    syscall_begin!(queue_create);
+    let ret = queue::queue_set_create(length).map(|ptr| unsafe { QueueHandle::new(ptr) });
+    syscall_end!(queue_create, ret);
+}
+
+// Members join and leave empty, and must be read only after a select
+pub fn sys_queue_add_to_set<T>(
+    q: QueueHandle<T>,
+    set: QueueSetHandle,
+    _: SyscallToken,
+) -> Result<(), QueueErr> {
+    syscall_begin!(queue_set);
+    let ret = queue::queue_set_add(QueueSetMember::Queue(q.ptr), set.ptr, set.gen);
+    syscall_end!(queue_set, ret);
+}
+
+pub fn sys_queue_remove_from_set<T>(
+    q: QueueHandle<T>,
+    set: QueueSetHandle,
+    _: SyscallToken,
+) -> Result<(), QueueErr> {
+    syscall_begin!(queue_set);
+    let ret = queue::queue_set_remove(QueueSetMember::Queue(q.ptr), set.ptr, set.gen);
+    syscall_end!(queue_set, ret);
+}
+
+pub fn sys_semaphore_add_to_set(
+    sem: SemaphoreHandle,
+    set: QueueSetHandle,
+    _: SyscallToken,
+) -> Result<(), QueueErr> {
+    syscall_begin!(queue_set);
+    let ret = queue::queue_set_add(QueueSetMember::Semaphore(sem), set.ptr, set.gen);
+    syscall_end!(queue_set, ret);
+}
+
+pub fn sys_semaphore_remove_from_set(
+    sem: SemaphoreHandle,
+    set: QueueSetHandle,
+    _: SyscallToken,
+) -> Result<(), QueueErr> {
+    syscall_begin!(queue_set);
+    let ret = queue::queue_set_remove(QueueSetMember::Semaphore(sem), set.ptr, set.gen);
+    syscall_end!(queue_set, ret);
+}
+
+// Block until a member has an item or a count, then take it from the member
+// without waiting
+pub fn sys_queue_select(
+    set: QueueSetHandle,
+    wait_ticks: Time,
+    _: SyscallToken,
+) -> Result<QueueSetMember, QueueErr> {
+    syscall_begin!(queue);
+    let ret = queue::queue_receive(set.ptr, set.gen, wait_ticks);
+    syscall_end!(queue, ret);
+}
+
+// Stream buffer syscalls
+pub type StreamBufferHandle = PMPtr<StreamBuffer>;
+
+// A blocked receiver wakes up once `trigger_level` bytes are in
+pub fn sys_stream_buffer_create(
+    capacity: usize,
+    trigger_level: usize,
+    _: SyscallToken,
+) -> Option<StreamBufferHandle> {
+    syscall_begin!(stream_buffer_create);
+    let ret = stream_buffer::stream_buffer_create(capacity, trigger_level);
+    syscall_end!(stream_buffer_create, ret);
+}
+
+// Each message takes its length plus a usize length prefix of `capacity`
+pub fn sys_message_buffer_create(capacity: usize, _: SyscallToken) -> Option<StreamBufferHandle> {
+    syscall_begin!(stream_buffer_create);
+    let ret = stream_buffer::message_buffer_create(capacity);
+    syscall_end!(stream_buffer_create, ret);
+}
+
+// A stream takes as many bytes as fit, a message all or nothing
+pub fn sys_stream_buffer_send(
+    sb: StreamBufferHandle,
+    data: &[u8],
+    wait_ticks: Time,
+    _: SyscallToken,
+) -> Result<usize, StreamErr> {
+    syscall_begin!(stream_buffer);
+    let ret = stream_buffer::stream_buffer_send(sb, data, wait_ticks);
+    syscall_end!(stream_buffer, ret);
+}
+
+// Up to N bytes, returned through the replay cache like a queue item, so N
+// is bounded by its size. A message larger than N is left in the buffer.
+pub fn sys_stream_buffer_receive<const N: usize>(
+    sb: StreamBufferHandle,
+    wait_ticks: Time,
+    _: SyscallToken,
+) -> Result<StreamChunk<N>, StreamErr> {
+    syscall_begin!(stream_buffer);
+    let ret = stream_buffer::stream_buffer_receive(sb, wait_ticks);
+    syscall_end!(stream_buffer, ret);
+}
+
+pub fn sys_stream_buffer_bytes_available(sb: StreamBufferHandle, _: SyscallToken) -> usize {
+    syscall_begin!(stream_buffer);
+    let ret = sb.as_ref().bytes_available();
+    syscall_end!(stream_buffer, ret);
+}
+
 // Event group syscalls
 pub fn sys_event_group_create(_: SyscallToken) -> Option<EventGroupHandle> {
     syscall_begin!(event_grp_create);
@@ -566,6 +965,38 @@ pub fn sys_event_group_sync(
     syscall_end!(event_grp, ret);
 }
 
+// Returns the bits as they were before clearing
+pub fn sys_event_group_clear(
+    event_grp: EventGroupHandle,
+    bits_to_clear: EventBits,
+    _: SyscallToken,
+) -> Result<EventBits, ()> {
+    syscall_begin!(event_grp);
+    let ret = event_group::event_group_clear(event_grp, bits_to_clear);
+    syscall_end!(event_grp, ret);
+}
+
+pub fn sys_event_group_get(event_grp: EventGroupHandle, _: SyscallToken) -> Result<EventBits, ()> {
+    syscall_begin!(event_grp);
+    let ret = event_group::event_group_get(event_grp);
+    syscall_end!(event_grp, ret);
+}
+
+// Blocked waiters get an error, as does any later use of a handle to the
+// group. A set on a deleted group is dropped.
+pub fn sys_event_group_delete(event_grp: EventGroupHandle, _: SyscallToken) -> Result<(), ()> {
+    syscall_begin!(event_grp_delete);
+    let ret = event_group::event_group_delete(event_grp);
+    syscall_end!(event_grp_delete, ret);
+}
+
+// Only for the timer daemon, applies a set deferred by an interrupt handler
+pub fn sys_event_group_apply_deferred(_: SyscallToken) -> bool {
+    syscall_begin!(event_grp_set);
+    let ret = event_group::apply_deferred_set();
+    syscall_end!(event_grp_set, ret);
+}
+
 pub type TimerHandle = PMPtr<Timer>;
 
 pub fn sys_timer_create<T>(
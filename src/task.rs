@@ -1,15 +1,27 @@
 use vcell::VolatileCell;
 
+#[cfg(feature = "jit_checkpoint")]
+use crate::checkpoint::{self, ExecMode};
 use crate::critical::{self, CriticalSection};
-use crate::heap::{self, create_per_task_pm_heap, MemStat, PMHeap, PerTaskPMBumpAllocator};
+use crate::heap::{
+    self, create_per_task_pm_heap, recycle_per_task_pm_heap, MemStat, PMHeap,
+    PerTaskPMBumpAllocator,
+};
 use crate::list::{self, CircularPList, InsertSortedPList, Node, SortedPList};
 use crate::marker::TxInSafe;
 use crate::pmem::{Journal, JournalHandle, PMPtr, PVolatilePtr};
 use crate::recover::{current_generation, get_boot_tx};
+use crate::sched::{SchedPolicy, Scheduler};
+use crate::rwlock;
+use crate::semaphore::{self, Semaphore};
 use crate::syscalls::SyscallReplayCache;
 use crate::time::{Time, MAX_DELAY_TIME, TIME_MANAGER};
 use crate::transaction::{self, run, Transaction, TxCache, UserTxInfo};
-use crate::util::{benchmark_clock, bubble_sort, get_time_diff, max, min, pretty_print_task_stats};
+use crate::util::{
+    benchmark_clock, bubble_sort, compiler_pm_fence, get_time_diff, max, min,
+    pretty_print_task_stats,
+};
+use core::alloc::Layout;
 use core::arch::asm;
 use core::cell::UnsafeCell;
 use core::fmt::{self, Display};
@@ -55,8 +67,17 @@ pub type TaskEventNode = Node<BlockedListItem>;
 // pub const TASK_NUM_LIMIT: usize = 6;
 pub const TASK_NUM_LIMIT: usize = board::TASK_NUM_LIMIT;
 const STACK_SIZE: usize = board::STACK_SIZE;
+// in words, like the board stack size
+pub const DEFAULT_STACK_SIZE: usize = STACK_SIZE;
+pub const MIN_STACK_SIZE: usize = 32;
+// only for the task slots, dynamic tasks take their stacks from the PM heap
+const STACK_ARENA_SIZE: usize = board::STACK_ARENA_SIZE;
+const STACK_FILL_PATTERN: usize = usize::MAX / 0xff * 0xa5;
 const TASK_STRUCT_SIZE: usize = mem::size_of::<Task>();
-const NUM_PRIORITY_LEVELS: usize = 8;
+// more levels than a word has bits take more than one bitmap word
+pub const NUM_PRIORITY_LEVELS: usize = board::NUM_PRIORITY_LEVELS;
+const PRIO_WORD_BITS: usize = usize::BITS as usize;
+const READY_BITMAP_WORDS: usize = (NUM_PRIORITY_LEVELS + PRIO_WORD_BITS - 1) / PRIO_WORD_BITS;
 const MIN_PRIORITY: usize = NUM_PRIORITY_LEVELS;
 
 // #[link_section = ".pmem"]
@@ -71,6 +92,15 @@ declare_pm_var_array!(
     CircularPList::new()
 );
 
+// A set bit for each priority level with ready tasks, the highest priority
+// is the most significant bit of the first word. The bit of a level is
+// synced with its ready list on every insertion and removal
+declare_pm_var_unsafe!(
+    READY_BITMAP,
+    [usize; READY_BITMAP_WORDS],
+    [0; READY_BITMAP_WORDS]
+);
+
 // static mut DELAYED_TASK_LIST: PMVar<SortedPList<SchedListItem>> = unsafe { PMVar::new(SortedPList::new()) };
 declare_pm_var!(
     DELAYED_TASK_LIST,
@@ -78,8 +108,15 @@ declare_pm_var!(
     SortedPList::new()
 );
 
-static mut USER_STACKS: [[usize; STACK_SIZE]; TASK_NUM_LIMIT] = [[0; STACK_SIZE]; TASK_NUM_LIMIT];
-// declare_pm_var_unsafe!(USER_STACKS,  [[u32; STACK_SIZE]; TASK_NUM_LIMIT], [[0; STACK_SIZE]; TASK_NUM_LIMIT]);
+// Stacks are volatile, where each task's stack lives in the arena is not
+static mut STACK_ARENA: [usize; STACK_ARENA_SIZE] = [0; STACK_ARENA_SIZE];
+// (offset, size) in words of the stack of each task slot
+declare_pm_var_unsafe!(
+    TASK_STACKS,
+    [(usize, usize); TASK_NUM_LIMIT],
+    [(0, 0); TASK_NUM_LIMIT]
+);
+declare_pm_var!(STACK_ARENA_TOP, usize, 0);
 // static mut TASK_STRUCTS: [[u8; TASK_STRUCT_SIZE]; TASK_NUM_LIMIT] =
 //     [[0; TASK_STRUCT_SIZE]; TASK_NUM_LIMIT];
 declare_pm_var_unsafe!(
@@ -89,11 +126,25 @@ declare_pm_var_unsafe!(
 );
 
 // static mut TASK_ARRAY: [Option<PMPtr<Task>>; TASK_NUM_LIMIT] = [None; TASK_NUM_LIMIT];
+#[cfg(not(feature = "dynamic_tasks"))]
 declare_pm_var_unsafe!(
     TASK_ARRAY,
     [Option<PMPtr<Task>>; TASK_NUM_LIMIT],
     [None; TASK_NUM_LIMIT]
 );
+
+// With dynamic tasks, the live tasks are linked through their TCBs instead.
+// Released TCBs of dynamic tasks keep their stack and PM heap for reuse.
+#[cfg(feature = "dynamic_tasks")]
+declare_pm_var_unsafe!(TASK_REGISTRY, Option<PMPtr<Task>>, None);
+#[cfg(feature = "dynamic_tasks")]
+declare_pm_var_unsafe!(FREE_TASKS, Option<PMPtr<Task>>, None);
+#[cfg(feature = "dynamic_tasks")]
+declare_pm_var!(NEXT_DYNAMIC_TID, usize, TASK_NUM_LIMIT);
+
+// checkpoint slots are indexed by task id
+#[cfg(all(feature = "dynamic_tasks", feature = "jit_checkpoint"))]
+compile_error!("dynamic_tasks can't be combined with jit_checkpoint");
 // static mut CUR_MAX_PRIORITY: PMVar<Priority> = unsafe { PMVar::new(Priority::min_priority()) };
 declare_pm_var!(CUR_MAX_PRIORITY, Priority, Priority::min_priority());
 // static mut SCHEDULER_STARTED: PMVar<bool> = unsafe { PMVar::new(false) };
@@ -101,6 +152,12 @@ declare_pm_var!(CUR_MAX_PRIORITY, Priority, Priority::min_priority());
 static mut SCHEDULER_STARTED: bool = false;
 // static mut TASK_CNT: PMVar<usize> = unsafe { PMVar::new(0) };
 declare_pm_var!(TASK_CNT, usize, 0);
+const MAX_IDLE_HOOKS: usize = 4;
+declare_pm_var!(
+    IDLE_HOOKS,
+    [Option<fn()>; MAX_IDLE_HOOKS],
+    [None; MAX_IDLE_HOOKS]
+);
 
 #[no_mangle]
 #[link_section = ".pmem"]
@@ -112,6 +169,109 @@ pub unsafe fn get_task_cnt() -> usize {
     *TASK_CNT.borrow_mut_no_logging()
 }
 
+#[cfg(not(feature = "dynamic_tasks"))]
+pub fn task_by_id(tid: usize) -> Option<PMPtr<Task>> {
+    unsafe { TASK_ARRAY.get(tid).copied().flatten() }
+}
+
+#[cfg(feature = "dynamic_tasks")]
+pub fn task_by_id(tid: usize) -> Option<PMPtr<Task>> {
+    iter_tasks().find(|t| t.as_ref().task_id == tid)
+}
+
+// All live tasks, including the idle one
+#[cfg(not(feature = "dynamic_tasks"))]
+pub fn iter_tasks() -> impl Iterator<Item = PMPtr<Task>> {
+    unsafe { TASK_ARRAY.iter().filter_map(|t| *t) }
+}
+
+#[cfg(feature = "dynamic_tasks")]
+pub fn iter_tasks() -> impl Iterator<Item = PMPtr<Task>> {
+    TaskChain(unsafe { TASK_REGISTRY })
+}
+
+#[cfg(feature = "dynamic_tasks")]
+struct TaskChain(Option<PMPtr<Task>>);
+
+#[cfg(feature = "dynamic_tasks")]
+impl Iterator for TaskChain {
+    type Item = PMPtr<Task>;
+
+    fn next(&mut self) -> Option<PMPtr<Task>> {
+        let task = self.0?;
+        self.0 = task.as_ref().next_task;
+        Some(task)
+    }
+}
+
+#[cfg(not(feature = "dynamic_tasks"))]
+unsafe fn register_task(id: usize, task: PMPtr<Task>, j: JournalHandle) {
+    j.get_mut()
+        .append_log_of(&mut TASK_ARRAY[id] as *mut Option<PMPtr<Task>>);
+    TASK_ARRAY[id] = Some(task);
+}
+
+#[cfg(feature = "dynamic_tasks")]
+unsafe fn register_task(_id: usize, task: PMPtr<Task>, j: JournalHandle) {
+    push_onto_chain(&mut TASK_REGISTRY, task, Some(j));
+}
+
+// Unlogged when there is no journal, a deletion is rolled forward instead
+#[cfg(not(feature = "dynamic_tasks"))]
+unsafe fn unregister_task(task: &mut Task, j: Option<JournalHandle>) {
+    let slot = &mut TASK_ARRAY[task.task_id];
+    if let Some(j) = j {
+        j.get_mut().append_log_of(slot as *mut Option<PMPtr<Task>>);
+    }
+    *slot = None;
+}
+
+#[cfg(feature = "dynamic_tasks")]
+unsafe fn unregister_task(task: &mut Task, j: Option<JournalHandle>) {
+    let ptr = PMPtr::from_mut_ref(task);
+    unlink_from_chain(&mut TASK_REGISTRY, ptr, j);
+    let freed = TaskChain(FREE_TASKS).any(|t| t == ptr);
+    if task.task_id >= TASK_NUM_LIMIT && !freed {
+        push_onto_chain(&mut FREE_TASKS, ptr, j);
+    }
+}
+
+#[cfg(feature = "dynamic_tasks")]
+unsafe fn push_onto_chain(
+    head: &mut Option<PMPtr<Task>>,
+    mut task: PMPtr<Task>,
+    j: Option<JournalHandle>,
+) {
+    let link = &mut task.as_mut_no_logging().next_task;
+    if let Some(j) = j {
+        j.get_mut().append_log_of(link as *mut Option<PMPtr<Task>>);
+        j.get_mut().append_log_of(head as *mut Option<PMPtr<Task>>);
+    }
+    *link = *head;
+    compiler_pm_fence();
+    *head = Some(task);
+}
+
+// Does nothing if the task isn't in the chain
+#[cfg(feature = "dynamic_tasks")]
+unsafe fn unlink_from_chain(
+    head: &mut Option<PMPtr<Task>>,
+    task: PMPtr<Task>,
+    j: Option<JournalHandle>,
+) {
+    let mut link = head as *mut Option<PMPtr<Task>>;
+    while let Some(mut t) = *link {
+        if t == task {
+            if let Some(j) = j {
+                j.get_mut().append_log_of(link);
+            }
+            *link = task.as_ref().next_task;
+            return;
+        }
+        link = &mut t.as_mut_no_logging().next_task;
+    }
+}
+
 pub unsafe fn get_delay_list() -> &'static mut SortedPList<SchedListItem> {
     DELAYED_TASK_LIST.borrow_mut_no_logging()
 }
@@ -121,6 +281,64 @@ pub unsafe fn get_sched_list(prio: Priority) -> &'static mut CircularPList<Sched
     TASK_LISTS[prio].borrow_mut_no_logging()
 }
 
+#[inline(always)]
+fn ready_bit(level: usize) -> (usize, usize) {
+    (level / PRIO_WORD_BITS, 1 << (PRIO_WORD_BITS - 1 - level % PRIO_WORD_BITS))
+}
+
+// Set or clear the bit of `level` from its ready list. It only reads the
+// list, so the ready list roll-forwards can redo it
+#[cfg(not(feature = "opt_list"))]
+pub fn sync_ready_level(level: usize, j: JournalHandle) {
+    let (word, bit) = ready_bit(level);
+    unsafe {
+        j.get_mut().append_log_of(&mut READY_BITMAP[word] as *mut usize);
+        if TASK_LISTS[level].length() > 0 {
+            READY_BITMAP[word] |= bit;
+        } else {
+            READY_BITMAP[word] &= !bit;
+        }
+    }
+}
+
+#[cfg(feature = "opt_list")]
+pub fn sync_ready_level(level: usize, _j: JournalHandle) {
+    let (word, bit) = ready_bit(level);
+    unsafe {
+        if TASK_LISTS[level].length() > 0 {
+            READY_BITMAP[word] |= bit;
+        } else {
+            READY_BITMAP[word] &= !bit;
+        }
+    }
+}
+
+pub fn is_ready_level_marked(level: usize) -> bool {
+    let (word, bit) = ready_bit(level);
+    unsafe { READY_BITMAP[word] & bit != 0 }
+}
+
+// The highest priority level from `from` on whose ready list is not empty
+pub fn next_ready_level(from: usize, _cs: &CriticalSection) -> Option<usize> {
+    if from >= NUM_PRIORITY_LEVELS {
+        return None;
+    }
+    let (first, bit) = ready_bit(from);
+    // ignore the levels above `from`
+    let mut bits = unsafe { READY_BITMAP[first] } & (bit | (bit - 1));
+    let mut word = first;
+    loop {
+        if bits != 0 {
+            return Some(word * PRIO_WORD_BITS + bits.leading_zeros() as usize);
+        }
+        word += 1;
+        if word == READY_BITMAP_WORDS {
+            return None;
+        }
+        bits = unsafe { READY_BITMAP[word] };
+    }
+}
+
 pub fn debug_display_sched_list(prio: usize) {
     os_print_no_header!("Sched List at Priority {}: \n {}", prio, unsafe {
         *TASK_LISTS[prio]
@@ -155,6 +373,24 @@ pub enum TaskState {
     Blocked,
     Running,
     Killed,
+    Suspended,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum NotifyState {
+    NotWaiting,
+    Waiting,
+    Received,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum NotifyAction {
+    NoAction,
+    SetBits,
+    Increment,
+    SetValueWithOverwrite,
+    // fails if the previous notification is still pending
+    SetValueWithoutOverwrite,
 }
 
 #[derive(Eq, Clone, Copy)]
@@ -173,6 +409,11 @@ impl SchedListItem {
     pub fn get_mut_task(&mut self) -> &mut Task {
         unsafe { self.task.as_mut_no_logging() }
     }
+
+    #[inline(always)]
+    pub fn get_task_ptr(&self) -> PMPtr<Task> {
+        self.task
+    }
 }
 
 impl Display for SchedListItem {
@@ -301,12 +542,18 @@ impl Priority {
     }
 }
 
+// The task id and the incarnation of its slot, a handle of a deleted task
+// doesn't reach the next task created in the slot
 #[derive(Clone, Copy)]
-pub struct TaskHandle(usize);
+pub struct TaskHandle(usize, usize);
 
 impl TaskHandle {
+    fn of(task: &Task) -> Self {
+        TaskHandle(task.task_id, task.incarnation)
+    }
+
     pub fn get_task_ptr(&self) -> Option<PMPtr<Task>> {
-        unsafe { TASK_ARRAY[self.0] }
+        task_by_id(self.0).filter(|t| t.as_ref().incarnation == self.1)
     }
 }
 
@@ -320,6 +567,8 @@ pub struct Task {
     param: usize,
     status: UnsafeCell<PMVar<TaskState>>,
     stack_bottom: usize,
+    // the lowest word of the stack
+    stack_limit: usize,
     tx: Transaction,
     journal: Journal,
     syscall_tx_cache: TxCache,
@@ -327,10 +576,47 @@ pub struct Task {
     list_tx_done: bool,
     user_tx_info: UserTxInfo,
     generation: usize,
+    // bumped each time the TCB is taken by a new task
+    incarnation: usize,
     recovery_mode: bool,
+    // the priority without any inherited one
+    base_priority: Priority,
+    mutexes_held: usize,
+    // the last taken mutex, chained to the ones taken before
+    held_mutexes: Option<PMPtr<Semaphore>>,
+    notify_value: usize,
+    notify_state: NotifyState,
+    // absolute tick, only used by the EDF policy
+    deadline: Option<Time>,
+    // how long after its wakeup the next job of the task is due
+    relative_deadline: Time,
+    #[cfg(feature = "jit_checkpoint")]
+    exec_mode: PMVar<ExecMode>,
     pm_heap: PMHeap<PerTaskPMBumpAllocator>,
     sched_node: TaskSchedNode,
     event_node: TaskEventNode,
+    // the next task in the registry or in the free list
+    #[cfg(feature = "dynamic_tasks")]
+    next_task: Option<PMPtr<Task>>,
+}
+
+// Returns the offset in words of a new stack
+unsafe fn bump_stack_arena(size: usize, j: JournalHandle) -> Result<usize, ErrorCode> {
+    let top = STACK_ARENA_TOP.borrow_mut(j);
+    if *top + size > STACK_ARENA_SIZE {
+        os_print!("No space for a stack of {} words", size);
+        return Err(ErrorCode::NoSpace);
+    }
+    let offset = *top;
+    *top += size;
+    Ok(offset)
+}
+
+// The lowest and the highest word of a stack
+unsafe fn stack_region(offset: usize, size: usize) -> (usize, usize) {
+    let limit = &mut STACK_ARENA[offset] as *mut usize as usize;
+    let bottom = &mut STACK_ARENA[offset + size - 1] as *mut usize as usize;
+    (limit, bottom)
 }
 
 pub unsafe fn is_scheduler_started() -> bool {
@@ -348,29 +634,94 @@ pub unsafe fn reset_scheduler_started() {
 unsafe impl TxInSafe for Task {}
 
 impl Task {
-    pub unsafe fn alloc_static(id: usize) -> (PMPtr<Task>, usize) {
+    pub unsafe fn alloc_static(id: usize, j: JournalHandle) -> PMPtr<Task> {
         unsafe {
             let task_ptr = &mut TASK_STRUCTS[id][0] as *mut u8 as *mut Task;
             let task_ptr = PMPtr::from_ptr(task_ptr);
-            let stack_ptr = &mut USER_STACKS[id][STACK_SIZE - 1] as *mut usize as usize;
-            TASK_ARRAY[id] = Some(task_ptr);
-            (task_ptr, stack_ptr)
+            // the slot may be a released one
+            register_task(id, task_ptr, j);
+            task_ptr
+        }
+    }
+
+    // Returns the lowest and the highest word of the stack. A slot keeps its
+    // stack for the next task if it's large enough.
+    pub unsafe fn alloc_stack(
+        id: usize,
+        size: usize,
+        j: JournalHandle,
+    ) -> Result<(usize, usize), ErrorCode> {
+        if size < MIN_STACK_SIZE {
+            return Err(ErrorCode::InvalidParam);
+        }
+        let (mut offset, mut cap) = TASK_STACKS[id];
+        if cap < size {
+            (offset, cap) = (bump_stack_arena(size, j)?, size);
+            j.get_mut()
+                .append_log_of(&mut TASK_STACKS[id] as *mut (usize, usize));
+            TASK_STACKS[id] = (offset, cap);
+        }
+        Ok(stack_region(offset, cap))
+    }
+
+    // A released dynamic TCB is taken over with its stack and PM heap if the
+    // stack is large enough. Otherwise the TCB and its stack are carved in one
+    // piece from the global PM heap, the stack is only used as volatile memory.
+    // Returns the task id, the TCB, the stack and whether the TCB is reused.
+    #[cfg(feature = "dynamic_tasks")]
+    unsafe fn alloc_dynamic(
+        size: usize,
+        j: JournalHandle,
+    ) -> Result<(usize, PMPtr<Task>, (usize, usize), bool), ErrorCode> {
+        if size < MIN_STACK_SIZE {
+            return Err(ErrorCode::InvalidParam);
         }
+        let free = TaskChain(FREE_TASKS).find(|t| t.as_ref().stack_capacity() >= size);
+        if let Some(task_ptr) = free {
+            let task = task_ptr.as_ref();
+            unlink_from_chain(&mut FREE_TASKS, task_ptr, Some(j));
+            register_task(task.task_id, task_ptr, j);
+            return Ok((task.task_id, task_ptr, (task.stack_limit, task.stack_bottom), true));
+        }
+        let words = Layout::array::<usize>(size).unwrap();
+        let (layout, stack_offset) = Layout::new::<Task>().extend(words).unwrap();
+        let start = match heap::palloc_global_layout(layout, j) {
+            Some(start) => start,
+            None => {
+                os_print!("No space for a dynamic task");
+                return Err(ErrorCode::NoSpace);
+            }
+        };
+        let task_ptr = PMPtr::new(start as *mut Task);
+        let limit = start + stack_offset;
+        let bottom = limit + (size - 1) * mem::size_of::<usize>();
+        let next_tid = NEXT_DYNAMIC_TID.borrow_mut(j);
+        let tid = *next_tid;
+        *next_tid += 1;
+        register_task(tid, task_ptr, j);
+        Ok((tid, task_ptr, (limit, bottom), false))
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize_task(
         task: &mut Task,
         tid: usize,
-        stack_ptr: usize,
+        stack: (usize, usize),
         name: &'static str,
         prio: usize,
         func: usize,
         param: usize,
+        reused: bool,
+        j: JournalHandle,
     ) {
+        if reused {
+            task.log_reused_slot(j);
+        }
         let task_pmptr = unsafe { PMPtr::from_mut_ref(task) };
         task.task_id = tid;
-        task.stack_top = stack_ptr;
-        task.stack_bottom = stack_ptr;
+        task.incarnation = task.incarnation.wrapping_add(1);
+        (task.stack_limit, task.stack_bottom) = stack;
+        task.stack_top = task.stack_bottom;
         task.param = param;
         task.sched_node = TaskSchedNode {
             prev: None,
@@ -390,7 +741,6 @@ impl Task {
             },
             list: None,
         };
-        task.pm_heap = PMHeap::new(PerTaskPMBumpAllocator::new());
         task.journal.init();
         task.tx = Transaction::new(
             unsafe { JournalHandle::new(&task.journal as *const Journal) },
@@ -403,17 +753,108 @@ impl Task {
         task.task_func = func;
         task.name = name;
         task.priority = Priority::new(prio);
+        task.base_priority = task.priority;
+        task.mutexes_held = 0;
+        task.held_mutexes = None;
+        task.notify_value = 0;
+        task.notify_state = NotifyState::NotWaiting;
+        task.deadline = None;
+        task.relative_deadline = 0;
         task.status = unsafe { UnsafeCell::new(PMVar::new(TaskState::Ready)) };
         task.recovery_mode = false;
+        #[cfg(feature = "jit_checkpoint")]
+        {
+            task.exec_mode = unsafe { PMVar::new(ExecMode::Transactional) };
+        }
         task.generation = current_generation();
         // initialize the stack by caling arch specific init function
+        task.paint_stack();
         task.stack_top = arch::initialize_stack(task.stack_top, task.task_func, param);
     }
 
+    // in words
+    pub fn stack_capacity(&self) -> usize {
+        (self.stack_bottom - self.stack_limit) / mem::size_of::<usize>() + 1
+    }
+
+    // The untouched part of the stack keeps the pattern
+    fn paint_stack(&mut self) {
+        let n = self.stack_capacity();
+        unsafe { core::slice::from_raw_parts_mut(self.stack_limit as *mut usize, n) }
+            .fill(STACK_FILL_PATTERN);
+    }
+
+    // The number of words never used since the stack was initialized
+    pub fn stack_high_water_mark(&self) -> usize {
+        let n = self.stack_capacity();
+        unsafe { core::slice::from_raw_parts(self.stack_limit as *const usize, n) }
+            .iter()
+            .take_while(|w| **w == STACK_FILL_PATTERN)
+            .count()
+    }
+
+    // Checked on a context switch, the lowest word works as a canary
+    pub fn stack_overflowed(&self) -> bool {
+        let canary = unsafe { *(self.stack_limit as *const usize) };
+        self.stack_top < self.stack_limit || canary != STACK_FILL_PATTERN
+    }
+
+    // A creation rolled back gives the slot back to the killed task, the rest
+    // of the TCB means nothing in a free slot
+    fn log_reused_slot(&mut self, j: JournalHandle) {
+        let journal = j.get_mut();
+        journal.append_log_of(&mut self.task_id as *mut usize);
+        journal.append_log_of(self.status.get());
+        journal.append_log_of(&mut self.stack_bottom as *mut usize);
+        journal.append_log_of(&mut self.stack_limit as *mut usize);
+        journal.append_log_of(&mut self.incarnation as *mut usize);
+    }
+
     pub fn get_task_id(&self) -> usize {
         self.task_id
     }
 
+    #[inline(always)]
+    pub fn is_idle(&self) -> bool {
+        self.task_func == idle_fn as usize
+    }
+
+    unsafe fn init_pm_heap(
+        &mut self,
+        size: usize,
+        reused: bool,
+        j: JournalHandle,
+    ) -> Result<(), ()> {
+        if reused {
+            j.get_mut()
+                .append_log_of(&mut self.pm_heap as *mut PMHeap<PerTaskPMBumpAllocator>);
+            recycle_per_task_pm_heap(&mut self.pm_heap, size, j)
+        } else {
+            self.pm_heap = PMHeap::new(PerTaskPMBumpAllocator::new());
+            create_per_task_pm_heap(&mut self.pm_heap, size, j)
+        }
+    }
+
+    // Mark the task as killed and free its slot. Every store is idempotent so
+    // a crashed deletion can be rolled forward. The PM heap is left as it is,
+    // other tasks may still use the objects the task created.
+    #[cfg(feature = "opt_list")]
+    pub fn release(&mut self, j: JournalHandle) {
+        self.set_status(TaskState::Killed, j);
+        compiler_pm_fence();
+        unsafe {
+            unregister_task(self, None);
+        }
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    pub fn release(&mut self, j: JournalHandle) {
+        self.set_status(TaskState::Killed, j);
+        unsafe {
+            unregister_task(self, Some(j));
+        }
+    }
+
     pub fn get_syscall_replay_cache(&mut self) -> &mut SyscallReplayCache {
         &mut self.syscall_replay_cache
     }
@@ -426,6 +867,24 @@ impl Task {
         self.stack_bottom - self.stack_top
     }
 
+    pub fn get_stack_region(&self) -> (usize, usize) {
+        (self.stack_top, self.stack_bottom)
+    }
+
+    pub fn get_generation(&self) -> usize {
+        self.generation
+    }
+
+    #[cfg(feature = "jit_checkpoint")]
+    pub fn get_exec_mode(&self) -> ExecMode {
+        *self.exec_mode
+    }
+
+    #[cfg(feature = "jit_checkpoint")]
+    pub fn set_exec_mode(&mut self, mode: ExecMode, j: JournalHandle) {
+        self.exec_mode.set(mode, j);
+    }
+
     pub fn user_tx_end(&mut self) {
         self.syscall_replay_cache.reset();
     }
@@ -498,6 +957,18 @@ impl Task {
         **status == TaskState::Running
     }
 
+    #[inline(always)]
+    pub fn is_suspended(&self) -> bool {
+        let status = &unsafe { *self.status.get() };
+        **status == TaskState::Suspended
+    }
+
+    #[inline(always)]
+    pub fn is_killed(&self) -> bool {
+        let status = &unsafe { *self.status.get() };
+        **status == TaskState::Killed
+    }
+
     #[inline(always)]
     pub fn is_schedulable(&self) -> bool {
         self.is_ready() || self.is_running()
@@ -659,6 +1130,7 @@ impl Task {
                 .borrow_mut(j)
                 .insert_node(cs, j, n);
         }
+        sync_ready_level(self.priority.get_value(), j);
         self.set_status(TaskState::Ready, j);
     }
 
@@ -678,6 +1150,7 @@ impl Task {
                 .borrow_mut_no_logging()
                 .insert(cs, j, n);
         }
+        sync_ready_level(self.priority.get_value(), j);
         self.set_status(TaskState::Ready, j);
     }
 
@@ -688,11 +1161,13 @@ impl Task {
         cs: &CriticalSection,
     ) -> &mut Node<SchedListItem> {
         let sched_node_ptr = self.get_sched_node_ptr();
-        unsafe {
+        let node = unsafe {
             TASK_LISTS[self.priority.get_value()]
                 .borrow_mut(j)
                 .remove(cs, j, sched_node_ptr)
-        }
+        };
+        sync_ready_level(self.priority.get_value(), j);
+        node
     }
 
     #[cfg(feature = "opt_list")]
@@ -703,6 +1178,7 @@ impl Task {
                 .borrow_mut_no_logging()
                 .remove(cs, j, sched_node_ptr)
         }
+        sync_ready_level(self.priority.get_value(), j);
     }
 
     pub fn less_important_than(&self, other: &Self) -> bool {
@@ -713,6 +1189,97 @@ impl Task {
         self.priority
     }
 
+    #[cfg(not(feature = "opt_list"))]
+    pub fn set_priority(&mut self, prio: Priority, j: JournalHandle) {
+        j.get_mut().append_log_of(&mut self.priority as *mut Priority);
+        self.priority = prio;
+    }
+
+    #[cfg(feature = "opt_list")]
+    pub fn set_priority(&mut self, prio: Priority, _j: JournalHandle) {
+        self.priority = prio;
+    }
+
+    pub fn get_base_priority(&self) -> Priority {
+        self.base_priority
+    }
+
+    #[cfg(not(feature = "opt_list"))]
+    pub fn set_base_priority(&mut self, prio: Priority, j: JournalHandle) {
+        j.get_mut()
+            .append_log_of(&mut self.base_priority as *mut Priority);
+        self.base_priority = prio;
+    }
+
+    #[cfg(feature = "opt_list")]
+    pub fn set_base_priority(&mut self, prio: Priority, _j: JournalHandle) {
+        self.base_priority = prio;
+    }
+
+    #[inline(always)]
+    pub fn is_priority_inherited(&self) -> bool {
+        self.priority != self.base_priority
+    }
+
+    pub fn get_mutexes_held(&self) -> usize {
+        self.mutexes_held
+    }
+
+    // Must be in the same transaction as the semaphore update
+    pub fn inc_mutexes_held(&mut self, j: JournalHandle) {
+        j.get_mut().append_log_of(&mut self.mutexes_held as *mut usize);
+        self.mutexes_held += 1;
+    }
+
+    pub fn dec_mutexes_held(&mut self, j: JournalHandle) {
+        j.get_mut().append_log_of(&mut self.mutexes_held as *mut usize);
+        self.mutexes_held = self.mutexes_held.saturating_sub(1);
+    }
+
+    pub fn held_mutexes(&self) -> Option<PMPtr<Semaphore>> {
+        self.held_mutexes
+    }
+
+    pub fn set_held_mutexes(&mut self, head: Option<PMPtr<Semaphore>>, j: JournalHandle) {
+        j.get_mut()
+            .append_log_of(&mut self.held_mutexes as *mut Option<PMPtr<Semaphore>>);
+        self.held_mutexes = head;
+    }
+
+    pub fn get_notify_value(&self) -> usize {
+        self.notify_value
+    }
+
+    pub fn get_deadline(&self) -> Option<Time> {
+        self.deadline
+    }
+
+    // Due `ticks` after `from`, and so is every later job
+    fn set_deadline(&mut self, ticks: Option<Time>, from: Time, j: JournalHandle) {
+        j.get_mut()
+            .append_log_of(&mut self.deadline as *mut Option<Time>);
+        j.get_mut()
+            .append_log_of(&mut self.relative_deadline as *mut Time);
+        self.deadline = ticks.map(|t| from.checked_add(t).unwrap());
+        self.relative_deadline = ticks.unwrap_or(0);
+    }
+
+    // A task which goes to sleep is done with its job, the next one is due
+    // after the wakeup. A passed deadline doesn't stay the earliest forever.
+    fn advance_deadline(&mut self, wakeup_time: Time, j: JournalHandle) {
+        if self.deadline.is_some() {
+            self.set_deadline(Some(self.relative_deadline), wakeup_time, j);
+        }
+    }
+
+    fn set_notification(&mut self, value: usize, state: NotifyState, j: JournalHandle) {
+        j.get_mut().append_log_of(&mut self.notify_value as *mut usize);
+        j.get_mut()
+            .append_log_of(&mut self.notify_state as *mut NotifyState);
+        self.notify_value = value;
+        self.notify_state = state;
+    }
+
     pub fn user_recovery(&mut self) {
         let user_tx = self.get_mut_user_tx();
         if user_tx.check_committed().is_ok() {
@@ -777,9 +1344,32 @@ impl Task {
         });
     }
 
+    // Resume a checkpoint-mode task from its JIT checkpoint, fall back to
+    // the transactional recovery if there is none.
+    #[cfg(feature = "jit_checkpoint")]
+    fn checkpoint_recovery(&mut self) -> bool {
+        if self.get_exec_mode() != ExecMode::Checkpoint {
+            return false;
+        }
+        match checkpoint::restore_stack(self.task_id, self.generation, self.stack_bottom) {
+            None => false,
+            Some(stack_top) => {
+                self.stack_top = stack_top;
+                self.generation = current_generation();
+                checkpoint::invalidate(self.task_id);
+                true
+            }
+        }
+    }
+
     pub fn jit_recovery(&mut self) {
         if self.is_crashed() {
             task_recovery_begin_stat(self);
+            #[cfg(feature = "jit_checkpoint")]
+            if self.checkpoint_recovery() {
+                task_recovery_end_stat(self);
+                return;
+            }
             // panic!("Impossible to do JIT recovery: task gen {}, global gen {}", self.generation, current_generation() );
             self.recovery_mode = true;
             // set generation to current one
@@ -804,6 +1394,7 @@ impl Task {
             // recover user TX
             self.user_recovery();
             // reinitialize the task stack since it is volatile
+            self.paint_stack();
             self.stack_top = arch::initialize_stack(self.stack_bottom, self.task_func, self.param);
             // mark the completion of recovery
             self.generation = current_generation();
@@ -824,6 +1415,15 @@ impl fmt::Display for Task {
     }
 }
 
+#[cfg(test)]
+static mut CRASH_IN_CREATE: bool = false;
+
+// Stop a creation after the TCB is set up, before it commits
+#[cfg(test)]
+pub fn set_crash_in_create(crash: bool) {
+    unsafe { CRASH_IN_CREATE = crash };
+}
+
 #[cfg(not(feature = "opt_list"))]
 pub fn create_task_static(
     name: &'static str,
@@ -831,31 +1431,24 @@ pub fn create_task_static(
     func: usize,
     param: usize,
     pmem_heap_sz: usize,
+    stack_size: usize,
 ) -> Result<TaskHandle, ErrorCode> {
     // TODO:  critical section can be shorter
     critical::with_no_interrupt(|cs| {
         transaction::run(|j| {
-            let task_cnt = unsafe { TASK_CNT.borrow_mut(j) };
-            let tid = *task_cnt;
-
-            if tid >= 8 {
-                os_print!("Maximun number of tasks reached, current number: {}", tid);
-                return Err(ErrorCode::NoSpace);
-            }
-
             if prio >= MIN_PRIORITY {
                 os_print!("Invalid priority for task: {}", name);
                 return Err(ErrorCode::InvalidParam);
             }
-
-            *task_cnt += 1;
-
-            // allocate TCB & stack statically
-            let (mut task, stack_ptr) = unsafe { Task::alloc_static(tid) };
+            let (tid, mut task, stack, reused) = unsafe { alloc_task(stack_size, j)? };
             // init TCB & stack
             let task = unsafe { task.as_mut_no_logging() };
-            Task::initialize_task(task, tid, stack_ptr, name, prio, func, param);
-            if let Err(_) = unsafe { create_per_task_pm_heap(&mut task.pm_heap, pmem_heap_sz, j) } {
+            Task::initialize_task(task, tid, stack, name, prio, func, param, reused, j);
+            #[cfg(test)]
+            if unsafe { CRASH_IN_CREATE } {
+                panic!("power failure");
+            }
+            if let Err(_) = unsafe { task.init_pm_heap(pmem_heap_sz, reused, j) } {
                 os_print!("No Space for per task PM Heap");
             }
             unsafe {
@@ -868,7 +1461,7 @@ pub fn create_task_static(
             }
 
             task.add_to_ready_list(j, cs);
-            Ok(TaskHandle(tid))
+            Ok(TaskHandle::of(task))
         })
     })
 }
@@ -880,31 +1473,24 @@ pub fn create_task_static(
     func: usize,
     param: usize,
     pmem_heap_sz: usize,
+    stack_size: usize,
 ) -> Result<TaskHandle, ErrorCode> {
     // TODO:  critical section can be shorter
     critical::with_no_interrupt(|cs| {
         let r = transaction::run(|j| {
-            let task_cnt = unsafe { TASK_CNT.borrow_mut(j) };
-            let tid = *task_cnt;
-
-            if tid >= TASK_NUM_LIMIT {
-                os_print!("Maximun number of tasks reached, current number: {}", tid);
-                return Err(ErrorCode::NoSpace);
-            }
-
             if prio >= MIN_PRIORITY {
                 os_print!("Invalid priority for task: {}", name);
                 return Err(ErrorCode::InvalidParam);
             }
-
-            *task_cnt += 1;
-
-            // allocate TCB & stack statically
-            let (mut task, stack_ptr) = unsafe { Task::alloc_static(tid) };
+            let (tid, mut task, stack, reused) = unsafe { alloc_task(stack_size, j)? };
             // init TCB & stack
             let task = unsafe { task.as_mut_no_logging() };
-            Task::initialize_task(task, tid, stack_ptr, name, prio, func, param);
-            if let Err(_) = unsafe { create_per_task_pm_heap(&mut task.pm_heap, pmem_heap_sz, j) } {
+            Task::initialize_task(task, tid, stack, name, prio, func, param, reused, j);
+            #[cfg(test)]
+            if unsafe { CRASH_IN_CREATE } {
+                panic!("power failure");
+            }
+            if let Err(_) = unsafe { task.init_pm_heap(pmem_heap_sz, reused, j) } {
                 os_print!("No Space for per task PM Heap");
             }
             unsafe {
@@ -915,7 +1501,7 @@ pub fn create_task_static(
                     }
                 }
             }
-            Ok(TaskHandle(tid))
+            Ok(TaskHandle::of(task))
         });
         match r {
             Ok(handle) => {
@@ -924,12 +1510,8 @@ pub fn create_task_static(
                 // let task = unsafe {TASK_ARRAY[handle.0].unwrap_unchecked().as_mut_no_logging()};
                 // os_print!("tid = {}", handle.0);
 
-                let task = unsafe {
-                    TASK_ARRAY
-                        .get_unchecked_mut(handle.0)
-                        .unwrap_unchecked()
-                        .as_mut_no_logging()
-                };
+                let task =
+                    unsafe { handle.get_task_ptr().unwrap_unchecked().as_mut_no_logging() };
                 list::atomic_roll_forward_insert_into_readylist(task, cs);
             }
             _ => {}
@@ -938,6 +1520,459 @@ pub fn create_task_static(
     })
 }
 
+// Returns the task id, the TCB, the stack and whether the TCB is reused
+unsafe fn alloc_task(
+    stack_size: usize,
+    j: JournalHandle,
+) -> Result<(usize, PMPtr<Task>, (usize, usize), bool), ErrorCode> {
+    let task_cnt = TASK_CNT.borrow_mut(j);
+    let tid = match free_task_slot(*task_cnt) {
+        Some(tid) => tid,
+        #[cfg(feature = "dynamic_tasks")]
+        None => return Task::alloc_dynamic(stack_size, j),
+        #[cfg(not(feature = "dynamic_tasks"))]
+        None => {
+            os_print!("Maximun number of tasks reached, current number: {}", *task_cnt);
+            return Err(ErrorCode::NoSpace);
+        }
+    };
+    let stack = Task::alloc_stack(tid, stack_size, j)?;
+    let reused = tid < *task_cnt;
+    if !reused {
+        *task_cnt += 1;
+    }
+    // allocate TCB statically
+    Ok((tid, Task::alloc_static(tid, j), stack, reused))
+}
+
+// Slots below the task count are only free after their task got deleted
+fn free_task_slot(task_cnt: usize) -> Option<usize> {
+    match (0..task_cnt).find(|&i| task_by_id(i).is_none()) {
+        Some(tid) => Some(tid),
+        None if task_cnt < TASK_NUM_LIMIT => Some(task_cnt),
+        None => None,
+    }
+}
+
+// The idle task can't be deleted or suspended
+fn handle_to_task(handle: TaskHandle) -> Result<&'static mut Task, ErrorCode> {
+    let mut task_ptr = match handle.get_task_ptr() {
+        None => return Err(ErrorCode::InvalidParam),
+        Some(ptr) => ptr,
+    };
+    let task = unsafe { task_ptr.as_mut_no_logging() };
+    if task.is_idle() {
+        return Err(ErrorCode::InvalidParam);
+    }
+    Ok(task)
+}
+
+fn deletable_task(handle: TaskHandle) -> Result<&'static mut Task, ErrorCode> {
+    let task = handle_to_task(handle)?;
+    // a task deletes itself through task_exit
+    if task.task_id == current().task_id {
+        return Err(ErrorCode::InvalidParam);
+    }
+    Ok(task)
+}
+
+#[cfg(not(feature = "opt_list"))]
+fn unlink_task(task: &mut Task, j: JournalHandle, cs: &CriticalSection) {
+    // ready list or delayed list
+    let sched_node_ptr = task.get_sched_node_ptr();
+    if let Some(mut list) = sched_node_ptr.as_ref().list {
+        list.as_mut(j).remove(cs, j, sched_node_ptr);
+        sync_ready_level(task.priority.get_value(), j);
+    }
+    // wait list
+    let event_node_ptr = task.get_event_node_ptr();
+    if let Some(mut list) = event_node_ptr.as_ref().list {
+        list.as_mut(j).remove(cs, j, event_node_ptr);
+    }
+}
+
+#[cfg(not(feature = "opt_list"))]
+fn remove_task(task: &mut Task, j: JournalHandle, cs: &CriticalSection) {
+    semaphore::abandon_mutexes(task, j, cs);
+    rwlock::abandon_rwlocks(task, j, cs);
+    unlink_task(task, j, cs);
+    task.release(j);
+}
+
+#[cfg(not(feature = "opt_list"))]
+pub fn delete_task(handle: TaskHandle) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        transaction::run(move |j| {
+            let task = deletable_task(handle)?;
+            remove_task(task, j, cs);
+            Ok(())
+        })
+    })
+}
+
+#[cfg(feature = "opt_list")]
+pub fn delete_task(handle: TaskHandle) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        // the deletion was rolled forward by the recovery
+        if current().list_transaction_done() {
+            return Ok(());
+        }
+        let task = deletable_task(handle)?;
+        semaphore::abandon_mutexes(task, cs);
+        rwlock::abandon_rwlocks(task, cs);
+        list::atomic_roll_forward_remove_task(task, cs);
+        Ok(())
+    })
+}
+
+// A suspended task leaves every list, a pending wait is given up as if it
+// timed out once the task is resumed
+#[cfg(not(feature = "opt_list"))]
+pub fn suspend_task(handle: TaskHandle) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        transaction::run(move |j| {
+            let task = handle_to_task(handle)?;
+            if !task.is_suspended() {
+                unlink_task(task, j, cs);
+                task.set_status(TaskState::Suspended, j);
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(feature = "opt_list")]
+pub fn suspend_task(handle: TaskHandle) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        if current().list_transaction_done() {
+            return Ok(());
+        }
+        let task = handle_to_task(handle)?;
+        if !task.is_suspended() {
+            list::atomic_roll_forward_suspend_task(task, cs);
+        }
+        Ok(())
+    })
+}
+
+#[cfg(not(feature = "opt_list"))]
+pub fn resume_task(handle: TaskHandle) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        transaction::run(move |j| {
+            let task = handle_to_task(handle)?;
+            if task.is_suspended() {
+                task.add_to_ready_list(j, cs);
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(feature = "opt_list")]
+pub fn resume_task(handle: TaskHandle) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        if current().list_transaction_done() {
+            return Ok(());
+        }
+        let task = handle_to_task(handle)?;
+        if task.is_suspended() {
+            list::atomic_roll_forward_resume_task(task, cs);
+        }
+        Ok(())
+    })
+}
+
+// Returns whether the task is waiting for the notification
+fn update_notification(
+    task: &mut Task,
+    value: usize,
+    action: NotifyAction,
+    j: JournalHandle,
+) -> Result<bool, ErrorCode> {
+    let new_value = match action {
+        NotifyAction::NoAction => task.notify_value,
+        NotifyAction::SetBits => task.notify_value | value,
+        NotifyAction::Increment => task.notify_value.wrapping_add(1),
+        NotifyAction::SetValueWithOverwrite => value,
+        NotifyAction::SetValueWithoutOverwrite => {
+            if task.notify_state == NotifyState::Received {
+                return Err(ErrorCode::NoSpace);
+            }
+            value
+        }
+    };
+    let waiting = task.notify_state == NotifyState::Waiting;
+    task.set_notification(new_value, NotifyState::Received, j);
+    Ok(waiting)
+}
+
+#[cfg(not(feature = "opt_list"))]
+pub fn notify_task(
+    handle: TaskHandle,
+    value: usize,
+    action: NotifyAction,
+) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        transaction::run(move |j| {
+            let task = handle_to_task(handle)?;
+            let waiting = update_notification(task, value, action, j)?;
+            // a suspended task finds the notification once it's resumed
+            if waiting && task.get_status() == TaskState::Blocked {
+                let n = task.remove_from_delayed_list(j, cs);
+                task.add_node_to_ready_list(n, j, cs);
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(feature = "opt_list")]
+pub fn notify_task(
+    handle: TaskHandle,
+    value: usize,
+    action: NotifyAction,
+) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        if current().list_transaction_done() {
+            return Ok(());
+        }
+        let waiting = transaction::run(move |j| {
+            let task = handle_to_task(handle)?;
+            update_notification(task, value, action, j)
+        })?;
+        let task = handle_to_task(handle)?;
+        // a suspended task finds the notification once it's resumed
+        if waiting && task.get_status() == TaskState::Blocked {
+            list::atomic_roll_forward_wake_notified_task(task, cs);
+        }
+        Ok(())
+    })
+}
+
+// Ok with the notification value, or Err with it on timeout
+pub fn notify_wait(
+    clear_on_entry: usize,
+    clear_on_exit: usize,
+    ticks: Time,
+) -> Result<usize, usize> {
+    let wait = critical::with_no_interrupt(|cs| {
+        let wait = transaction::run(move |j| {
+            let task = current();
+            if task.notify_state == NotifyState::Received || ticks == 0 {
+                return false;
+            }
+            let value = task.notify_value & !clear_on_entry;
+            task.set_notification(value, NotifyState::Waiting, j);
+            true
+        });
+        // no notifier can run before the task is in the delayed list
+        if wait {
+            task_delay(ticks, false);
+        }
+        wait
+    });
+    if wait {
+        task_yield();
+    }
+    transaction::run(move |j| {
+        let task = current();
+        let value = task.notify_value;
+        if task.notify_state == NotifyState::Received {
+            task.set_notification(value & !clear_on_exit, NotifyState::NotWaiting, j);
+            Ok(value)
+        } else {
+            task.set_notification(value, NotifyState::NotWaiting, j);
+            Err(value)
+        }
+    })
+}
+
+pub fn get_stack_high_water_mark(handle: TaskHandle) -> Result<usize, ErrorCode> {
+    match handle.get_task_ptr() {
+        None => Err(ErrorCode::InvalidParam),
+        Some(ptr) => Ok(ptr.as_ref().stack_high_water_mark()),
+    }
+}
+
+pub fn get_task_priority(handle: TaskHandle) -> Result<usize, ErrorCode> {
+    match handle.get_task_ptr() {
+        None => Err(ErrorCode::InvalidParam),
+        Some(ptr) => Ok(ptr.as_ref().get_priority().get_value()),
+    }
+}
+
+// The deadline is `ticks` from now, `None` makes the task a background one
+pub fn set_task_deadline(handle: TaskHandle, ticks: Option<Time>) -> Result<(), ErrorCode> {
+    transaction::run(move |j| {
+        let task = handle_to_task(handle)?;
+        task.set_deadline(ticks, TIME_MANAGER.get_ticks(), j);
+        Ok(())
+    })
+}
+
+fn check_priority(prio: usize) -> Result<Priority, ErrorCode> {
+    if prio >= MIN_PRIORITY {
+        return Err(ErrorCode::InvalidParam);
+    }
+    Ok(Priority::new(prio))
+}
+
+// The priority a task runs at once its base priority is set to `prio`, an
+// inherited priority stays if it's higher
+fn target_priority(task: &Task, prio: Priority) -> Priority {
+    if task.is_priority_inherited() && task.priority.is_higher_than(&prio) {
+        task.priority
+    } else {
+        prio
+    }
+}
+
+#[cfg(not(feature = "opt_list"))]
+pub fn change_task_priority(
+    task: &mut Task,
+    prio: Priority,
+    j: JournalHandle,
+    cs: &CriticalSection,
+) {
+    if task.priority == prio {
+        return;
+    }
+    let status = task.get_status();
+    // a delayed task stays in the delayed list
+    let in_ready_list = task.is_schedulable() && task.sched_node.list.is_some();
+    if in_ready_list {
+        task.remove_from_ready_list(j, cs);
+    }
+    let wait_list = task.event_node.list;
+    if let Some(mut list) = wait_list {
+        list.as_mut(j).remove(cs, j, task.get_event_node_ptr());
+    }
+    task.set_priority(prio, j);
+    if in_ready_list {
+        task.add_to_ready_list(j, cs);
+        task.set_status(status, j);
+    }
+    // wait lists are ordered by priority
+    if let Some(mut list) = wait_list {
+        list.as_mut(j).insert_sorted(cs, j, task.get_event_node_ptr());
+    }
+}
+
+#[cfg(not(feature = "opt_list"))]
+pub fn set_task_priority(handle: TaskHandle, prio: usize) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        transaction::run(move |j| {
+            let task = handle_to_task(handle)?;
+            let prio = check_priority(prio)?;
+            let target = target_priority(task, prio);
+            task.set_base_priority(prio, j);
+            change_task_priority(task, target, j, cs);
+            if !unsafe { is_scheduler_started() } {
+                update_boot_task(j);
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(feature = "opt_list")]
+pub fn set_task_priority(handle: TaskHandle, prio: usize) -> Result<(), ErrorCode> {
+    critical::with_no_interrupt(|cs| {
+        if current().list_transaction_done() {
+            return Ok(());
+        }
+        let task = handle_to_task(handle)?;
+        let prio = check_priority(prio)?;
+        let target = target_priority(task, prio);
+        if task.priority != target {
+            // the base priority and the boot task are set by the roll forward
+            list::atomic_roll_forward_change_priority(task, target, cs);
+        } else {
+            task.set_base_priority(prio, unsafe { JournalHandle::new_dummy() });
+        }
+        Ok(())
+    })
+}
+
+// Drop the priorities inherited by tasks which don't hold any mutex anymore
+#[cfg(feature = "opt_list")]
+pub fn recover_inherited_priorities() {
+    let cs = unsafe { CriticalSection::new() };
+    for mut ptr in iter_tasks() {
+        let task = unsafe { ptr.as_mut_no_logging() };
+        if task.mutexes_held == 0 && task.is_priority_inherited() {
+            debug_print!("Restoring the priority of task {}", task.name);
+            list::atomic_roll_forward_inherit_priority(task, task.base_priority, &cs);
+        }
+    }
+}
+
+// Before the scheduler starts the task of the highest priority runs first
+fn boot_task() -> (Option<PMPtr<Task>>, Priority) {
+    let mut max_prio = Priority::min_priority();
+    let mut boot_task = None;
+    for ptr in iter_tasks() {
+        let task = ptr.as_ref();
+        if task.priority.is_higher_than(&max_prio) {
+            max_prio = task.priority;
+            boot_task = Some(ptr);
+        }
+    }
+    (boot_task, max_prio)
+}
+
+#[cfg(not(feature = "opt_list"))]
+fn update_boot_task(j: JournalHandle) {
+    let (boot_task, max_prio) = boot_task();
+    unsafe {
+        if let Some(ptr) = boot_task {
+            CURRENT_TASK_PTR.store(ptr.as_ptr(), j);
+        }
+        *CUR_MAX_PRIORITY.borrow_mut(j) = max_prio;
+    }
+}
+
+// Part of the roll forward of a priority change, so it only recomputes and
+// can run again. Nothing to do once the scheduler runs, the recovery after
+// the first boot doesn't move the current task either.
+#[cfg(feature = "opt_list")]
+pub fn update_boot_task() {
+    if unsafe { is_scheduler_started() } || crate::recover::is_first_boot_done() {
+        return;
+    }
+    let (boot_task, max_prio) = boot_task();
+    unsafe {
+        if let Some(ptr) = boot_task {
+            CURRENT_TASK_PTR.store(ptr.as_ptr());
+        }
+        *CUR_MAX_PRIORITY.borrow_mut_no_logging() = max_prio;
+    }
+}
+
+// Whether a ready task outranks the current one
+pub fn need_reschedule() -> bool {
+    let cs = unsafe { CriticalSection::new() };
+    let cur = current();
+    !cur.is_schedulable()
+        || Scheduler::pick_next(&cs).map_or(false, |(_, next)| {
+            next.as_ptr() != cur as *mut Task && Scheduler::preempts(next.as_ref(), cur)
+        })
+}
+
+// The current task is killed, it runs on until it yields
+pub fn task_exit() {
+    critical::with_no_interrupt(|cs| {
+        #[cfg(not(feature = "opt_list"))]
+        transaction::run(|j| remove_task(current(), j, cs));
+        #[cfg(feature = "opt_list")]
+        {
+            semaphore::abandon_mutexes(current(), cs);
+            rwlock::abandon_rwlocks(current(), cs);
+            list::atomic_roll_forward_remove_task(current(), cs);
+        }
+    });
+}
+
 pub fn register_app_custom<T: Send + 'static>(
     name: &'static str,
     prio: usize,
@@ -949,7 +1984,14 @@ pub fn register_app_custom<T: Send + 'static>(
     let param_usize = unsafe { core::mem::transmute_copy::<T, usize>(&param) };
     // We should not run task's param destructor
     core::mem::forget(param);
-    match create_task_static(name, prio, func as usize, param_usize, pm_heap_sz) {
+    match create_task_static(
+        name,
+        prio,
+        func as usize,
+        param_usize,
+        pm_heap_sz,
+        DEFAULT_STACK_SIZE,
+    ) {
         Ok(_) => {
             #[cfg(feature = "verbose_os_info")]
             os_print!("Task {} created", name);
@@ -971,7 +2013,7 @@ pub fn register_app_no_param_custom(
     func: fn(),
     pm_heap_sz: usize,
 ) {
-    match create_task_static(name, prio, func as usize, 0, pm_heap_sz) {
+    match create_task_static(name, prio, func as usize, 0, pm_heap_sz, DEFAULT_STACK_SIZE) {
         Ok(_) => {
             os_print!("Task {} created", name);
         }
@@ -985,6 +2027,72 @@ pub fn register_app_no_param(name: &'static str, prio: usize, func: fn()) {
     register_app_no_param_custom(name, prio, func, heap::PM_HEAP_SIZE_PER_TASK);
 }
 
+// Hooks run by the idle task before it sleeps. They must not block, and they
+// may run again after a power failure.
+pub fn register_idle_hook(hook: fn()) {
+    let r = transaction::run(|j| {
+        let hooks = unsafe { IDLE_HOOKS.borrow_mut(j) };
+        if hooks.iter().any(|h| h.map(|f| f as usize) == Some(hook as usize)) {
+            return Ok(());
+        }
+        match hooks.iter_mut().find(|h| h.is_none()) {
+            Some(slot) => {
+                *slot = Some(hook);
+                Ok(())
+            }
+            None => Err(ErrorCode::NoSpace),
+        }
+    });
+    if let Err(e) = r {
+        os_print!("Failed to register idle hook with code {:?}", e);
+    }
+}
+
+#[cfg(feature = "jit_checkpoint")]
+pub fn register_checkpointed_app<T: Send + 'static>(
+    name: &'static str,
+    prio: usize,
+    func: fn(T),
+    param: T,
+) {
+    assert!(core::mem::size_of::<T>() == core::mem::size_of::<usize>());
+    let param_usize = unsafe { core::mem::transmute_copy::<T, usize>(&param) };
+    core::mem::forget(param);
+    let r = create_task_static(
+        name,
+        prio,
+        func as usize,
+        param_usize,
+        heap::PM_HEAP_SIZE_PER_TASK,
+        DEFAULT_STACK_SIZE,
+    )
+    .and_then(|handle| set_task_exec_mode(handle, ExecMode::Checkpoint));
+    match r {
+        Ok(_) => {
+            #[cfg(feature = "verbose_os_info")]
+            os_print!("Task {} created in checkpoint mode", name);
+        }
+        Err(e) => {
+            #[cfg(feature = "verbose_os_info")]
+            os_print!("Failed to create task {}  with code {:?}", name, e);
+        }
+    }
+}
+
+#[cfg(feature = "jit_checkpoint")]
+pub fn set_task_exec_mode(handle: TaskHandle, mode: ExecMode) -> Result<(), ErrorCode> {
+    let task_ptr = match handle.get_task_ptr() {
+        None => return Err(ErrorCode::InvalidParam),
+        Some(ptr) => ptr,
+    };
+    transaction::run(move |j| {
+        let mut task_ptr = task_ptr;
+        let task = unsafe { task_ptr.as_mut_no_logging() };
+        task.set_exec_mode(mode, j);
+    });
+    Ok(())
+}
+
 pub fn create_task_static_with_closure<F>(
     name: &'static str,
     prio: usize,
@@ -1001,11 +2109,50 @@ where
         closure_task_runner::<F> as usize,
         param,
         pm_heap_sz,
+        DEFAULT_STACK_SIZE,
     )
 }
 
 pub fn idle_fn() {
-    loop {}
+    loop {
+        run_idle_hooks();
+        idle_sleep();
+    }
+}
+
+pub fn run_idle_hooks() {
+    let hooks = unsafe { *IDLE_HOOKS };
+    for hook in hooks.iter().flatten() {
+        hook();
+    }
+}
+
+// Busy idle unless tickless idle is on
+#[inline(always)]
+fn idle_sleep() {
+    // the injected power failures are driven by the tick
+    #[cfg(all(feature = "tickless_idle", not(feature = "power_failure")))]
+    arch::arch_suppress_ticks_and_sleep();
+    #[cfg(all(feature = "tickless_idle", feature = "power_failure"))]
+    arch::arch_sleep();
+}
+
+// Ticks before a delayed task or a timer is due, 0 if some task can run now.
+// Called with interrupts disabled.
+#[cfg(feature = "tickless_idle")]
+pub fn expected_idle_ticks() -> Time {
+    let cs = unsafe { CriticalSection::new() };
+    let idle_prio = MIN_PRIORITY - 1;
+    let others_ready = (0..idle_prio).any(|i| unsafe { TASK_LISTS[i].peek_next(&cs).is_some() })
+        || unsafe { TASK_LISTS[idle_prio].length() } > 1;
+    if others_ready {
+        return 0;
+    }
+    let next = core::cmp::min(
+        TIME_MANAGER.get_next_unblock_time(),
+        TIME_MANAGER.get_next_expiry_time(),
+    );
+    next.saturating_sub(TIME_MANAGER.get_ticks())
 }
 
 pub fn closure_task_runner<F>(f: &mut F)
@@ -1018,7 +2165,14 @@ where
 }
 
 pub fn create_idle_task() {
-    let res = create_task_static("idle", MIN_PRIORITY - 1, idle_fn as usize, 0, 0);
+    let res = create_task_static(
+        "idle",
+        MIN_PRIORITY - 1,
+        idle_fn as usize,
+        0,
+        0,
+        DEFAULT_STACK_SIZE,
+    );
     match res {
         Err(_) => {
             #[cfg(feature = "verbose_os_info")]
@@ -1032,11 +2186,37 @@ pub fn create_idle_task() {
     }
 }
 
+// Move a blocked task to the ready list from an interrupt handler, which has
+// no transaction of its own
+#[cfg(not(feature = "opt_list"))]
+pub fn wake_task_from_isr(task: &mut Task, cs: &CriticalSection) {
+    if task.get_status() != TaskState::Blocked {
+        return;
+    }
+    let task_ptr = task.as_pm_ptr();
+    transaction::run_no_ctx(move |j| {
+        let task = task_ptr.as_ref();
+        let sched_node = task.remove_from_delayed_list(j, cs);
+        task.event_node.list.map(|mut wait_list| {
+            let wait_list = wait_list.as_mut(j);
+            wait_list.remove(cs, j, task.get_event_node_ptr());
+        });
+        task.add_node_to_ready_list(sched_node, j, cs);
+    });
+}
+
+#[cfg(feature = "opt_list")]
+pub fn wake_task_from_isr(task: &mut Task, cs: &CriticalSection) {
+    if task.get_status() == TaskState::Blocked {
+        list::atomic_roll_forward_isr_wakeup(task, cs);
+    }
+}
+
 #[cfg(feature = "opt_list")]
 #[export_name = "process_tick"]
 pub fn process_tick() {
     critical::with_no_interrupt(|cs| {
-        let cur_ticks = TIME_MANAGER.inc_tick(1);
+        let cur_ticks = TIME_MANAGER.inc_tick(1 + TIME_MANAGER.take_suppressed_ticks());
         let next_unblock_time = TIME_MANAGER.get_next_unblock_time();
 
         #[cfg(not(feature = "power_failure"))]
@@ -1084,7 +2264,7 @@ pub fn process_tick() {
 #[export_name = "process_tick"]
 pub fn process_tick() {
     critical::with_no_interrupt(|cs| {
-        let cur_ticks = TIME_MANAGER.inc_tick(1);
+        let cur_ticks = TIME_MANAGER.inc_tick(1 + TIME_MANAGER.take_suppressed_ticks());
         // let msp: usize;
         // unsafe {
         //     asm!(
@@ -1142,6 +2322,7 @@ pub fn process_tick() {
                                     .borrow_mut(j)
                                     .insert_node(cs, j, old_head);
                             }
+                            sync_ready_level(task.priority.get_value(), j);
                             //debug_print!("Updating status...");
                             // update task status
                             task.set_status(TaskState::Ready, j);
@@ -1171,13 +2352,20 @@ pub fn task_yield() {
     arch::arch_yield();
 }
 
+#[cfg(feature = "opt_list")]
+fn block_until(wakeup_time: Time, cs: &CriticalSection) {
+    if current().get_deadline().is_some() {
+        transaction::run(move |j| current().advance_deadline(wakeup_time, j));
+    }
+    current().set_wakeup_time(wakeup_time);
+    list::atomic_roll_forward_insert_into_delaylist(cs);
+}
+
 #[cfg(feature = "opt_list")]
 pub fn task_delay(nticks: Time, yield_now: bool) {
     critical::with_no_interrupt(|cs| {
-        let task = current();
         let wakeup_time = TIME_MANAGER.get_ticks().checked_add(nticks).unwrap();
-        task.set_wakeup_time(wakeup_time);
-        list::atomic_roll_forward_insert_into_delaylist(cs);
+        block_until(wakeup_time, cs);
     });
     // yield immediately
     if yield_now {
@@ -1185,6 +2373,31 @@ pub fn task_delay(nticks: Time, yield_now: bool) {
     }
 }
 
+// A task which ran over its stack is removed as if it exited, the others keep
+// running. Nothing can take over from the idle task.
+fn fail_overflowed_task(task: &mut Task, cs: &CriticalSection) {
+    if task.is_killed() || !task.stack_overflowed() {
+        return;
+    }
+    if task.is_idle() {
+        panic!("Stack overflow in task {}", task.name);
+    }
+    os_print!("Stack overflow in task {}, killed", task.name);
+    #[cfg(not(feature = "opt_list"))]
+    {
+        let mut task_ptr = task.as_pm_ptr();
+        transaction::run_no_ctx(move |j| {
+            remove_task(unsafe { task_ptr.as_mut_no_logging() }, j, cs)
+        });
+    }
+    #[cfg(feature = "opt_list")]
+    {
+        semaphore::abandon_mutexes(task, cs);
+        rwlock::abandon_rwlocks(task, cs);
+        list::atomic_roll_forward_remove_task(task, cs);
+    }
+}
+
 #[cfg(feature = "opt_list")]
 #[export_name = "task_switch"]
 pub unsafe extern "C" fn task_switch() {
@@ -1194,42 +2407,36 @@ pub unsafe extern "C" fn task_switch() {
     let cs = CriticalSection::new();
     let j = JournalHandle::new_dummy();
     let mut ok = false;
+    #[cfg(feature = "jit_checkpoint")]
+    if checkpoint::is_checkpoint_requested() {
+        checkpoint::checkpoint_and_wait_for_power_failure(&cs);
+    }
 
     // For Timing purpose
     switch_out_task_update_stats(prev_task);
-    for i in 0..NUM_PRIORITY_LEVELS {
-        match TASK_LISTS[i].peek_next(&cs) {
-            None => continue,
-
-            Some(list_item) => {
-                let mut task_ptr = list_item.task;
-                ok = true;
-                // let task = task_ptr.as_ref();
-                // os_print!("Switching to task : {}, prio = {}", task.name, task.priority.get_value());
-                let task = task_ptr.as_mut_no_logging();
-                // For Timing purpose
-                switch_to_task_update_stats(task);
-
-                task.jit_recovery();
-
-                if task_ptr.as_ptr() != prev_task as *mut _ {
-                    //debug_print_no_header!("Ready to switch to task: {}", task.name);
-                    debug_assert!(task.is_schedulable());
-                    let readylist = unsafe { TASK_LISTS[i].borrow_mut_no_logging() };
-                    list::atomic_roll_foward_context_switch(
-                        task,
-                        prev_task,
-                        readylist,
-                        &mut CURRENT_TASK_PTR,
-                        j,
-                        &cs,
-                    );
-                } else {
-                    // debug_display_sched_list(i);
-                    debug_assert!(TASK_LISTS[i].length() == 1)
-                }
-                break;
-            }
+    fail_overflowed_task(prev_task, &cs);
+    if let Some((i, mut task_ptr)) = Scheduler::pick_next(&cs) {
+        ok = true;
+        // let task = task_ptr.as_ref();
+        // os_print!("Switching to task : {}, prio = {}", task.name, task.priority.get_value());
+        let task = task_ptr.as_mut_no_logging();
+        // For Timing purpose
+        switch_to_task_update_stats(task);
+
+        task.jit_recovery();
+
+        if task_ptr.as_ptr() != prev_task as *mut _ {
+            //debug_print_no_header!("Ready to switch to task: {}", task.name);
+            debug_assert!(task.is_schedulable());
+            let readylist = unsafe { TASK_LISTS[i].borrow_mut_no_logging() };
+            list::atomic_roll_foward_context_switch(
+                task,
+                prev_task,
+                readylist,
+                &mut CURRENT_TASK_PTR,
+                j,
+                &cs,
+            );
         }
     }
     ctx_switch_end_stat();
@@ -1238,19 +2445,25 @@ pub unsafe extern "C" fn task_switch() {
     }
 }
 
+#[cfg(not(feature = "opt_list"))]
+fn block_until(wakeup_time: Time, cs: &CriticalSection) {
+    transaction::run(|j| {
+        let task = current();
+        task.set_status(TaskState::Blocked, j);
+        task.set_wakeup_time(wakeup_time);
+        task.advance_deadline(wakeup_time, j);
+        // remove from the ready list
+        let n = task.remove_from_ready_list(j, cs);
+        // insert into delayed list
+        task.add_node_to_delayed_list(n, j, cs);
+    });
+}
+
 #[cfg(not(feature = "opt_list"))]
 pub fn task_delay(nticks: Time, yield_now: bool) {
     critical::with_no_interrupt(|cs| {
-        transaction::run(|j| {
-            let task = current();
-            let wakeup_time = TIME_MANAGER.get_ticks().checked_add(nticks).unwrap();
-            task.set_status(TaskState::Blocked, j);
-            task.set_wakeup_time(wakeup_time);
-            // remove from the ready list
-            let n = task.remove_from_ready_list(j, cs);
-            // insert into delayed list
-            task.add_node_to_delayed_list(n, j, cs);
-        });
+        let wakeup_time = TIME_MANAGER.get_ticks().checked_add(nticks).unwrap();
+        block_until(wakeup_time, cs);
     });
     // yield immediately
     if yield_now {
@@ -1258,54 +2471,112 @@ pub fn task_delay(nticks: Time, yield_now: bool) {
     }
 }
 
+// What a periodic task does about the periods that passed while it was late
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum MissedPeriodPolicy {
+    // resume on the next period, dropping the missed ones
+    Skip,
+    // run once for every missed period without waiting
+    CatchUp,
+    // like `Skip`, but the number of missed periods is returned as an error
+    Report,
+}
+
+// Start of the next period after `last_wake` and the number of periods missed
+pub fn next_period(
+    last_wake: Time,
+    period: Time,
+    now: Time,
+    policy: MissedPeriodPolicy,
+) -> (Time, Time) {
+    let next = last_wake.checked_add(period).unwrap();
+    if next >= now {
+        return (next, 0);
+    }
+    let missed = (now - next) / period;
+    match policy {
+        MissedPeriodPolicy::CatchUp => (next, missed),
+        MissedPeriodPolicy::Skip | MissedPeriodPolicy::Report => (next + missed * period, missed),
+    }
+}
+
+// `last_wake` must live in PM. The wake tick is computed in a cached
+// transaction and `last_wake` is only advanced after the wakeup, so a call
+// cut by a power failure sleeps until the same tick when it's re-executed.
+// A call which already returned is only bypassed inside a user transaction,
+// outside of one its re-execution waits for the following period.
+pub fn task_delay_until(
+    mut last_wake: PMPtr<Time>,
+    period: Time,
+    policy: MissedPeriodPolicy,
+) -> Result<(), Time> {
+    assert!(period > 0);
+    let (next, missed, delayed) = critical::with_no_interrupt(|cs| {
+        let (next, missed, delayed) = transaction::run(move |_| {
+            let now = TIME_MANAGER.get_ticks();
+            let (next, missed) = next_period(*last_wake.as_ref(), period, now, policy);
+            (next, missed, next > now)
+        });
+        if delayed {
+            block_until(next, cs);
+        }
+        (next, missed, delayed)
+    });
+    // a replayed call may find itself woken already
+    if delayed && current().get_status() == TaskState::Blocked {
+        task_yield();
+    }
+    transaction::run(move |j| *last_wake.as_mut(j) = next);
+    if missed > 0 && policy == MissedPeriodPolicy::Report {
+        return Err(missed);
+    }
+    Ok(())
+}
+
 #[cfg(not(feature = "opt_list"))]
 #[export_name = "task_switch"]
 pub unsafe extern "C" fn task_switch() {
     use crate::recover::{finish_ctx_switch_tx, start_ctx_switch_tx};
 
     ctx_switch_start_stat();
-    start_ctx_switch_tx();
     let prev_task = CURRENT_TASK_PTR.load().as_mut().unwrap();
     // critical section token
     let cs = CriticalSection::new();
     let mut ok = false;
+    #[cfg(feature = "jit_checkpoint")]
+    if checkpoint::is_checkpoint_requested() {
+        checkpoint::checkpoint_and_wait_for_power_failure(&cs);
+    }
+    // runs its own transaction
+    fail_overflowed_task(prev_task, &cs);
+    start_ctx_switch_tx();
 
     // For Timing purpose
     switch_out_task_update_stats(prev_task);
 
-    for i in 0..NUM_PRIORITY_LEVELS {
-        match TASK_LISTS[i].peek_next(&cs) {
-            None => continue,
-
-            Some(list_item) => {
-                let mut task_ptr = list_item.task;
-                ok = true;
-                // let task = task_ptr.as_mut();
-                // os_print!("task : {}", task.name).unwrap();
-                let task = task_ptr.as_mut_no_logging();
-                // For Timing purpose
-                switch_to_task_update_stats(task);
-                task.jit_recovery();
-                if task_ptr.as_ptr() != prev_task as *mut _ {
-                    //debug_print_no_header!("Ready to switch to task: {}", task.name);
-                    transaction::run_no_ctx(|j| {
-                        // os_print!("switching to task : {}", task.name).unwrap();
-                        // debug_display_sched_list(i);
-                        debug_assert!(task.is_schedulable());
-                        task.set_status(TaskState::Running, j);
-                        if prev_task.get_status() != TaskState::Blocked {
-                            prev_task.set_status(TaskState::Ready, j);
-                        }
-                        // move the cursor to next task ready to run
-                        TASK_LISTS[i].borrow_mut(j).next(&cs);
-                        CURRENT_TASK_PTR.store(task_ptr.as_ptr(), j);
-                    });
-                } else {
-                    // debug_display_sched_list(i);
-                    debug_assert!(TASK_LISTS[i].length() == 1)
+    if let Some((i, mut task_ptr)) = Scheduler::pick_next(&cs) {
+        ok = true;
+        // let task = task_ptr.as_mut();
+        // os_print!("task : {}", task.name).unwrap();
+        let task = task_ptr.as_mut_no_logging();
+        // For Timing purpose
+        switch_to_task_update_stats(task);
+        task.jit_recovery();
+        if task_ptr.as_ptr() != prev_task as *mut _ {
+            //debug_print_no_header!("Ready to switch to task: {}", task.name);
+            transaction::run_no_ctx(|j| {
+                // os_print!("switching to task : {}", task.name).unwrap();
+                // debug_display_sched_list(i);
+                debug_assert!(task.is_schedulable());
+                task.set_status(TaskState::Running, j);
+                if prev_task.is_running() {
+                    prev_task.set_status(TaskState::Ready, j);
                 }
-                break;
-            }
+                // the cursor of the ready list points to the running task
+                let node = task.get_sched_node_ptr();
+                TASK_LISTS[i].borrow_mut(j).move_cursor_to(node, &cs);
+                CURRENT_TASK_PTR.store(task_ptr.as_ptr(), j);
+            });
         }
     }
     finish_ctx_switch_tx();
@@ -1343,6 +2614,13 @@ pub fn mock_task_switch() {
         task_switch();
     }
 }
+
+// Run the function of `task` on the host stack, as the first switch to it would
+#[cfg(test)]
+pub fn mock_task_entry(task: &Task) {
+    let func: fn(usize) = unsafe { mem::transmute(task.task_func) };
+    func(task.param);
+}
 // Timing related metadata and functions
 
 #[derive(Clone, Copy)]
@@ -1476,7 +2754,11 @@ impl TaskStats {
     }
 }
 
+#[cfg(not(feature = "dynamic_tasks"))]
 const TASK_STATS_TABLE_SIZE: usize = 8;
+// dynamic tasks share the last entry
+#[cfg(feature = "dynamic_tasks")]
+const TASK_STATS_TABLE_SIZE: usize = TASK_NUM_LIMIT + 1;
 pub struct TaskStatsTable {
     table: [TaskStats; TASK_STATS_TABLE_SIZE],
 }
@@ -1489,7 +2771,7 @@ impl TaskStatsTable {
     }
 
     pub fn get_stats_of_task(&mut self, tid: usize) -> &mut TaskStats {
-        &mut self.table[tid]
+        &mut self.table[min(tid, TASK_STATS_TABLE_SIZE - 1)]
     }
 }
 
@@ -1633,8 +2915,7 @@ pub fn task_exit_kernel(task: &Task) {
 }
 
 pub fn task_get_stats(t: &mut Task) -> &mut TaskStats {
-    let tid = t.task_id;
-    unsafe { &mut TASK_STATS.table[tid] }
+    unsafe { TASK_STATS.get_stats_of_task(t.task_id) }
 }
 
 pub fn print_ctx_switch_stat() {
@@ -1651,34 +2932,49 @@ pub fn print_all_task_stats() {
     let mut log_sz = 0;
     let mut recovery = 0;
     let kern_recovery = get_kernel_recovery_time();
-    // skip idle task (id = 0), so we need to start from 1
-    for i in 1..TASK_NUM_LIMIT {
-        let task_ptr = unsafe { TASK_ARRAY[i] };
-        if !task_ptr.is_none() {
-            let task_ptr = task_ptr.unwrap();
-            let t = task_ptr.as_ref();
-
-            let stats = unsafe { &TASK_STATS.table[i] };
-            total += stats.total_run_time;
-            kern += stats.in_kernel_run_time;
-            user += stats.user_time;
-            recovery += stats.total_recovery_time;
-            pretty_print_task_stats(
-                t.get_name(),
-                stats.total_run_time,
-                stats.user_time,
-                stats.in_kernel_run_time,
-                stats.total_recovery_time,
-            );
-            // os_print!(
-            //     "[Stat] Count task: {}, total: {}, kern: {}, user: {}, recovery: {}",
-            //     t.get_name(),
-            //     stats.total_run_time,
-            //     stats.in_kernel_run_time,
-            //     stats.user_time,
-            //     stats.total_recovery_time
-            // );
+    // skip idle task (id = 0)
+    for task_ptr in iter_tasks().filter(|t| t.as_ref().task_id != 0) {
+        let t = task_ptr.as_ref();
+
+        let stats = unsafe { &*TASK_STATS.get_stats_of_task(t.task_id) };
+        total += stats.total_run_time;
+        kern += stats.in_kernel_run_time;
+        user += stats.user_time;
+        recovery += stats.total_recovery_time;
+        pretty_print_task_stats(
+            t.get_name(),
+            stats.total_run_time,
+            stats.user_time,
+            stats.in_kernel_run_time,
+            stats.total_recovery_time,
+        );
+        #[cfg(feature = "verbose_os_info")]
+        os_print!(
+            "[Stack] task: {}, size: {}, high water mark: {}",
+            t.get_name(),
+            t.stack_capacity(),
+            t.stack_high_water_mark()
+        );
+        #[cfg(feature = "crash_safe")]
+        {
+            let watchdog = t.get_user_tx_info().get_watchdog();
+            if watchdog.get_max_reexec_cnt() > 0 {
+                os_print!(
+                    "[Watchdog] task: {}, max re-execution: {}, threshold exceeded: {}",
+                    t.get_name(),
+                    watchdog.get_max_reexec_cnt(),
+                    watchdog.get_trips()
+                );
+            }
         }
+        // os_print!(
+        //     "[Stat] Count task: {}, total: {}, kern: {}, user: {}, recovery: {}",
+        //     t.get_name(),
+        //     stats.total_run_time,
+        //     stats.in_kernel_run_time,
+        //     stats.user_time,
+        //     stats.total_recovery_time
+        // );
     }
     // print_ctx_switch_stat();
     let total_recovery = recovery + kern_recovery;
@@ -1707,17 +3003,13 @@ pub fn print_all_task_tx_stats() {
     let mut max_tx_time = 0;
     let mut min_tx_time = 0xffffffff;
     let mut total_tx_time = 0;
-    for i in 1..TASK_NUM_LIMIT {
-        let task_ptr = unsafe { TASK_ARRAY[i] };
-        if !task_ptr.is_none() {
-            let task_ptr = task_ptr.unwrap();
-            let t = task_ptr.as_ref();
-            let stats = unsafe { &TASK_STATS.table[i] };
-            tx_cnt += stats.tx_stat.usr_tx_cnt;
-            total_tx_time += stats.tx_stat.usr_tx_time_total;
-            max_tx_time = max(stats.tx_stat.usr_tx_time_max, max_tx_time);
-            min_tx_time = min(stats.tx_stat.usr_tx_time_min, min_tx_time);
-        }
+    for task_ptr in iter_tasks().filter(|t| t.as_ref().task_id != 0) {
+        let t = task_ptr.as_ref();
+        let stats = unsafe { &*TASK_STATS.get_stats_of_task(t.task_id) };
+        tx_cnt += stats.tx_stat.usr_tx_cnt;
+        total_tx_time += stats.tx_stat.usr_tx_time_total;
+        max_tx_time = max(stats.tx_stat.usr_tx_time_max, max_tx_time);
+        min_tx_time = min(stats.tx_stat.usr_tx_time_min, min_tx_time);
     }
     let median = unsafe {
         let win = &mut USER_TX_TIME_WIN[0..USER_TX_CNT];
@@ -1729,15 +3021,11 @@ pub fn print_all_task_tx_stats() {
 }
 
 pub fn print_all_task_pm_usage() {
-    for i in 1..TASK_NUM_LIMIT {
-        let task_ptr = unsafe { TASK_ARRAY[i] };
-        if !task_ptr.is_none() {
-            let task_ptr = task_ptr.unwrap();
-            let t = task_ptr.as_ref();
-            let name = t.get_name();
-            let pm_used = t.get_pm_heap_stat().mem_used;
-            os_print!("[PM Usage] task: {}, PM used: {}", t.get_name(), pm_used);
-        }
+    for task_ptr in iter_tasks().filter(|t| t.as_ref().task_id != 0) {
+        let t = task_ptr.as_ref();
+        let name = t.get_name();
+        let pm_used = t.get_pm_heap_stat().mem_used;
+        os_print!("[PM Usage] task: {}, PM used: {}", t.get_name(), pm_used);
     }
 }
 
@@ -1750,6 +3038,19 @@ pub fn reset_static_vars() {
         *CUR_MAX_PRIORITY.borrow_mut_no_logging() = Priority::min_priority();
         TASK_LISTS = [PMVar::new(CircularPList::new()); NUM_PRIORITY_LEVELS];
         DELAYED_TASK_LIST = PMVar::new(SortedPList::new());
+        READY_BITMAP = [0; READY_BITMAP_WORDS];
+        TASK_STACKS = [(0, 0); TASK_NUM_LIMIT];
+        *STACK_ARENA_TOP.borrow_mut_no_logging() = 0;
+        #[cfg(feature = "dynamic_tasks")]
+        {
+            TASK_REGISTRY = None;
+            FREE_TASKS = None;
+            *NEXT_DYNAMIC_TID.borrow_mut_no_logging() = TASK_NUM_LIMIT;
+        }
+        #[cfg(not(feature = "dynamic_tasks"))]
+        {
+            TASK_ARRAY = [None; TASK_NUM_LIMIT];
+        }
     }
 }
 
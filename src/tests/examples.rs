@@ -116,7 +116,7 @@ pub fn task_timer() {
     let mut cnt = 0;
     let data61 = 61;
     let data62 = 62;
-    let callback = move |j| {
+    let callback = move |j: JournalHandle| {
         let v = data61 + data62;
         task_print!("Hello from Timer callback, Data is {}", v);
     };
@@ -318,10 +318,13 @@ pub fn task_test_closure() {
     tx::run_sys(|j, t| {
         let mut x = X { v: 1 };
         let mut y = X { v: 2 };
-        let _ = pthread::create("x", 1, t, move || {
+        let h = pthread::create("x", 1, t, move || {
             x.v += 1 + y.v;
             debug_print!("this is x = {}", x.v);
         });
+        if let Ok(h) = h {
+            pthread::detach(h, t);
+        }
     });
 }
 
@@ -10,8 +10,8 @@ use crate::list::{self, InsertSortedPList, ListTxOpLog};
 use crate::marker::TxInSafe;
 use crate::pmem::{JournalHandle, PMPtr, PMVar};
 use crate::queue::{queue_block_until_not_empty, queue_create, queue_send_back};
-use crate::syscalls::{sys_queue_receive, QueueHandle};
-use crate::task::{create_task_static, ErrorCode, Task};
+use crate::syscalls::{sys_event_group_apply_deferred, sys_queue_receive, QueueHandle};
+use crate::task::{create_task_static, ErrorCode, Task, DEFAULT_STACK_SIZE};
 use crate::user::pbox::RelaxedPBox;
 use crate::user::transaction as user_tx;
 use crate::util::cast_to_u8_ptr;
@@ -20,6 +20,8 @@ use crate::{heap, list::Node, queue::Queue};
 use core::cell::UnsafeCell;
 use core::mem::{size_of, transmute};
 pub const MAX_DELAY_TIME: Time = Time::MAX;
+// Not worth stopping the tick for a shorter idle period
+pub const MIN_SUPPRESSED_TICKS: Time = 2;
 
 const CMD_QUEUE_SIZE: usize = 32;
 
@@ -46,6 +48,8 @@ pub struct TimeManager {
     tick_counter: VolatileCell<Time>,
     next_unblock_time: VolatileCell<Time>,
     next_expiry_time: VolatileCell<Time>,
+    // ticks skipped by the idle task, accounted by the next tick
+    suppressed_ticks: VolatileCell<Time>,
     timer_cmd_queue: UnsafeCell<Option<PMPtr<Queue>>>,
     active_timer_list: UnsafeCell<PMVar<SortedPList<TimerListItem>>>,
     message_buf: UnsafeCell<TimerMessage>,
@@ -74,6 +78,7 @@ impl TimeManager {
             tick_counter: VolatileCell::new(0),
             next_unblock_time: VolatileCell::new(MAX_DELAY_TIME),
             next_expiry_time: VolatileCell::new(MAX_DELAY_TIME),
+            suppressed_ticks: VolatileCell::new(0),
             timer_cmd_queue: UnsafeCell::new(None),
             active_timer_list: unsafe { UnsafeCell::new(PMVar::new(SortedPList::new())) },
             message_buf: UnsafeCell::new(TimerMessage {
@@ -87,7 +92,7 @@ impl TimeManager {
 
     #[inline(always)]
     pub fn inc_tick(&self, x: Time) -> Time {
-        let v = self.tick_counter.get() + 1;
+        let v = self.tick_counter.get() + x;
         self.tick_counter.set(v);
         v
     }
@@ -97,6 +102,18 @@ impl TimeManager {
         self.tick_counter.get()
     }
 
+    #[inline(always)]
+    pub fn set_suppressed_ticks(&self, x: Time) {
+        self.suppressed_ticks.set(x);
+    }
+
+    #[inline(always)]
+    pub fn take_suppressed_ticks(&self) -> Time {
+        let x = self.suppressed_ticks.get();
+        self.suppressed_ticks.set(0);
+        x
+    }
+
     #[inline(always)]
     pub fn set_next_unblock_time(&self, x: Time) {
         self.next_unblock_time.set(x);
@@ -647,7 +664,8 @@ impl TimeManager {
             unsafe {
                 *(self.timer_cmd_queue.get()) = q;
             }
-            match create_task_static("timer daemon", 1, daemon_timer_task as usize, 0, 0) {
+            let daemon_fn = daemon_timer_task as usize;
+            match create_task_static("timer daemon", 1, daemon_fn, 0, 0, DEFAULT_STACK_SIZE) {
                 Err(e) => {
                     os_print!("Failed to create daemon timer task..., errcode = {:?}", e);
                 }
@@ -876,7 +894,8 @@ fn timer_control(
     };
 
     let buf_ptr = cast_to_u8_ptr(&msg);
-    if let Err(_) = queue_send_back(cmd_queue, buf_ptr, wait_ticks) {
+    let gen = cmd_queue.as_ref().get_generation();
+    if let Err(_) = queue_send_back(cmd_queue, gen, buf_ptr, wait_ticks) {
         Err(TimerErr::CmdQueueBusy)
     } else {
         Ok(())
@@ -913,11 +932,28 @@ pub fn get_time() -> Time {
     TIME_MANAGER.get_ticks()
 }
 
+// Apply the event group sets deferred by interrupt handlers
+fn process_deferred_event_sets() {
+    loop {
+        let res = user_tx::run_sys_once(|_, t| {
+            if sys_event_group_apply_deferred(t) {
+                Ok(())
+            } else {
+                Err(ErrorCode::TxExit)
+            }
+        });
+        if let Err(_) = res {
+            break;
+        }
+    }
+}
+
 pub fn daemon_timer_task() {
     // loop to execute timer cmds...
     #[cfg(any(bench_task = "sense", bench_task = "sense_base"))]
     crate::benchmarks::benchmark_start();
     loop {
+        process_deferred_event_sets();
         TIME_MANAGER.process_expired_timer();
         TIME_MANAGER.process_receive_timer_cmd();
     }
@@ -936,6 +972,7 @@ pub fn reset_timer_manager() {
     TIME_MANAGER.next_expiry_time.set(MAX_DELAY_TIME);
     TIME_MANAGER.next_unblock_time.set(MAX_DELAY_TIME);
     TIME_MANAGER.tick_counter.set(0);
+    TIME_MANAGER.suppressed_ticks.set(0);
     unsafe { *TIME_MANAGER.timer_cmd_queue.get() = None };
 }
 
@@ -1035,6 +1072,26 @@ mod test {
         }
     }
 
+    #[test]
+    #[cfg(feature = "tickless_idle")]
+    fn test_expected_idle_ticks_with_pending_timer() {
+        reset_timer_manager();
+        mock_boot_with_timer_daemon(0);
+        let mut t = new_timer(false, 7);
+        let t_ptr = unsafe { PMPtr::new(&t as *const Timer as *mut Timer) };
+        unsafe {
+            t.list_node.borrow_mut_no_logging().value.timer = Some(t_ptr);
+        }
+        list::atomic_roll_forward_remove_reinsert_into_activelist(t.get_list_node());
+        // the daemon sleeps until the timer is due, a yield can't return on the host
+        let sleep = || TIME_MANAGER.process_expired_timer();
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(sleep)).is_err());
+        assert_eq!(TIME_MANAGER.get_next_expiry_time(), 7);
+        assert_eq!(crate::task::expected_idle_ticks(), 7);
+        crate::task::process_tick();
+        assert_eq!(crate::task::expected_idle_ticks(), 6);
+    }
+
     #[test]
     fn test_crashed_process_timer_cmd() {
         fn run(cp0: usize, cp1: usize, cp2: usize, cp_syscall: usize, op: &str) {
@@ -4,14 +4,16 @@ use core::ptr::NonNull;
 use crate::arch::ARCH_ALIGN;
 use crate::marker::{TxInSafe, TxOutSafe};
 use crate::pmem::{Journal, JournalHandle, PMPtr};
-use crate::recover::{finish_ctx_switch_tx, get_boot_tx, in_ctx_switch_tx, start_ctx_switch_tx};
+use crate::recover::{
+    current_generation, finish_ctx_switch_tx, get_boot_tx, in_ctx_switch_tx, start_ctx_switch_tx,
+};
 use crate::syscalls::SyscallToken;
 use crate::task::{current, get_current_tx, is_scheduler_started, ErrorCode};
-use crate::util::debug_syscall_tx_cache;
+use crate::util::{compiler_pm_fence, debug_syscall_tx_cache};
 use crate::{arch, debug_print, os_print};
 
 pub struct Transaction {
-    journal: Option<JournalHandle>,
+    journal: Option<NonNull<Journal>>,
     nesting_level: usize,
     cache: Option<PMPtr<TxCache>>,
 }
@@ -24,7 +26,7 @@ pub fn get_addr<T>(obj: &T) -> usize {
 impl Transaction {
     pub fn new(j: JournalHandle, c: PMPtr<TxCache>) -> Self {
         Self {
-            journal: Some(j),
+            journal: unsafe { Some(NonNull::new_unchecked(j.as_ptr() as *mut Journal)) },
             nesting_level: 0,
             cache: Some(c),
         }
@@ -40,12 +42,20 @@ impl Transaction {
 
     #[inline(always)]
     pub fn set_journal(&mut self, j: &Journal) {
-        self.journal = unsafe { Some(JournalHandle::new(j as *const Journal)) };
+        self.journal = Some(NonNull::from(j));
     }
 
+    // Every call hands out a handle with a new brand, which the caller may
+    // pick. Only for code outside of any transaction, the closure of a
+    // transaction gets its handle from `run`.
     #[inline(always)]
-    pub fn get_journal(&mut self) -> JournalHandle {
-        unsafe { self.journal.unwrap_unchecked() }
+    pub unsafe fn get_journal<'id>(&mut self) -> JournalHandle<'id> {
+        unsafe { self.journal_handle() }
+    }
+
+    #[inline(always)]
+    unsafe fn journal_handle<'id>(&self) -> JournalHandle<'id> {
+        JournalHandle::new(self.journal.unwrap_unchecked().as_ptr())
     }
 
     #[inline(always)]
@@ -75,7 +85,7 @@ impl Transaction {
         unsafe {
             let tx_cache = self.cache.unwrap_unchecked().as_mut_no_logging();
             tx_cache.cache_result(res);
-            self.journal.unwrap_unchecked().get_mut().clear()
+            self.journal_handle().get_mut().clear()
         };
     }
 
@@ -84,13 +94,13 @@ impl Transaction {
         unsafe {
             let tx_cache = self.cache.unwrap_unchecked().as_mut_no_logging();
             tx_cache.commit_no_replay();
-            self.journal.unwrap_unchecked().get_mut().clear()
+            self.journal_handle().get_mut().clear()
         };
     }
 
     pub fn commit_no_replay_roll_forward(&mut self) {
         unsafe {
-            self.journal.unwrap_unchecked().get_mut().clear();
+            self.journal_handle().get_mut().clear();
             self.reset_nesting_level();
             let tx_cache = self.cache.unwrap_unchecked().as_mut_no_logging();
             tx_cache.commit_no_replay();
@@ -103,7 +113,7 @@ impl Transaction {
         if !cache.is_committed() {
             Err(())
         } else {
-            self.get_journal().get_mut().clear();
+            unsafe { self.get_journal() }.get_mut().clear();
             Ok(())
         }
     }
@@ -111,14 +121,14 @@ impl Transaction {
     pub fn roll_back_if_uncommitted(&mut self) {
         // roll back if uncommitted
         if let Err(_) = self.check_committed() {
-            self.get_journal().get_mut().recover();
+            unsafe { self.get_journal() }.get_mut().recover();
         }
     }
 
     #[inline(always)]
     pub fn roll_back(&mut self) {
         // roll back
-        self.get_journal().get_mut().recover();
+        unsafe { self.get_journal() }.get_mut().recover();
     }
 
     #[inline(always)]
@@ -162,7 +172,7 @@ impl Transaction {
         F: FnOnce(JournalHandle) -> Result<T, ErrorCode>,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() });
+        let ret = f(unsafe { self.journal_handle() });
         match ret {
             Err(ErrorCode::TxRetry) => {
                 self.commit_no_replay();
@@ -180,7 +190,7 @@ impl Transaction {
         F: FnOnce(JournalHandle) -> T,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() });
+        let ret = f(unsafe { self.journal_handle() });
         self.commit(&ret);
         ret
     }
@@ -191,7 +201,7 @@ impl Transaction {
         F: FnOnce(JournalHandle, SyscallToken) -> T,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() }, unsafe {
+        let ret = f(unsafe { self.journal_handle() }, unsafe {
             SyscallToken::new()
         });
         self.commit(&ret);
@@ -232,7 +242,7 @@ impl Transaction {
         F: FnOnce(JournalHandle, SyscallToken) -> T,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() }, unsafe {
+        let ret = f(unsafe { self.journal_handle() }, unsafe {
             SyscallToken::new()
         });
         self.commit_no_replay();
@@ -245,7 +255,7 @@ impl Transaction {
         F: FnOnce(JournalHandle) -> T,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() });
+        let ret = f(unsafe { self.journal_handle() });
         self.commit_no_replay();
         ret
     }
@@ -255,7 +265,7 @@ impl Transaction {
     where
         F: FnOnce(JournalHandle) -> T,
     {
-        let ret = f(unsafe { self.journal.unwrap_unchecked() });
+        let ret = f(unsafe { self.journal_handle() });
         ret
     }
 
@@ -267,7 +277,7 @@ impl Transaction {
         F: FnOnce(JournalHandle, SyscallToken) -> T,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() }, unsafe {
+        let ret = f(unsafe { self.journal_handle() }, unsafe {
             SyscallToken::new()
         });
         if !crash {
@@ -282,7 +292,7 @@ impl Transaction {
         F: FnOnce(JournalHandle) -> T,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() });
+        let ret = f(unsafe { self.journal_handle() });
         if !crash {
             self.commit(&ret);
         }
@@ -295,7 +305,7 @@ impl Transaction {
         F: FnOnce(JournalHandle) -> T,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() });
+        let ret = f(unsafe { self.journal_handle() });
         if !crash {
             self.commit_no_replay();
         }
@@ -308,7 +318,7 @@ impl Transaction {
         F: FnOnce(JournalHandle, SyscallToken) -> T,
     {
         self.begin();
-        let ret = f(unsafe { self.journal.unwrap_unchecked() }, unsafe {
+        let ret = f(unsafe { self.journal_handle() }, unsafe {
             SyscallToken::new()
         });
         if !crash {
@@ -795,6 +805,237 @@ impl TxCache {
     }
 }
 
+const MAX_CHUNK_SIZE: usize = 1 << 12;
+
+// Persistent state of `user::transaction::run_chunked`. The chunk size is
+// halved whenever a chunk is cut by a power failure and otherwise sized to
+// fit in half of the last observed on-time, so there is always progress as
+// long as a single item fits in one power cycle.
+#[derive(Clone, Copy)]
+struct ChunkSizing {
+    chunk: usize,
+    in_chunk: bool,
+    // generation in which the last chunk was started
+    attempt_gen: usize,
+    gen_start_cycles: u32,
+    alive_cycles: u32,
+    // lower bound of the time between two failures, 0 if never observed
+    on_time_cycles: u32,
+}
+
+// Only the last started `run_chunked` loop of a task is kept, it's known by
+// the id of the tx which started it. The sizing is updated in a copy which
+// takes over with a single store, a power failure never leaves it half done.
+pub struct ChunkCtl {
+    loop_id: usize,
+    progress: usize,
+    sizing: [ChunkSizing; 2],
+    cur: usize,
+}
+
+impl ChunkCtl {
+    pub fn init(&mut self) {
+        self.loop_id = usize::MAX;
+        self.progress = 0;
+        self.cur = 0;
+        self.sizing[0] = ChunkSizing {
+            chunk: 1,
+            in_chunk: false,
+            attempt_gen: 0,
+            gen_start_cycles: 0,
+            alive_cycles: 0,
+            on_time_cycles: 0,
+        };
+    }
+
+    // Start the loop `loop_id` from its first item, what was learned about the
+    // on-time is kept
+    pub fn start(&mut self, loop_id: usize) {
+        self.progress = 0;
+        self.update(|s| {
+            s.chunk = 1;
+            s.in_chunk = false;
+            s.attempt_gen = 0;
+        });
+        compiler_pm_fence();
+        self.loop_id = loop_id;
+    }
+
+    #[inline(always)]
+    pub fn get_loop_id(&self) -> usize {
+        self.loop_id
+    }
+
+    fn update<F: FnOnce(&mut ChunkSizing)>(&mut self, f: F) {
+        let mut next = self.sizing[self.cur];
+        f(&mut next);
+        self.sizing[1 - self.cur] = next;
+        compiler_pm_fence();
+        #[cfg(test)]
+        if get_chunk_crash_point() == 0 {
+            return;
+        }
+        self.cur = 1 - self.cur;
+    }
+
+    // fall back to one item per chunk
+    pub fn split(&mut self) {
+        self.update(|s| {
+            s.chunk = 1;
+            s.on_time_cycles = 0;
+        });
+    }
+
+    #[inline(always)]
+    pub fn get_progress(&self) -> usize {
+        self.progress
+    }
+
+    #[inline(always)]
+    pub fn progress_ptr(&mut self) -> *mut usize {
+        &mut self.progress as *mut usize
+    }
+
+    pub fn advance(&mut self, n: usize, j: JournalHandle) {
+        j.get_mut().append_log_of(&mut self.progress as *mut usize);
+        self.progress += n;
+    }
+
+    pub fn get_chunk_size(&self) -> usize {
+        self.sizing[self.cur].chunk
+    }
+
+    // returns the size of the next chunk
+    pub fn begin_chunk(&mut self, remaining: usize) -> usize {
+        let gen = current_generation();
+        self.update(|s| {
+            if s.attempt_gen != gen {
+                if s.in_chunk {
+                    // the last attempt was cut by a power failure
+                    s.chunk = core::cmp::max(1, s.chunk / 2);
+                    s.on_time_cycles = s.alive_cycles;
+                    debug_print!("chunk interrupted, new chunk size: {}", s.chunk);
+                }
+                s.gen_start_cycles = arch::arch_get_cycle_cnt();
+                s.alive_cycles = 0;
+            }
+            s.attempt_gen = gen;
+            s.in_chunk = true;
+        });
+        core::cmp::min(self.get_chunk_size(), remaining)
+    }
+
+    pub fn end_chunk(&mut self, items: usize, cycles: u32) {
+        let now = arch::arch_get_cycle_cnt();
+        self.update(|s| {
+            s.alive_cycles = now.wrapping_sub(s.gen_start_cycles);
+            let cost = cycles / items as u32;
+            let next = if s.on_time_cycles != 0 && cost != 0 {
+                (s.on_time_cycles / 2 / cost) as usize
+            } else {
+                s.chunk * 2
+            };
+            s.chunk = core::cmp::max(1, core::cmp::min(next, MAX_CHUNK_SIZE));
+            s.in_chunk = false;
+        });
+    }
+}
+
+// Transactions cut by power failures whose re-executions are counted. The
+// least re-executed one is forgotten when a further one is cut.
+const WATCHED_TX_NUM: usize = 4;
+
+// Re-execution counters of the user transactions. A transaction which is
+// started again in a later generation without having committed was cut by a
+// power failure; its count tells how often in a row this happened. The count
+// is kept by transaction, others which commit in between don't reset it.
+pub struct ReexecWatchdog {
+    // (tx id, re-executions), a count of 0 marks a free slot
+    cut_txs: [(usize, usize); WATCHED_TX_NUM],
+    tx_id: usize,
+    attempt_gen: usize,
+    pending: bool,
+    reexec_cnt: usize,
+    max_reexec_cnt: usize,
+    trips: usize,
+}
+
+impl ReexecWatchdog {
+    pub fn init(&mut self) {
+        self.cut_txs = [(0, 0); WATCHED_TX_NUM];
+        self.tx_id = 0;
+        self.attempt_gen = 0;
+        self.pending = false;
+        self.reexec_cnt = 0;
+        self.max_reexec_cnt = 0;
+        self.trips = 0;
+    }
+
+    fn slot_of(&self, tx_id: usize) -> Option<usize> {
+        self.cut_txs
+            .iter()
+            .position(|&(id, cnt)| cnt > 0 && id == tx_id)
+    }
+
+    fn count_cut(&mut self, tx_id: usize) {
+        let slot = match self.slot_of(tx_id) {
+            Some(slot) => slot,
+            None => {
+                let slot = (0..WATCHED_TX_NUM)
+                    .min_by_key(|&i| self.cut_txs[i].1)
+                    .unwrap();
+                self.cut_txs[slot] = (tx_id, 0);
+                slot
+            }
+        };
+        self.cut_txs[slot].1 += 1;
+    }
+
+    // returns true if the transaction exceeded the threshold
+    pub fn tx_begin(&mut self, tx_id: usize, threshold: usize) -> bool {
+        let gen = current_generation();
+        if self.pending && self.attempt_gen != gen {
+            self.count_cut(self.tx_id);
+        }
+        self.reexec_cnt = self.slot_of(tx_id).map_or(0, |slot| self.cut_txs[slot].1);
+        self.tx_id = tx_id;
+        self.attempt_gen = gen;
+        self.pending = true;
+        if self.reexec_cnt > self.max_reexec_cnt {
+            self.max_reexec_cnt = self.reexec_cnt;
+        }
+        if self.reexec_cnt >= threshold {
+            self.trips += 1;
+            return true;
+        }
+        false
+    }
+
+    pub fn tx_end(&mut self) {
+        self.pending = false;
+        self.reexec_cnt = 0;
+        if let Some(slot) = self.slot_of(self.tx_id) {
+            self.cut_txs[slot] = (0, 0);
+        }
+    }
+
+    pub fn get_tx_id(&self) -> usize {
+        self.tx_id
+    }
+
+    pub fn get_reexec_cnt(&self) -> usize {
+        self.reexec_cnt
+    }
+
+    pub fn get_max_reexec_cnt(&self) -> usize {
+        self.max_reexec_cnt
+    }
+
+    pub fn get_trips(&self) -> usize {
+        self.trips
+    }
+}
+
 const DEFAULT_STACK_DEPTH: usize = 8;
 type IdemTail = u16;
 type TxTail = u16;
@@ -811,6 +1052,7 @@ pub struct UserTxInfo {
     user_tx: Transaction,
     user_journal: Journal,
     user_tx_cache: TxCache,
+    watchdog: ReexecWatchdog,
     stack_top: u8,
     tail_stack: [TxTail; DEFAULT_STACK_DEPTH],
     #[cfg(feature = "opt_loop_end")]
@@ -819,6 +1061,7 @@ pub struct UserTxInfo {
     loop_cnt_ptr: Option<NonNull<usize>>,
     #[cfg(feature = "opt_loop_end")]
     step: usize,
+    chunk_ctl: ChunkCtl,
 }
 
 #[cfg(not(feature = "nested_idem_regions"))]
@@ -827,14 +1070,27 @@ impl UserTxInfo {
         &mut self.user_tx
     }
 
-    pub fn get_journal(&mut self) -> JournalHandle {
-        unsafe { JournalHandle::new(&self.user_journal as *const Journal) }
+    // see `Transaction::get_journal`
+    pub unsafe fn get_journal<'id>(&mut self) -> JournalHandle<'id> {
+        JournalHandle::new(&self.user_journal as *const Journal)
     }
 
     pub fn get_tx_cache(&mut self) -> &mut TxCache {
         &mut self.user_tx_cache
     }
 
+    pub fn get_chunk_ctl(&mut self) -> &mut ChunkCtl {
+        &mut self.chunk_ctl
+    }
+
+    pub fn get_watchdog(&self) -> &ReexecWatchdog {
+        &self.watchdog
+    }
+
+    pub fn get_mut_watchdog(&mut self) -> &mut ReexecWatchdog {
+        &mut self.watchdog
+    }
+
     pub fn restart(&mut self) {
         // reset tx cache ptr
         self.user_tx_cache.reset_ptr();
@@ -853,8 +1109,10 @@ impl UserTxInfo {
         self.user_tx.reset_nesting_level();
         self.user_journal.init();
         self.user_tx_cache.init();
+        self.watchdog.init();
         self.stack_top = 0;
         self.tail_stack = [0; DEFAULT_STACK_DEPTH];
+        self.chunk_ctl.init();
         #[cfg(feature = "opt_loop_end")]
         {
             self.loop_cnt_ptr = None;
@@ -1004,6 +1262,19 @@ impl UserTxInfo {
     }
 }
 
+#[cfg(test)]
+static mut CHUNK_CRASH_POINT: usize = 10000;
+
+#[cfg(test)]
+pub fn set_chunk_crash_point(cp: usize) {
+    unsafe { CHUNK_CRASH_POINT = cp };
+}
+
+#[cfg(test)]
+pub fn get_chunk_crash_point() -> usize {
+    unsafe { CHUNK_CRASH_POINT }
+}
+
 #[cfg(test)]
 static mut TX_LOOP_CRASH_POINT: usize = 0;
 
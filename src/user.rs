@@ -1,7 +1,10 @@
 pub mod parc;
+pub mod patomic;
 pub mod pbox;
+pub mod pcondvar;
 pub mod pmutex;
 pub mod pqueue;
+pub mod prwlock;
 pub mod pstatics;
 pub mod pthread;
 pub mod pvec;
@@ -0,0 +1,240 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+
+use crate::critical;
+use crate::declare_pm_var_unsafe;
+use crate::marker::PSafe;
+#[cfg(feature = "crash_safe")]
+use crate::task::{current, task_by_id};
+use crate::util::compiler_pm_fence;
+
+// Persistent atomics for counters and flags shared between tasks. Every
+// operation runs with interrupts disabled, so there is at most one of them in
+// flight and a single-word undo log is enough to make it crash-atomic. The
+// value returned by an operation is cached like the result of a user
+// transaction, a replayed operation is bypassed.
+// Must be called outside of user transactions, e.g. directly in nv_loop!.
+
+#[macro_export]
+macro_rules! declare_pm_atomic {
+    ($name: ident, $t: ident, $e: expr) => {
+        #[link_section = ".pmem"]
+        pub static $name: crate::user::patomic::$t = crate::user::patomic::$t::new($e);
+    };
+}
+
+struct AtomicLog {
+    addr: usize,
+    old: usize,
+    // the operation is committed once the tx cache tail of the task moves on
+    task_id: usize,
+    tx_id: usize,
+    valid: bool,
+}
+
+impl AtomicLog {
+    const fn new() -> Self {
+        Self {
+            addr: 0,
+            old: 0,
+            task_id: 0,
+            tx_id: 0,
+            valid: false,
+        }
+    }
+}
+
+declare_pm_var_unsafe!(ATOMIC_LOG, AtomicLog, AtomicLog::new());
+
+// Undo an operation which was cut before it committed
+#[cfg(feature = "crash_safe")]
+pub fn recover() {
+    unsafe {
+        if !ATOMIC_LOG.valid {
+            return;
+        }
+        if let Some(mut task_ptr) = task_by_id(ATOMIC_LOG.task_id) {
+            let task = task_ptr.as_mut_no_logging();
+            let tx_cache = task.get_mut_user_tx_info().get_tx_cache();
+            if tx_cache.get_tx_id_of_tail() == ATOMIC_LOG.tx_id {
+                crate::debug_print!("Rolling back unfinished atomic operation");
+                *(ATOMIC_LOG.addr as *mut usize) = ATOMIC_LOG.old;
+                compiler_pm_fence();
+            }
+        }
+        ATOMIC_LOG.valid = false;
+    }
+}
+
+// Apply `f` to the word and return its previous value, `f` returns None if
+// nothing is to be written
+#[cfg(feature = "crash_safe")]
+fn update<F>(word: *mut usize, f: F) -> usize
+where
+    F: FnOnce(usize) -> Option<usize>,
+{
+    critical::with_no_interrupt(|_| {
+        let task = current();
+        let task_id = task.get_task_id();
+        let user_tx_info = task.get_mut_user_tx_info();
+        debug_assert_eq!(user_tx_info.get_tx().get_nesting_level(), 0);
+        let tx_cache = user_tx_info.get_tx_cache();
+        if let Ok(old) = tx_cache.try_retrieve_result::<usize>() {
+            return old;
+        }
+        let old = unsafe { *word };
+        if let Some(new) = f(old) {
+            unsafe {
+                ATOMIC_LOG.addr = word as usize;
+                ATOMIC_LOG.old = old;
+                ATOMIC_LOG.task_id = task_id;
+                ATOMIC_LOG.tx_id = tx_cache.get_tx_id_of_tail();
+                compiler_pm_fence();
+                ATOMIC_LOG.valid = true;
+                compiler_pm_fence();
+                *word = new;
+                compiler_pm_fence();
+            }
+        }
+        // commit
+        tx_cache.cache_result(&old);
+        compiler_pm_fence();
+        unsafe {
+            ATOMIC_LOG.valid = false;
+        }
+        old
+    })
+}
+
+#[cfg(not(feature = "crash_safe"))]
+fn update<F>(word: *mut usize, f: F) -> usize
+where
+    F: FnOnce(usize) -> Option<usize>,
+{
+    critical::with_no_interrupt(|_| {
+        let old = unsafe { *word };
+        if let Some(new) = f(old) {
+            unsafe { *word = new };
+        }
+        old
+    })
+}
+
+fn compare_exchange_word(word: *mut usize, current: usize, new: usize) -> Result<usize, usize> {
+    let old = update(word, |v| if v == current { Some(new) } else { None });
+    if old == current {
+        Ok(old)
+    } else {
+        Err(old)
+    }
+}
+
+pub struct PAtomicUsize {
+    v: UnsafeCell<usize>,
+}
+
+unsafe impl Sync for PAtomicUsize {}
+unsafe impl PSafe for PAtomicUsize {}
+
+impl PAtomicUsize {
+    pub const fn new(v: usize) -> Self {
+        Self {
+            v: UnsafeCell::new(v),
+        }
+    }
+
+    #[inline(always)]
+    pub fn load(&self) -> usize {
+        unsafe { core::ptr::read_volatile(self.v.get()) }
+    }
+
+    pub fn store(&self, v: usize) {
+        update(self.v.get(), |_| Some(v));
+    }
+
+    pub fn fetch_add(&self, n: usize) -> usize {
+        update(self.v.get(), |v| Some(v.wrapping_add(n)))
+    }
+
+    pub fn fetch_sub(&self, n: usize) -> usize {
+        update(self.v.get(), |v| Some(v.wrapping_sub(n)))
+    }
+
+    pub fn compare_exchange(&self, current: usize, new: usize) -> Result<usize, usize> {
+        compare_exchange_word(self.v.get(), current, new)
+    }
+}
+
+pub struct PAtomicBool {
+    v: UnsafeCell<usize>,
+}
+
+unsafe impl Sync for PAtomicBool {}
+unsafe impl PSafe for PAtomicBool {}
+
+impl PAtomicBool {
+    pub const fn new(v: bool) -> Self {
+        Self {
+            v: UnsafeCell::new(v as usize),
+        }
+    }
+
+    #[inline(always)]
+    pub fn load(&self) -> bool {
+        unsafe { core::ptr::read_volatile(self.v.get()) != 0 }
+    }
+
+    pub fn store(&self, v: bool) {
+        update(self.v.get(), |_| Some(v as usize));
+    }
+
+    pub fn swap(&self, v: bool) -> bool {
+        update(self.v.get(), |_| Some(v as usize)) != 0
+    }
+
+    pub fn compare_exchange(&self, current: bool, new: bool) -> Result<bool, bool> {
+        compare_exchange_word(self.v.get(), current as usize, new as usize)
+            .map(|v| v != 0)
+            .map_err(|v| v != 0)
+    }
+}
+
+// Only meaningful for pointers into PM
+pub struct PAtomicPtr<T> {
+    v: UnsafeCell<usize>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T> Sync for PAtomicPtr<T> {}
+unsafe impl<T> PSafe for PAtomicPtr<T> {}
+
+impl<T> PAtomicPtr<T> {
+    pub fn new(p: *mut T) -> Self {
+        Self {
+            v: UnsafeCell::new(p as usize),
+            _marker: PhantomData,
+        }
+    }
+
+    pub const fn null() -> Self {
+        Self {
+            v: UnsafeCell::new(0),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    pub fn load(&self) -> *mut T {
+        unsafe { core::ptr::read_volatile(self.v.get()) as *mut T }
+    }
+
+    pub fn store(&self, p: *mut T) {
+        update(self.v.get(), |_| Some(p as usize));
+    }
+
+    pub fn compare_exchange(&self, current: *mut T, new: *mut T) -> Result<*mut T, *mut T> {
+        compare_exchange_word(self.v.get(), current as usize, new as usize)
+            .map(|v| v as *mut T)
+            .map_err(|v| v as *mut T)
+    }
+}
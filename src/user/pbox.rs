@@ -75,7 +75,8 @@ impl<T: PSafe> PBox<T> {
     //     self.ptr.as_mut(j)
     // }
 
-    pub fn as_mut(&self, j: JournalHandle) -> &mut T {
+    // the reference can't outlive the transaction of `j`
+    pub fn as_mut<'a, 'id: 'a>(&'a self, j: JournalHandle<'id>) -> &'a mut T {
         self.ptr.create_log(j);
         unsafe { &mut *self.ptr.as_ptr() }
     }
@@ -244,7 +245,7 @@ impl<T: PSafe> PtrWLog<T> {
         PtrRW { boxed: self.boxed }
     }
 
-    pub fn as_mut(&mut self, j: JournalHandle) -> &mut T {
+    pub fn as_mut<'b, 'id: 'b>(&'b mut self, j: JournalHandle<'id>) -> &'b mut T {
         self.boxed.as_mut(j)
     }
 
@@ -366,7 +367,7 @@ impl<'a, T> PRefWLog<'a, T> {
         &self.inner
     }
 
-    pub fn as_mut(&mut self, j: JournalHandle) -> &mut T {
+    pub fn as_mut<'b, 'id: 'b>(&'b mut self, j: JournalHandle<'id>) -> &'b mut T {
         //log
         j.get_mut().append_log_of(self.inner as *mut T);
         &mut self.inner
@@ -0,0 +1,62 @@
+use crate::syscalls::{self as sys, SyscallToken};
+use crate::time::Time;
+
+use super::pmutex::PMutexGuard;
+use super::AllocError;
+
+const DEFAULT_CONDVAR_WAIT_TIME: Time = (Time::MAX) >> 2;
+
+// Wakeups may be spurious, so wait in a loop which checks the condition. A
+// notification is kept until the waiter holds the mutex again, even across a
+// crash.
+pub struct PCondvar {
+    handle: sys::CondVarHandle,
+}
+
+unsafe impl Send for PCondvar {}
+unsafe impl Sync for PCondvar {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl PCondvar {
+    pub fn new(t: SyscallToken) -> Self {
+        match Self::try_new(t) {
+            Ok(cv) => cv,
+            Err(_) => panic!("PCondvar New OOM"),
+        }
+    }
+
+    pub fn try_new(t: SyscallToken) -> Result<Self, AllocError> {
+        let handle = sys::sys_condvar_create(t).ok_or(AllocError)?;
+        Ok(Self { handle })
+    }
+
+    // Give the mutex behind `guard` and sleep until notified
+    pub fn wait<'a, T>(&self, guard: PMutexGuard<'a, T>) -> PMutexGuard<'a, T> {
+        self.wait_timeout(guard, DEFAULT_CONDVAR_WAIT_TIME).0
+    }
+
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: PMutexGuard<'a, T>,
+        wait_ticks: Time,
+    ) -> (PMutexGuard<'a, T>, WaitTimeoutResult) {
+        let r = sys::sys_condvar_wait(self.handle, guard.semaphore_handle(), wait_ticks);
+        (guard, WaitTimeoutResult(r.is_err()))
+    }
+
+    pub fn notify_one(&self) {
+        sys::sys_condvar_notify(self.handle, false);
+    }
+
+    pub fn notify_all(&self) {
+        sys::sys_condvar_notify(self.handle, true);
+    }
+}
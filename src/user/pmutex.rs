@@ -30,12 +30,17 @@ pub struct PMutexGuard<'a, T> {
     phantom: PhantomData<&'a PMutex<T>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum PMutexError {
     NoMemory,
+    // a holder went away while holding the lock
     Poisoned,
+    // another task holds the lock
+    WouldBlock,
+    Timeout,
 }
 
+#[derive(Debug, PartialEq)]
 pub enum PMutexStatus {
     Unlocked = 0,
     Locked = 1,
@@ -47,7 +52,7 @@ unsafe impl<T: Send> Sync for PMutex<T> {}
 
 impl<T> PMutex<T> {
     pub fn new(data: T, t: SyscallToken) -> Self {
-        let semaphore = sys::sys_create_semaphore(1, t);
+        let semaphore = sys::sys_create_mutex(t);
         let sem = match semaphore {
             None => {
                 panic!("PMutex New OOM");
@@ -61,7 +66,7 @@ impl<T> PMutex<T> {
     }
 
     pub fn try_new(data: T, t: SyscallToken) -> Result<Self, PMutexError> {
-        let semaphore = sys::sys_create_semaphore(1, t);
+        let semaphore = sys::sys_create_mutex(t);
         let sem = match semaphore {
             None => {
                 return Err(PMutexError::NoMemory);
@@ -74,18 +79,66 @@ impl<T> PMutex<T> {
         })
     }
 
-    fn sys_lock(&self) {
-        while let Err(_) = sys::sys_semaphore_take(self.semaphore_handle, DEFAULT_MUTEX_WAIT_TIME) {
+    fn guard(&self) -> PMutexGuard<T> {
+        PMutexGuard {
+            mutex_ptr: unsafe { NonNull::new_unchecked(self as *const Self as *mut Self) },
+            phantom: PhantomData,
+        }
+    }
+
+    // A poisoned lock is given back right away
+    fn sys_lock(&self, wait_ticks: Time) -> Result<PMutexGuard<T>, PMutexError> {
+        if let Err(_) = sys::sys_semaphore_take(self.semaphore_handle, wait_ticks) {
+            return Err(PMutexError::Timeout);
         }
+        if self.is_poisoned() {
+            self.sys_unlock();
+            return Err(PMutexError::Poisoned);
+        }
+        Ok(self.guard())
     }
 
     pub fn lock(&self) -> Result<PMutexGuard<T>, PMutexError> {
-        self.sys_lock();
-        let guard = PMutexGuard {
-            mutex_ptr: unsafe { NonNull::new_unchecked(self as *const Self as *mut Self) },
-            phantom: PhantomData,
-        };
-        Ok(guard)
+        loop {
+            match self.sys_lock(DEFAULT_MUTEX_WAIT_TIME) {
+                Err(PMutexError::Timeout) => {}
+                r => return r,
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Result<PMutexGuard<T>, PMutexError> {
+        self.sys_lock(0).map_err(|e| match e {
+            PMutexError::Timeout => PMutexError::WouldBlock,
+            e => e,
+        })
+    }
+
+    pub fn lock_timeout(&self, wait_ticks: Time) -> Result<PMutexGuard<T>, PMutexError> {
+        self.sys_lock(wait_ticks)
+    }
+
+    // Lock even if poisoned, to look at and repair the data. The lock stays
+    // poisoned until `PMutexGuard::clear_poison`.
+    pub fn lock_ignore_poison(&self) -> PMutexGuard<T> {
+        while let Err(_) = sys::sys_semaphore_take(self.semaphore_handle, DEFAULT_MUTEX_WAIT_TIME) {
+        }
+        self.guard()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.semaphore_handle.as_ref().is_poisoned()
+    }
+
+    pub fn status(&self) -> PMutexStatus {
+        let sem = self.semaphore_handle.as_ref();
+        if sem.is_poisoned() {
+            PMutexStatus::Poisoned
+        } else if sem.is_empty() {
+            PMutexStatus::Locked
+        } else {
+            PMutexStatus::Unlocked
+        }
     }
 
     fn sys_unlock(&self) {
@@ -103,7 +156,8 @@ impl<T> PMutexGuard<'_, T> {
     //     j.get_mut().append_log_of(mut_ref as * mut T);
     //     mut_ref
     // }
-    pub fn as_mut(&self, j: JournalHandle) -> &mut T {
+    // the reference can't outlive the transaction of `j`
+    pub fn as_mut<'a, 'id: 'a>(&'a self, j: JournalHandle<'id>) -> &'a mut T {
         let mutex = unsafe { self.mutex_ptr.as_ref() };
         let mut_ref = unsafe { &mut *mutex.inner.get() };
         j.get_mut().append_log_of(mut_ref as *mut T);
@@ -115,6 +169,17 @@ impl<T> PMutexGuard<'_, T> {
         let r = unsafe { &*mutex.inner.get() };
         r
     }
+
+    pub(crate) fn semaphore_handle(&self) -> sys::SemaphoreHandle {
+        unsafe { self.mutex_ptr.as_ref().semaphore_handle }
+    }
+
+    // The data is consistent again, later lockers get it as usual
+    pub fn clear_poison(&self) {
+        let mutex = unsafe { self.mutex_ptr.as_ref() };
+        // the guard's task is the holder
+        let _ = sys::sys_semaphore_clear_poison(mutex.semaphore_handle);
+    }
 }
 
 impl<T> Drop for PMutexGuard<'_, T> {
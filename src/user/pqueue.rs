@@ -61,7 +61,7 @@ impl<T: PSafe> PQueue<T> {
         }
     }
 
-    fn raw_mut_pqueue(&self, j: JournalHandle) -> &mut RawPQueue<T> {
+    fn raw_mut_pqueue<'a, 'id: 'a>(&'a self, j: JournalHandle<'id>) -> &'a mut RawPQueue<T> {
         unsafe { self.boxed_buf.as_mut(j) }
     }
 
@@ -0,0 +1,150 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::NonNull;
+
+use crate::marker::{PSafe, TxInSafe, TxRefInSafe};
+use crate::pmem::JournalHandle;
+use crate::rwlock::RwLockErr;
+use crate::syscalls::{self as sys, SyscallToken};
+use crate::time::Time;
+
+use super::transaction;
+
+const DEFAULT_RWLOCK_WAIT_TIME: Time = (Time::MAX) >> 2;
+
+// Many readers or a single writer. A waiting writer holds back new readers.
+// Lock and unlock outside of transactions, a task which takes the lock twice
+// panics.
+pub struct PRwLock<T> {
+    inner: UnsafeCell<T>,
+    handle: sys::RwLockHandle,
+}
+
+unsafe impl<T: PSafe> PSafe for PRwLock<T> {}
+impl<T> !TxRefInSafe for PRwLock<T> {}
+impl<T> !TxInSafe for PRwLock<T> {}
+
+unsafe impl<T: Send> Send for PRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for PRwLock<T> {}
+
+pub struct PRwLockReadGuard<'a, T> {
+    lock_ptr: NonNull<PRwLock<T>>,
+    phantom: PhantomData<&'a PRwLock<T>>,
+}
+
+pub struct PRwLockWriteGuard<'a, T> {
+    lock_ptr: NonNull<PRwLock<T>>,
+    phantom: PhantomData<&'a PRwLock<T>>,
+}
+
+#[derive(Debug)]
+pub enum PRwLockError {
+    NoMemory,
+}
+
+impl<T> PRwLock<T> {
+    pub fn new(data: T, t: SyscallToken) -> Self {
+        match Self::try_new(data, t) {
+            Ok(l) => l,
+            Err(_) => panic!("PRwLock New OOM"),
+        }
+    }
+
+    pub fn try_new(data: T, t: SyscallToken) -> Result<Self, PRwLockError> {
+        let handle = sys::sys_rwlock_create(t).ok_or(PRwLockError::NoMemory)?;
+        Ok(Self {
+            inner: UnsafeCell::new(data),
+            handle,
+        })
+    }
+
+    pub fn handle(&self) -> sys::RwLockHandle {
+        self.handle
+    }
+
+    // Each try is a transaction of its own, so a replay skips the ones which
+    // went through before a crash
+    fn sys_lock(&self, writer: bool) -> NonNull<Self> {
+        let (handle, wait) = (self.handle, DEFAULT_RWLOCK_WAIT_TIME);
+        loop {
+            match transaction::run_pure_sys(|t| sys::sys_rwlock_acquire(handle, wait, writer, t)) {
+                Ok(_) => break,
+                Err(RwLockErr::Deadlock) => panic!("PRwLock is held by the caller already"),
+                Err(RwLockErr::Timeout) => {}
+            }
+        }
+        unsafe { NonNull::new_unchecked(self as *const Self as *mut Self) }
+    }
+
+    fn sys_unlock(&self) {
+        let handle = self.handle;
+        transaction::run_pure_sys(|t| sys::sys_rwlock_release(handle, t));
+    }
+
+    pub fn read(&self) -> PRwLockReadGuard<T> {
+        PRwLockReadGuard {
+            lock_ptr: self.sys_lock(false),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn write(&self) -> PRwLockWriteGuard<T> {
+        PRwLockWriteGuard {
+            lock_ptr: self.sys_lock(true),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn reader_count(&self) -> usize {
+        self.handle.as_ref().reader_count()
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.handle.as_ref().is_write_locked()
+    }
+}
+
+impl<T> PRwLockWriteGuard<'_, T> {
+    // the reference can't outlive the transaction of `j`
+    pub fn as_mut<'a, 'id: 'a>(&'a self, j: JournalHandle<'id>) -> &'a mut T {
+        let lock = unsafe { self.lock_ptr.as_ref() };
+        let mut_ref = unsafe { &mut *lock.inner.get() };
+        j.get_mut().append_log_of(mut_ref as *mut T);
+        mut_ref
+    }
+
+    pub fn as_ref(&self, _j: JournalHandle) -> &T {
+        let lock = unsafe { self.lock_ptr.as_ref() };
+        unsafe { &*lock.inner.get() }
+    }
+}
+
+impl<T> Drop for PRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock_ptr.as_ref() }.sys_unlock()
+    }
+}
+
+impl<T> Drop for PRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock_ptr.as_ref() }.sys_unlock()
+    }
+}
+
+// Readers never write, so they need no journal
+impl<T> Deref for PRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock_ptr.as_ref().inner.get() }
+    }
+}
+
+impl<T> Deref for PRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock_ptr.as_ref().inner.get() }
+    }
+}
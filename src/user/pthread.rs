@@ -1,18 +1,123 @@
+use core::mem::{self, MaybeUninit};
+
+use crate::event_group::{EventBits, EventGroupHandle};
 use crate::marker::PSafe;
+use crate::pmem::PMPtr;
 use crate::syscalls::{self as sys, SyscallToken};
-use crate::task::TaskHandle;
+use crate::task::{ErrorCode, TaskHandle};
+use crate::time::Time;
 
 use super::pbox::RelaxedPBox;
+use super::transaction;
+
+const THREAD_FINISHED: EventBits = 0b01;
+const THREAD_DETACHED: EventBits = 0b10;
+
+// Where a thread leaves its result, lives in the creator's PM heap
+struct JoinSlot<T: PSafe> {
+    finished: bool,
+    result: MaybeUninit<T>,
+    done: EventGroupHandle,
+}
+
+struct ThreadStart<F, T: PSafe> {
+    f: F,
+    slot: PMPtr<JoinSlot<T>>,
+}
+
+// Dropping the handle detaches the thread, which must happen outside of a
+// transaction. Inside one, call `detach` instead.
+pub struct JoinHandle<T: PSafe> {
+    task: TaskHandle,
+    slot: PMPtr<JoinSlot<T>>,
+}
+
+impl<T: PSafe> JoinHandle<T> {
+    pub fn task(&self) -> TaskHandle {
+        self.task
+    }
+
+    #[cfg(test)]
+    pub fn done_event_group(&self) -> EventGroupHandle {
+        self.slot.as_ref().done
+    }
+}
+
+impl<T: PSafe> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        let slot = self.slot;
+        transaction::run_sys(move |_, t| detach_slot(slot, t));
+    }
+}
+
+unsafe impl<F: Send, T: PSafe + Send> Send for ThreadStart<F, T> {}
+unsafe impl<T: PSafe + Send> Send for JoinHandle<T> {}
+
+#[cfg(test)]
+static mut CRASH_AFTER_RESULT: bool = false;
 
-fn closure_runner<F, T>(mut pboxed: RelaxedPBox<F>)
+// Stop a thread between its result and the flag, as a power failure would
+#[cfg(test)]
+pub fn set_crash_after_result(crash: bool) {
+    unsafe { CRASH_AFTER_RESULT = crash };
+}
+
+fn closure_runner<F, T>(mut pboxed: RelaxedPBox<ThreadStart<F, T>>)
 where
     F: FnMut() -> T + Send + 'static,
+    T: PSafe,
 {
-    unsafe {
-        (pboxed.as_mut_no_logging())();
+    let start = unsafe { pboxed.as_mut_no_logging() };
+    let mut slot = start.slot;
+    // a result delivered before a power failure is not produced again
+    if !slot.as_ref().finished {
+        let ret = (start.f)();
+        // the result only counts once the slot is marked finished, a power
+        // failure before that runs `f` again
+        unsafe {
+            slot.as_mut_no_logging().result.write(ret);
+        }
+        #[cfg(test)]
+        if unsafe { CRASH_AFTER_RESULT } {
+            return;
+        }
+        transaction::run_sys(move |j, t| {
+            let s = slot.as_mut(j);
+            s.finished = true;
+            // the last of the thread and the handle frees the slot
+            if exchange_bits(s.done, THREAD_FINISHED, THREAD_DETACHED, t) {
+                release_slot(slot, t);
+            }
+        });
     }
     drop(pboxed);
-    loop {}
+    transaction::run_pure_sys(|t| sys::sys_task_exit(t));
+}
+
+// Set `bits` and tell whether `other` was set already. It's one syscall, so
+// only one of two racing callers sees the bits of the other.
+fn exchange_bits(
+    done: EventGroupHandle,
+    bits: EventBits,
+    other: EventBits,
+    t: SyscallToken,
+) -> bool {
+    let r = sys::sys_event_group_sync(done, bits, other, 0, t);
+    r.map_or(false, |b| b & other != 0)
+}
+
+fn release_slot<T: PSafe>(slot: PMPtr<JoinSlot<T>>, t: SyscallToken) {
+    // nobody else waits on it
+    let _ = sys::sys_event_group_delete(slot.as_ref().done, t);
+    unsafe {
+        sys::sys_pfree(slot, t);
+    }
+}
+
+fn detach_slot<T: PSafe>(slot: PMPtr<JoinSlot<T>>, t: SyscallToken) {
+    if exchange_bits(slot.as_ref().done, THREAD_DETACHED, THREAD_FINISHED, t) {
+        release_slot(slot, t);
+    }
 }
 
 pub fn create<F, T>(
@@ -20,10 +125,46 @@ pub fn create<F, T>(
     prio: usize,
     t: SyscallToken,
     f: F,
-) -> Result<TaskHandle, crate::task::ErrorCode>
+) -> Result<JoinHandle<T>, ErrorCode>
 where
     F: FnMut() -> T + Send + 'static,
+    T: PSafe + Send + 'static,
 {
-    let pboxed = RelaxedPBox::new(f, t);
-    sys::sys_create_task::<RelaxedPBox<F>>(name, prio, closure_runner::<F, T>, pboxed, t)
+    let done = sys::sys_event_group_create(t).ok_or(ErrorCode::NoSpace)?;
+    let slot = JoinSlot {
+        finished: false,
+        result: MaybeUninit::uninit(),
+        done,
+    };
+    let slot = unsafe { sys::sys_palloc_relaxed(slot, t) }.ok_or(ErrorCode::NoSpace)?;
+    let pboxed = RelaxedPBox::new(ThreadStart { f, slot }, t);
+    let task = sys::sys_create_task(name, prio, closure_runner::<F, T>, pboxed, t)?;
+    Ok(JoinHandle { task, slot })
+}
+
+// Wait for the thread to finish and take its result, the handle is given
+// back if the thread is still running after `ticks`
+pub fn join<T: PSafe>(
+    handle: JoinHandle<T>,
+    ticks: Time,
+    t: SyscallToken,
+) -> Result<T, JoinHandle<T>> {
+    let slot = handle.slot;
+    let done = slot.as_ref().done;
+    // like a poll, a wait that times out gives back the bits as they are
+    let r = sys::sys_event_group_wait(done, THREAD_FINISHED, false, true, ticks, t);
+    if r.map_or(true, |b| b & THREAD_FINISHED == 0) {
+        return Err(handle);
+    }
+    mem::forget(handle);
+    let ret = unsafe { slot.as_ref().result.assume_init_read() };
+    release_slot(slot, t);
+    Ok(ret)
+}
+
+// Give up on the result, the thread frees its slot when it finishes
+pub fn detach<T: PSafe>(handle: JoinHandle<T>, t: SyscallToken) {
+    let slot = handle.slot;
+    mem::forget(handle);
+    detach_slot(slot, t);
 }
@@ -140,7 +140,7 @@ impl<T: PSafe> PVec<T> {
     //     mut_ref.boxed_buf.as_mut(j)
     // }
 
-    fn raw_vec_mut(&self, j: JournalHandle) -> &mut RawPVec<T> {
+    fn raw_vec_mut<'a, 'id: 'a>(&'a self, j: JournalHandle<'id>) -> &'a mut RawPVec<T> {
         self.boxed_buf.as_mut(j)
     }
 
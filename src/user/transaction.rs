@@ -1,9 +1,12 @@
-use crate::marker::{TxInSafe, TxOutSafe};
+use core::ops::Range;
+
+use crate::arch::arch_get_cycle_cnt;
+use crate::marker::{TxInSafe, TxOutSafe, TxRefInSafe};
 use crate::pmem::JournalHandle;
 use crate::syscalls::SyscallToken;
 use crate::task::{current, task_get_stats, ErrorCode};
 use crate::util::{benchmark_clock, get_time_diff, max, min};
-use crate::{critical, debug_print, get_time_diff};
+use crate::{critical, debug_print, get_time_diff, os_print, semaphore};
 
 #[macro_export]
 macro_rules! nv_loop {
@@ -121,7 +124,71 @@ macro_rules! continuei {
     };
 }
 
-fn pre_tx_hook() {
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchdogAction {
+    // report and keep re-executing
+    Log,
+    // shrink the chunk of `run_chunked` to a single item
+    Split,
+    // run the fallback of `run_with_fallback` instead
+    Skip,
+}
+
+// Called when a transaction has been cut by power failures `reexec_cnt`
+// times in a row without committing
+pub type WatchdogPolicy = fn(task: &str, tx_id: usize, reexec_cnt: usize) -> WatchdogAction;
+
+const DEFAULT_REEXEC_THRESHOLD: usize = 8;
+
+// volatile, set up again by the application after reboot
+static mut REEXEC_THRESHOLD: usize = DEFAULT_REEXEC_THRESHOLD;
+static mut WATCHDOG_POLICY: WatchdogPolicy = default_watchdog_policy;
+
+fn default_watchdog_policy(_task: &str, _tx_id: usize, _reexec_cnt: usize) -> WatchdogAction {
+    WatchdogAction::Log
+}
+
+pub fn set_watchdog_threshold(threshold: usize) {
+    unsafe {
+        REEXEC_THRESHOLD = threshold;
+    }
+}
+
+pub fn set_watchdog_policy(policy: WatchdogPolicy) {
+    unsafe {
+        WATCHDOG_POLICY = policy;
+    }
+}
+
+#[cfg(feature = "crash_safe")]
+fn check_forward_progress() -> WatchdogAction {
+    let task = current();
+    let user_tx_info = task.get_mut_user_tx_info();
+    let tx_id = user_tx_info.get_tx_cache().get_tx_id_of_ptr();
+    let threshold = unsafe { REEXEC_THRESHOLD };
+    let watchdog = user_tx_info.get_mut_watchdog();
+    if !watchdog.tx_begin(tx_id, threshold) {
+        return WatchdogAction::Log;
+    }
+    let reexec_cnt = watchdog.get_reexec_cnt();
+    let action = unsafe { WATCHDOG_POLICY(task.get_name(), tx_id, reexec_cnt) };
+    os_print!(
+        "[Watchdog] task {} tx {} re-executed {} times, action: {:?}",
+        task.get_name(),
+        tx_id,
+        reexec_cnt,
+        action
+    );
+    if action == WatchdogAction::Split {
+        current()
+            .get_mut_user_tx_info()
+            .get_chunk_ctl()
+            .split();
+    }
+    action
+}
+
+fn pre_tx_hook() -> WatchdogAction {
     #[cfg(feature = "profile_tx")]
     {
         let task = current();
@@ -133,9 +200,15 @@ fn pre_tx_hook() {
             stats.tx_stat.usr_tx_time = 0;
         });
     }
+    #[cfg(feature = "crash_safe")]
+    return check_forward_progress();
+    #[cfg(not(feature = "crash_safe"))]
+    WatchdogAction::Log
 }
 
 fn post_tx_hook() {
+    #[cfg(feature = "crash_safe")]
+    current().get_mut_user_tx_info().get_mut_watchdog().tx_end();
     #[cfg(feature = "profile_tx")]
     {
         let task = current();
@@ -186,7 +259,7 @@ where
         r
     }
     // } else {
-    //     f(current_tx.get_journal())
+    //     f(unsafe { current_tx.get_journal() })
     // }
 }
 
@@ -298,10 +371,42 @@ where
         r
     }
     // } else {
-    //     f(current_tx.get_journal())
+    //     f(unsafe { current_tx.get_journal() })
     // }
 }
 
+// Like `run`, but executes `fallback` instead of `f` once the watchdog policy
+// decides to skip a transaction that never gets to commit
+#[cfg(feature = "crash_safe")]
+#[inline(always)]
+pub fn run_with_fallback<F, G, T>(f: F, fallback: G) -> T
+where
+    F: TxInSafe + FnOnce(JournalHandle) -> T,
+    G: TxInSafe + FnOnce(JournalHandle) -> T,
+    T: TxOutSafe,
+{
+    let current_tx = current().get_mut_user_tx();
+    debug_assert_eq!(current_tx.get_nesting_level(), 0);
+    if let Ok(cache_res) = current_tx.try_get_cached_result() {
+        debug_print!("bypassing user TX...");
+        debug_assert!(
+            current().in_recovery_mode(),
+            "Can't bypass TX when not recovering"
+        );
+        cache_res
+    } else {
+        let r = if pre_tx_hook() == WatchdogAction::Skip {
+            // the skipped tx may have left the data behind its locks half updated
+            semaphore::poison_held_mutexes(current());
+            current_tx.run(fallback)
+        } else {
+            current_tx.run(f)
+        };
+        post_tx_hook();
+        r
+    }
+}
+
 // No cache version
 #[cfg(feature = "crash_safe")]
 #[inline(always)]
@@ -344,7 +449,7 @@ where
     T: TxOutSafe,
 {
     let current_tx = current().get_mut_user_tx();
-    f(current_tx.get_journal())
+    f(unsafe { current_tx.get_journal() })
 }
 
 #[cfg(not(feature = "crash_safe"))]
@@ -356,7 +461,7 @@ where
 {
     let current_tx = current().get_mut_user_tx();
     let st = unsafe { SyscallToken::new() };
-    f(current_tx.get_journal(), st)
+    f(unsafe { current_tx.get_journal() }, st)
 }
 
 #[cfg(not(feature = "crash_safe"))]
@@ -388,7 +493,19 @@ where
     T: TxOutSafe,
 {
     let current_tx = current().get_mut_user_tx();
-    f(current_tx.get_journal())
+    f(unsafe { current_tx.get_journal() })
+}
+
+#[cfg(not(feature = "crash_safe"))]
+#[inline(always)]
+pub fn run_with_fallback<F, G, T>(f: F, fallback: G) -> T
+where
+    F: TxInSafe + FnOnce(JournalHandle) -> T,
+    G: TxInSafe + FnOnce(JournalHandle) -> T,
+    T: TxOutSafe,
+{
+    let current_tx = current().get_mut_user_tx();
+    f(unsafe { current_tx.get_journal() })
 }
 
 // No cache version
@@ -412,12 +529,68 @@ where
     T: TxOutSafe,
 {
     let current_tx = current().get_mut_user_tx();
-    f(current_tx.get_journal(), unsafe {
+    f(unsafe { current_tx.get_journal() }, unsafe {
         crate::syscalls::SyscallToken::new()
     })
 }
 
 /* ---------------------------------------------------------------------- */
+// Process items [0, total) in transactions of adaptive size. The chunk size
+// shrinks when a chunk is cut by a power failure and grows to fit in the
+// observed on-time otherwise. The length and cost of a chunk are part of the
+// result of its transaction, so a bypassed chunk is accounted as it ran.
+pub fn run_chunked<F>(total: usize, f: F)
+where
+    F: TxRefInSafe + Fn(Range<usize>, JournalHandle, SyscallToken),
+{
+    let loop_id = current().get_mut_user_tx_info().get_tx_cache().get_tx_id_of_ptr();
+    fast_run(|| {
+        current()
+            .get_mut_user_tx_info()
+            .get_chunk_ctl()
+            .start(loop_id);
+    });
+    // replayed, the loop went through before a later one took the state
+    let ctl_loop_id = current().get_mut_user_tx_info().get_chunk_ctl().get_loop_id();
+    if ctl_loop_id != loop_id {
+        return;
+    }
+    loop {
+        let done = current()
+            .get_mut_user_tx_info()
+            .get_chunk_ctl()
+            .get_progress();
+        if done >= total {
+            break;
+        }
+        let user_tx_info = current().get_mut_user_tx_info();
+        let len = user_tx_info.get_chunk_ctl().begin_chunk(total - done);
+        user_tx_info.enter_idempotent_loop();
+        let f_ref = &f;
+        let (len, cycles) = run_sys(move |j, t| {
+            let start = arch_get_cycle_cnt();
+            f_ref(done..done + len, j, t);
+            (len, get_time_diff(arch_get_cycle_cnt(), start))
+        });
+        #[cfg(all(feature = "crash_safe", feature = "opt_loop_end"))]
+        {
+            let ptr = user_tx_info.get_chunk_ctl().progress_ptr();
+            user_tx_info.log_loop_cnt(ptr, done, len);
+        }
+        #[cfg(not(all(feature = "crash_safe", feature = "opt_loop_end")))]
+        {
+            run(move |j| {
+                current()
+                    .get_mut_user_tx_info()
+                    .get_chunk_ctl()
+                    .advance(len, j);
+            });
+        }
+        user_tx_info.exit_idempotent_loop();
+        user_tx_info.get_chunk_ctl().end_chunk(len, cycles);
+    }
+}
+
 #[inline(always)]
 pub fn idempotent_region_start() -> bool {
     current().user_tx_group_start()
@@ -181,7 +181,7 @@ pub fn debug_syscall_tx_cache() {
 
 pub fn debug_kernel_tx_journal() {
     let tx = get_current_tx();
-    let j = tx.get_journal();
+    let j = unsafe { tx.get_journal() };
     debug_print!("Kernel Tx Journal: {}", j.get());
 }
 